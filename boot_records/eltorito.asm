@@ -0,0 +1,162 @@
+; pcboot El Torito boot image
+;
+; This is the "no emulation" boot image of a pcboot CD.  The BIOS loads its
+; first 2048-byte sector to 0x7c00 and jumps to it with DL set to the CD's
+; BIOS drive number.  The image file consists of this sector followed by
+; stage1, so we read stage1 from the sectors that follow the image's first
+; sector, using INT 13h extended reads with 2048-byte sectors.
+;
+; mkisofs/xorriso must be run with -boot-info-table, which patches the image's
+; own LBA into the boot information table at offset 8.  Run it with
+; -boot-load-size 4 so that the BIOS only loads this sector.
+;
+; stage1 is entered with DL set to the drive number and ESI set to 0, the LBA
+; of the ISO 9660 volume.  stage1 recognizes the CD by its sector size.
+;
+
+
+        bits 16
+
+
+;
+; Memory layout:
+;   0x800..0xbff                CRC-32C table
+;   ...
+;   0x????..0x7bff              stack
+;   0x7c00..0x83ff              executing boot image sector
+;   ...
+;   0x9000..0xc7ff              stage1
+;
+
+crc32c_table:                   equ 0x800
+stack:                          equ 0x7c00
+stage1:                         equ 0x9000
+stage1_sector_count:            equ 28
+cd_sector_size:                 equ 2048
+stage1_cd_sector_count:         equ stage1_sector_count * 512 / cd_sector_size
+
+
+%include "shared_macros.asm"
+
+
+        static_assert_eq (stage1_cd_sector_count * cd_sector_size), (stage1_sector_count * 512)
+
+
+        section .boot_record
+
+        global main
+main:
+        jmp short .skip_boot_info_table
+        nop
+
+        ;
+        ; El Torito boot information table, filled in by mkisofs.
+        ;
+        times 8-($-main) db 0
+.boot_info_pvd_lba:     dd 0            ; LBA of the primary volume descriptor
+.boot_info_file_lba:    dd 0            ; LBA of this boot image
+.boot_info_file_size:   dd 0            ; Size of this boot image in bytes
+.boot_info_checksum:    dd 0            ; Checksum of the image after 64 bytes
+        times 64-($-main) db 0
+
+.skip_boot_info_table:
+        cli
+        xor ax, ax
+        mov ss, ax
+        mov sp, stack
+        mov ds, ax
+        mov es, ax
+        sti
+        jmp 0:.relocated                ; Ensure CS is zero.
+
+.relocated:
+        mov [disk_number], dl
+
+        ;
+        ; Check that the image is large enough to include stage1.
+        ;
+        mov al, image_too_small_error
+        cmp dword [main.boot_info_file_size], cd_sector_size * (1 + stage1_cd_sector_count)
+        jb fail
+
+        ;
+        ; Read stage1.
+        ;
+        mov eax, [main.boot_info_file_lba]
+        inc eax
+        mov [dap.lba], eax
+        mov ah, 0x42
+        mov dl, [disk_number]
+        mov si, dap
+        int 0x13
+        mov al, read_error
+        jc fail
+
+        ;
+        ; Check the checksum.
+        ;
+        mov si, stage1
+        mov cx, stage1_sector_count * 512 - 4
+        call crc32c
+        cmp eax, [stage1 + stage1_sector_count * 512 - 4]
+        mov al, bad_stage1_checksum_error
+        jne fail
+
+        ;
+        ; Jump to stage1.
+        ;  - dl is the BIOS disk number.
+        ;  - esi is the starting LBA of the boot volume.
+        ;
+        mov dl, [disk_number]
+        xor esi, esi
+        jmp 0:stage1
+
+
+        ;
+        ; Print an error message and halt.
+        ;
+        ; Input: al: the error code character
+        ;
+fail:
+        mov [pcboot_error_char], al
+        mov si, pcboot_error
+.loop:
+        cld
+        lodsb
+        test al, al
+        jz .done
+        mov ah, 0x0e
+        mov bx, 7
+        int 0x10
+        jmp .loop
+.done:
+        cli
+.halt:
+        hlt
+        jmp .halt
+
+
+%include "crc32c.asm"
+
+
+read_error:                     equ 'A'
+image_too_small_error:          equ 'B'
+bad_stage1_checksum_error:      equ 'C'
+
+pcboot_error:                   db "pcboot CD err"
+pcboot_error_char:              db 0, 0
+
+disk_number:                    db 0
+
+        align 4
+dap:
+        db 16                           ; DAP size
+        db 0
+        dw stage1_cd_sector_count       ; sector count
+        dw stage1                       ; buffer offset
+        dw 0                            ; buffer segment
+.lba:   dd 0
+        dd 0
+
+
+        times cd_sector_size-($-main) db 0
@@ -0,0 +1,100 @@
+; pcboot PXE network bootstrap program (NBP) stub
+;
+; The PXE ROM downloads the NBP file (this stub, followed by stage2.bin) to
+; 0x7c00 and jumps to it.  The stub moves stage2 down to 0x5000, where it is
+; linked to run, and jumps to its entry point.
+;
+; stage2 is entered with DL set to 0xff, which marks a network boot, and ESI
+; set to 0.  stage2 locates the PXE API itself, using INT 1Ah/5650h, so the
+; PXENV+ and !PXE pointers passed to the NBP are not preserved.
+;
+; The stub does not know the size of stage2, so it always moves the largest
+; possible stage2 image.  Since stage2 overlaps this stub once it is moved,
+; the move loop is first relocated to the transfer area just below 0x80000,
+; which is also what stage1 uses.
+;
+
+
+        bits 16
+
+
+;
+; Memory layout:
+;   0x5000..0x77fff             stage2, after the move
+;   0x7c00..0x7dff              executing stub
+;   0x7e00..0x7adff             stage2, as downloaded
+;   0x7ff80..0x7ffff            relocated move loop
+;
+
+stub:                           equ 0x7c00
+stage2_download:                equ stub + 0x200
+stage2:                         equ 0x5000
+stage2_max_size:                equ 0x73000
+transfer_segment:               equ 0x7ff8
+network_boot_disk:              equ 0xff
+
+
+%include "shared_macros.asm"
+
+
+        static_assert_i32_le (stage2_download + stage2_max_size), (transfer_segment * 16)
+
+
+        section .boot_record
+
+        global main
+main:
+        cli
+        xor ax, ax
+        mov ss, ax
+        mov sp, stub
+        mov ds, ax
+        mov es, ax
+        jmp 0:.relocated                ; Ensure CS is zero.
+
+.relocated:
+        cld
+        mov si, move_loop
+        mov ax, transfer_segment
+        mov es, ax
+        xor di, di
+        mov cx, move_loop_size
+        rep movsb
+        jmp transfer_segment:0
+
+
+        ;
+        ; Position-independent: this code runs at transfer_segment:0.
+        ; Interrupts stay disabled, because the stack is overwritten.
+        ;
+move_loop:
+        mov ax, stage2_download >> 4    ; source segment
+        mov bx, stage2 >> 4             ; dest segment
+        mov cx, stage2_max_size >> 4
+.loop:
+        mov ds, ax
+        mov es, bx
+        xor si, si
+        xor di, di
+        movsd
+        movsd
+        movsd
+        movsd
+        inc ax
+        inc bx
+        dec cx
+        jnz .loop
+
+        xor ax, ax
+        mov ds, ax
+        mov es, ax
+        mov dl, network_boot_disk
+        xor esi, esi
+        jmp 0:stage2
+
+move_loop_size: equ $ - move_loop
+
+        static_assert_i32_le move_loop_size, 0x80
+
+
+        times 512-($-main) db 0
@@ -19,11 +19,33 @@ build/dummy_fat_vbr.bin : boot_records/dummy_fat_vbr.ld
 	mk/extract_boot_sector.sh dummy_fat_vbr 0 0
 	mv build/boot_records/dummy_fat_vbr.bin build/dummy_fat_vbr.bin
 
+build/eltorito.bin : boot_records/eltorito.ld
+	mk/build_boot_record_elf.sh eltorito
+	objcopy -j.boot_record -Obinary build/boot_records/eltorito.elf build/eltorito.bin
+
+# The El Torito boot image file: the boot sector, followed by stage1.
+build/pcboot-cd.img : build/eltorito.bin build/stage1.bin
+	cat build/eltorito.bin build/stage1.bin > build/pcboot-cd.img
+
+build/pxe_stub.bin : boot_records/pxe_stub.ld
+	mk/build_boot_record_elf.sh pxe_stub
+	objcopy -j.boot_record -Obinary build/boot_records/pxe_stub.elf build/pxe_stub.bin
+
+# The PXE network bootstrap program: the stub, followed by stage2.
+build/pcboot.pxe : build/pxe_stub.bin build/stage2.bin
+	cat build/pxe_stub.bin build/stage2.bin > build/pcboot.pxe
+
 FINAL_OUTPUTS := $(FINAL_OUTPUTS) \
 	build/mbr.bin \
 	build/vbr.bin \
-	build/dummy_fat_vbr.bin
+	build/dummy_fat_vbr.bin \
+	build/eltorito.bin \
+	build/pcboot-cd.img \
+	build/pxe_stub.bin \
+	build/pcboot.pxe
 
 -include build/boot_records/mbr.d
 -include build/boot_records/vbr.d
 -include build/boot_records/dummy_fat_vbr.d
+-include build/boot_records/eltorito.d
+-include build/boot_records/pxe_stub.d
@@ -7,6 +7,8 @@ build/libsys.rlib : src/libsys/lib.rs $(RUST_LIBCORE_DEP)
 	$(RUSTC) $(RUSTC_TARGET_FLAGS) $(RUST_LIBCORE_EXTERN) $< --out-dir $(dir $@) --emit link,dep-info
 
 OBJECT_FILES := \
+	build/libsys/cpuid.o \
+	build/libsys/io.o \
 	build/libsys/mode_switch.o \
 	build/libsys/sys.o
 
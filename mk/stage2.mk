@@ -8,7 +8,9 @@ build/stage2/libstage2.a : src/stage2/lib.rs build/libsys.rlib $(RUST_LIBCORE_DE
 		--out-dir build/stage2 \
 		--emit link,dep-info
 
-STAGE2_OBJECTS :=
+STAGE2_OBJECTS := \
+	build/stage2/long_mode.o \
+	build/stage2/pxe.o
 
 STAGE2_INPUTS := \
 	build/entry/entry.o \
@@ -5,6 +5,13 @@
 #  - The dosfstools package (mkfs.msdos)
 #  - sfdisk
 #  - qemu-system-x86_64
+#  - xorriso (for the CD image)
+#
+# Run "mk/test.sh cd" to boot from an El Torito CD image instead of the hard
+# disk image, or "mk/test.sh pxe" to boot over the network from QEMU's
+# built-in TFTP server.  "mk/test.sh ahci" attaches the hard disk image to an
+# AHCI controller, to exercise the native AHCI driver, and "mk/test.sh nvme"
+# boots from it on an NVMe controller.
 
 set -e -x
 
@@ -53,11 +60,53 @@ do_virtualbox_disk() {
     VBoxManage internalcommands sethduuid test/disk.vmdk 885d9adc-5f17-4bef-a28e-76d4ebefcc88
 }
 
+# Prepare an El Torito CD image.  -boot-info-table lets the boot image find
+# the rest of itself, and -boot-load-size 4 loads only its first 2048 bytes.
+do_cd_image() {
+    rm -fr test/cdroot
+    mkdir -p test/cdroot
+    cp build/pcboot-cd.img test/cdroot/pcboot-cd.img
+    cp build/stage2.bin test/cdroot/STAGE2.BIN
+    # A stub kernel that writes "K" to the debug port (0xE9) and halts.  Its
+    # name is not an ISO 9660 level 1 name, so stage2 only finds it through
+    # the Rock Ridge or Joliet names.
+    printf '\260K\346\351\364\353\375' > test/cdroot/kernel-x86_64.bin
+    xorriso -as mkisofs -R -J \
+        -b pcboot-cd.img -no-emul-boot -boot-load-size 4 -boot-info-table \
+        -o test/cd.iso test/cdroot
+}
+
 # Launch qemu.
 do_qemu() {
     qemu-system-x86_64 -hda test/disk
 }
 
+do_qemu_ahci() {
+    qemu-system-x86_64 \
+        -drive file=test/disk,format=raw,if=none,id=disk0 \
+        -device ahci,id=ahci0 -device ide-hd,drive=disk0,bus=ahci0.0
+}
+
+do_qemu_nvme() {
+    qemu-system-x86_64 \
+        -drive file=test/disk,format=raw,if=none,id=nvm0 \
+        -device nvme,serial=pcboot,drive=nvm0,bootindex=0
+}
+
+do_qemu_cd() {
+    qemu-system-x86_64 -cdrom test/cd.iso -boot d -debugcon stdio
+}
+
+# Serve pcboot.pxe from QEMU's user-mode network stack.
+do_qemu_pxe() {
+    rm -fr test/tftp
+    mkdir -p test/tftp
+    cp build/pcboot.pxe test/tftp/pcboot.pxe
+    qemu-system-x86_64 \
+        -netdev user,id=n0,tftp=test/tftp,bootfile=pcboot.pxe \
+        -device e1000,netdev=n0 -boot n
+}
+
 # Launch bochs.  (Install bochs and bochs-sdl Ubuntu packages.)
 do_bochs() {
 cat > test/bochsrc.txt << EOF
@@ -68,4 +117,15 @@ EOF
     cd test && bochs
 }
 
-do_qemu
+if [ "$1" = "cd" ]; then
+    do_cd_image
+    do_qemu_cd
+elif [ "$1" = "pxe" ]; then
+    do_qemu_pxe
+elif [ "$1" = "ahci" ]; then
+    do_qemu_ahci
+elif [ "$1" = "nvme" ]; then
+    do_qemu_nvme
+else
+    do_qemu
+fi
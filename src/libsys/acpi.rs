@@ -0,0 +1,285 @@
+// Minimal ACPI table access.
+//
+// pcboot only needs a handful of FADT fields to reset and power off the
+// machine, so this module locates the RSDP, walks the RSDT (or XSDT), and
+// returns tables by signature.  The only AML it understands is the \_S5
+// package, found with the usual byte-pattern search of the DSDT.
+//
+// Tables are accessed by their physical addresses, which is fine because
+// pcboot runs with flat segments and paging disabled.
+
+use core;
+
+use io;
+
+unsafe fn read8(addr: u32) -> u8 {
+    core::ptr::read(addr as *const u8)
+}
+
+unsafe fn read16(addr: u32) -> u16 {
+    core::ptr::read(addr as *const u16)
+}
+
+unsafe fn read32(addr: u32) -> u32 {
+    core::ptr::read(addr as *const u32)
+}
+
+unsafe fn read64(addr: u32) -> u64 {
+    core::ptr::read(addr as *const u64)
+}
+
+unsafe fn bytes_match(addr: u32, expected: &[u8]) -> bool {
+    for (i, byte) in expected.iter().enumerate() {
+        if read8(addr + i as u32) != *byte {
+            return false;
+        }
+    }
+    true
+}
+
+unsafe fn checksum_ok(addr: u32, len: u32) -> bool {
+    let mut sum = 0u8;
+    for i in 0..len {
+        sum = sum.wrapping_add(read8(addr + i));
+    }
+    sum == 0
+}
+
+unsafe fn scan_for_rsdp(start: u32, end: u32) -> Option<u32> {
+    let mut addr = start;
+    while addr + 20 <= end {
+        if bytes_match(addr, b"RSD PTR ") && checksum_ok(addr, 20) {
+            let revision = read8(addr + 15);
+            if revision < 2 || checksum_ok(addr, read32(addr + 20)) {
+                return Some(addr);
+            }
+        }
+        addr += 16;
+    }
+    None
+}
+
+fn find_rsdp() -> Option<u32> {
+    unsafe {
+        // The first KiB of the EBDA, whose segment is stored in the BDA.
+        let ebda = (read16(0x40e) as u32) << 4;
+        if ebda >= 0x80000 && ebda < 0xa0000 {
+            let found = scan_for_rsdp(ebda, ebda + 1024);
+            if found.is_some() {
+                return found;
+            }
+        }
+        scan_for_rsdp(0xe0000, 0x100000)
+    }
+}
+
+// Returns the physical address of the first table with the given signature,
+// with a valid checksum.
+pub fn find_table(signature: &[u8; 4]) -> Option<u32> {
+    let rsdp = match find_rsdp() {
+        None => { return None; },
+        Some(rsdp) => rsdp
+    };
+    unsafe {
+        // Prefer the XSDT, but only if it lies within the 32-bit address
+        // space.
+        let (root, entry_size) = {
+            let xsdt = if read8(rsdp + 15) >= 2 { read64(rsdp + 24) } else { 0 };
+            if xsdt != 0 && xsdt <= 0xffff_ffff {
+                (xsdt as u32, 8)
+            } else {
+                (read32(rsdp + 16), 4)
+            }
+        };
+        let root_len = read32(root + 4);
+        if root_len < 36 || !checksum_ok(root, root_len) {
+            return None;
+        }
+        let mut entry = root + 36;
+        while entry + entry_size <= root + root_len {
+            let table = if entry_size == 8 {
+                let addr = read64(entry);
+                if addr > 0xffff_ffff { 0 } else { addr as u32 }
+            } else {
+                read32(entry)
+            };
+            if table != 0 && bytes_match(table, signature) &&
+                    checksum_ok(table, read32(table + 4)) {
+                return Some(table);
+            }
+            entry += entry_size;
+        }
+    }
+    None
+}
+
+// Offsets of FADT fields used by pcboot.
+const FADT_DSDT: u32            = 40;
+const FADT_SMI_CMD: u32         = 48;
+const FADT_ACPI_ENABLE: u32     = 52;
+const FADT_PM1A_CNT_BLK: u32    = 64;
+const FADT_PM1B_CNT_BLK: u32    = 68;
+const FADT_FLAGS: u32           = 112;
+const FADT_RESET_REG: u32       = 116;
+const FADT_RESET_VALUE: u32     = 128;
+const FADT_X_DSDT: u32          = 140;
+
+const FADT_FLAG_RESET_REG_SUP: u32 = 1 << 10;
+
+const PM1_CNT_SCI_EN: u16   = 1 << 0;
+const PM1_CNT_SLP_EN: u16   = 1 << 13;
+
+// Generic Address Structure address spaces.
+const GAS_SYSTEM_MEMORY: u8 = 0;
+const GAS_SYSTEM_IO: u8     = 1;
+const GAS_PCI_CONFIG: u8    = 2;
+
+fn fadt() -> Option<(u32, u32)> {
+    find_table(b"FACP").map(|fadt| (fadt, unsafe { read32(fadt + 4) }))
+}
+
+// Write the FADT reset value to the reset register.  Returns false if the
+// firmware does not advertise a usable reset register.  If the write works,
+// the machine resets shortly afterwards.
+pub fn reset() -> bool {
+    let (fadt, len) = match fadt() {
+        None => { return false; },
+        Some(x) => x
+    };
+    unsafe {
+        if len <= FADT_RESET_VALUE ||
+                read32(fadt + FADT_FLAGS) & FADT_FLAG_RESET_REG_SUP == 0 {
+            return false;
+        }
+        let space = read8(fadt + FADT_RESET_REG);
+        let addr = read64(fadt + FADT_RESET_REG + 4);
+        let value = read8(fadt + FADT_RESET_VALUE);
+        match space {
+            GAS_SYSTEM_MEMORY if addr <= 0xffff_ffff => {
+                io::mmio_write8(addr as u32, value);
+            },
+            GAS_SYSTEM_IO if addr <= 0xffff => {
+                io::outb(addr as u16, value);
+            },
+            GAS_PCI_CONFIG => {
+                // Bus 0.  Device, function, and register are packed into the
+                // 64-bit address.
+                let device = ((addr >> 32) & 0x1f) as u32;
+                let function = ((addr >> 16) & 0x7) as u32;
+                let register = (addr & 0xff) as u32;
+                io::outl(0xcf8, 0x8000_0000 | (device << 11) |
+                                (function << 8) | (register & 0xfc));
+                io::outb(0xcfc + (register & 3) as u16, value);
+            },
+            _ => { return false; },
+        }
+    }
+    true
+}
+
+fn dsdt(fadt: u32, fadt_len: u32) -> Option<u32> {
+    unsafe {
+        let x_dsdt = if fadt_len >= FADT_X_DSDT + 8 {
+            read64(fadt + FADT_X_DSDT)
+        } else {
+            0
+        };
+        let dsdt = if x_dsdt != 0 && x_dsdt <= 0xffff_ffff {
+            x_dsdt as u32
+        } else {
+            read32(fadt + FADT_DSDT)
+        };
+        if dsdt != 0 && bytes_match(dsdt, b"DSDT") {
+            Some(dsdt)
+        } else {
+            None
+        }
+    }
+}
+
+// Decode an AML integer constant used as a package element.
+unsafe fn read_aml_byte_const(addr: &mut u32) -> Option<u8> {
+    let op = read8(*addr);
+    *addr += 1;
+    match op {
+        0x00 => Some(0),                // ZeroOp
+        0x01 => Some(1),                // OneOp
+        0x0a => {                       // BytePrefix
+            let value = read8(*addr);
+            *addr += 1;
+            Some(value)
+        },
+        _ => None,
+    }
+}
+
+// Find the SLP_TYPa and SLP_TYPb values of the \_S5 (soft-off) object.
+fn s5_sleep_types(dsdt: u32) -> Option<(u8, u8)> {
+    unsafe {
+        let len = read32(dsdt + 4);
+        let mut addr = dsdt + 36;
+        while addr + 4 < dsdt + len {
+            if bytes_match(addr, b"_S5_") {
+                // The name should be preceded by NameOp (0x08), optionally
+                // with a root prefix, and followed by PackageOp (0x12).
+                let named = read8(addr - 1) == 0x08 ||
+                    (read8(addr - 2) == 0x08 && read8(addr - 1) == b'\\');
+                if named && read8(addr + 4) == 0x12 {
+                    // Skip the PkgLength, whose top two bits give the number
+                    // of additional length bytes, and the element count.
+                    let mut p = addr + 5;
+                    p += ((read8(p) >> 6) as u32) + 1;
+                    p += 1;
+                    if let Some(slp_typa) = read_aml_byte_const(&mut p) {
+                        let slp_typb = read_aml_byte_const(&mut p).unwrap_or(0);
+                        return Some((slp_typa, slp_typb));
+                    }
+                }
+            }
+            addr += 1;
+        }
+    }
+    None
+}
+
+// Enter the S5 soft-off state by writing SLP_TYP|SLP_EN to the PM1 control
+// registers.  Returns false if the firmware tables lack what we need.  If the
+// write works, the machine turns off shortly afterwards.
+pub fn power_off() -> bool {
+    let (fadt, len) = match fadt() {
+        None => { return false; },
+        Some(x) => x
+    };
+    let (slp_typa, slp_typb) = match dsdt(fadt, len).and_then(s5_sleep_types) {
+        None => { return false; },
+        Some(x) => x
+    };
+    unsafe {
+        let pm1a_cnt = read32(fadt + FADT_PM1A_CNT_BLK);
+        let pm1b_cnt = read32(fadt + FADT_PM1B_CNT_BLK);
+        if pm1a_cnt == 0 || pm1a_cnt > 0xffff {
+            return false;
+        }
+        let pm1a_cnt = pm1a_cnt as u16;
+
+        // Switch the chipset into ACPI mode if the firmware left it in legacy
+        // mode.
+        let smi_cmd = read32(fadt + FADT_SMI_CMD);
+        let acpi_enable = read8(fadt + FADT_ACPI_ENABLE);
+        if io::inw(pm1a_cnt) & PM1_CNT_SCI_EN == 0 &&
+                smi_cmd != 0 && smi_cmd <= 0xffff && acpi_enable != 0 {
+            io::outb(smi_cmd as u16, acpi_enable);
+            let mut tries = 0;
+            while io::inw(pm1a_cnt) & PM1_CNT_SCI_EN == 0 && tries < 100000 {
+                io::wait();
+                tries += 1;
+            }
+        }
+
+        io::outw(pm1a_cnt, ((slp_typa as u16) << 10) | PM1_CNT_SLP_EN);
+        if pm1b_cnt != 0 && pm1b_cnt <= 0xffff {
+            io::outw(pm1b_cnt as u16, ((slp_typb as u16) << 10) | PM1_CNT_SLP_EN);
+        }
+    }
+    true
+}
@@ -0,0 +1,49 @@
+        section .text
+        bits 32
+
+
+        ;
+        ; Return 1 if the CPU supports the CPUID instruction, 0 otherwise.
+        ; CPUID is supported if software can toggle the EFLAGS.ID bit.
+        ;
+        ; C prototype:
+        ;
+        ;     u32 cpuid_supported(void);
+        ;
+        global cpuid_supported
+cpuid_supported:
+        pushfd
+        pushfd
+        xor dword [esp], 1 << 21
+        popfd
+        pushfd
+        pop eax
+        xor eax, [esp]
+        popfd
+        shr eax, 21
+        and eax, 1
+        ret
+
+
+        ;
+        ; Execute CPUID and store EAX, EBX, ECX, and EDX into regs[0..4].
+        ;
+        ; C prototype:
+        ;
+        ;     void cpuid_raw(u32 leaf, u32 subleaf, u32 *regs);
+        ;
+        global cpuid_raw
+cpuid_raw:
+        push ebx
+        push edi
+        mov eax, [esp + 12]
+        mov ecx, [esp + 16]
+        mov edi, [esp + 20]
+        cpuid
+        mov [edi + 0], eax
+        mov [edi + 4], ebx
+        mov [edi + 8], ecx
+        mov [edi + 12], edx
+        pop edi
+        pop ebx
+        ret
@@ -0,0 +1,138 @@
+// CPU identification using the CPUID instruction.
+
+extern "C" {
+    fn cpuid_supported() -> u32;
+    fn cpuid_raw(leaf: u32, subleaf: u32, regs: *mut [u32; 4]);
+}
+
+// Feature flags reported by CpuInfo::has().
+pub const FEATURE_PAE: u32              = 1 << 0;
+pub const FEATURE_LONG_MODE: u32        = 1 << 1;
+pub const FEATURE_NX: u32               = 1 << 2;
+pub const FEATURE_1GIB_PAGES: u32       = 1 << 3;
+pub const FEATURE_SSE4_2: u32           = 1 << 4;
+pub const FEATURE_RDRAND: u32           = 1 << 5;
+pub const FEATURE_INVARIANT_TSC: u32    = 1 << 6;
+pub const FEATURE_HYPERVISOR: u32       = 1 << 7;
+
+pub struct CpuInfo {
+    vendor: [u8; 12],
+    brand: [u8; 48],
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    features: u32,
+}
+
+impl CpuInfo {
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    // e.g. "GenuineIntel" or "AuthenticAMD".  Empty if CPUID is unsupported.
+    pub fn vendor(&self) -> &[u8] {
+        trim(&self.vendor)
+    }
+
+    // The processor brand string, or an empty string if the CPU lacks one.
+    pub fn brand(&self) -> &[u8] {
+        trim(&self.brand)
+    }
+}
+
+// Strip NUL padding and the leading spaces some CPUs put in the brand string.
+fn trim(text: &[u8]) -> &[u8] {
+    let mut start = 0;
+    let mut end = text.len();
+    while end > 0 && text[end - 1] == 0 {
+        end -= 1;
+    }
+    while start < end && text[start] == b' ' {
+        start += 1;
+    }
+    &text[start..end]
+}
+
+pub fn is_supported() -> bool {
+    unsafe { cpuid_supported() != 0 }
+}
+
+// Execute CPUID and return [EAX, EBX, ECX, EDX].  The caller must check
+// is_supported() and the maximum leaf first.
+pub fn cpuid(leaf: u32, subleaf: u32) -> [u32; 4] {
+    let mut regs = [0u32; 4];
+    unsafe { cpuid_raw(leaf, subleaf, &mut regs); }
+    regs
+}
+
+fn store_u32(dest: &mut [u8], value: u32) {
+    dest[0] = value as u8;
+    dest[1] = (value >> 8) as u8;
+    dest[2] = (value >> 16) as u8;
+    dest[3] = (value >> 24) as u8;
+}
+
+pub fn cpu_info() -> CpuInfo {
+    let mut info = CpuInfo {
+        vendor: [0u8; 12],
+        brand: [0u8; 48],
+        family: 0,
+        model: 0,
+        stepping: 0,
+        features: 0,
+    };
+    if !is_supported() {
+        return info;
+    }
+
+    let leaf0 = cpuid(0, 0);
+    let max_leaf = leaf0[0];
+    store_u32(&mut info.vendor[0..4], leaf0[1]);
+    store_u32(&mut info.vendor[4..8], leaf0[3]);
+    store_u32(&mut info.vendor[8..12], leaf0[2]);
+
+    if max_leaf >= 1 {
+        let leaf1 = cpuid(1, 0);
+        let signature = leaf1[0];
+        let base_family = (signature >> 8) & 0xf;
+        let base_model = (signature >> 4) & 0xf;
+        info.stepping = signature & 0xf;
+        info.family = base_family;
+        info.model = base_model;
+        if base_family == 0xf {
+            info.family += (signature >> 20) & 0xff;
+        }
+        if base_family == 0x6 || base_family == 0xf {
+            info.model += ((signature >> 16) & 0xf) << 4;
+        }
+
+        let (ecx, edx) = (leaf1[2], leaf1[3]);
+        if edx & (1 << 6) != 0 { info.features |= FEATURE_PAE; }
+        if ecx & (1 << 20) != 0 { info.features |= FEATURE_SSE4_2; }
+        if ecx & (1 << 30) != 0 { info.features |= FEATURE_RDRAND; }
+        if ecx & (1 << 31) != 0 { info.features |= FEATURE_HYPERVISOR; }
+    }
+
+    let max_extended_leaf = cpuid(0x8000_0000, 0)[0];
+    if max_extended_leaf >= 0x8000_0001 {
+        let edx = cpuid(0x8000_0001, 0)[3];
+        if edx & (1 << 20) != 0 { info.features |= FEATURE_NX; }
+        if edx & (1 << 26) != 0 { info.features |= FEATURE_1GIB_PAGES; }
+        if edx & (1 << 29) != 0 { info.features |= FEATURE_LONG_MODE; }
+    }
+    if max_extended_leaf >= 0x8000_0004 {
+        for i in 0..3 {
+            let regs = cpuid(0x8000_0002 + i as u32, 0);
+            for j in 0..4 {
+                let offset = i * 16 + j * 4;
+                store_u32(&mut info.brand[offset..offset + 4], regs[j]);
+            }
+        }
+    }
+    if max_extended_leaf >= 0x8000_0007 {
+        let edx = cpuid(0x8000_0007, 0)[3];
+        if edx & (1 << 8) != 0 { info.features |= FEATURE_INVARIANT_TSC; }
+    }
+
+    info
+}
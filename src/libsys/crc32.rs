@@ -0,0 +1,54 @@
+// Table-driven CRC-32 computation.
+//
+// stage1 checks stage2 with CRC-32C (Castagnoli).  GPT headers and partition
+// entry arrays use the IEEE (PKZIP) CRC-32.  Both use the same reflected
+// algorithm and differ only in the polynomial.
+
+// We need to use the bit-by-bit reversed polynomial.
+//  - CRC-32 (PKZIP) uses 0x04C11DB7, reversed to 0xEDB88320.
+//  - CRC-32C (Castagnoli) uses 0x1EDC6F41, reversed to 0x82F63B78.
+pub const POLYNOMIAL_IEEE: u32 = 0xEDB88320;
+pub const POLYNOMIAL_CASTAGNOLI: u32 = 0x82F63B78;
+
+// A 1KiB-sized table used to compute the checksum.
+pub struct Table {
+    data: [u32; 256]
+}
+
+pub fn table_for_polynomial(polynomial: u32) -> Table {
+    let mut table = Table { data: [0u32; 256] };
+    for (i, cell) in table.data.iter_mut().enumerate() {
+        let mut result = i as u32;
+        for _ in 0..8 {
+            let lsb = result & 1;
+            result >>= 1;
+            if lsb == 1 {
+                result ^= polynomial;
+            }
+        }
+        *cell = result;
+    }
+    table
+}
+
+// The CRC-32C table.
+pub fn table_castagnoli() -> Table {
+    table_for_polynomial(POLYNOMIAL_CASTAGNOLI)
+}
+
+// The IEEE CRC-32 table.
+pub fn table_ieee() -> Table {
+    table_for_polynomial(POLYNOMIAL_IEEE)
+}
+
+pub fn compute(table: &Table, buffer: &[u8]) -> u32 {
+    let mut acc = 0xffffffff_u32;
+    for b in buffer.iter() {
+        let lookup_value = unsafe {
+            let index: u8 = *b ^ (acc as u8);
+            *table.data.get_unchecked(index as usize)
+        };
+        acc = lookup_value ^ (acc >> 8);
+    }
+    acc ^ 0xffffffff
+}
@@ -0,0 +1,216 @@
+// BIOS Enhanced Disk Drive (EDD) parameters.
+//
+// INT 13h/48h returns the drive geometry and, on EDD 3.0 BIOSes, a device
+// path describing where the disk is attached: the host bus (e.g. PCI
+// bus/device/function), the interface (e.g. ATA or USB), and the device on
+// that interface (e.g. ATA master/slave).  This maps a BIOS disk number such
+// as 0x80 to a physical disk.
+
+use core;
+use core::fmt;
+use core::mem;
+
+use pci;
+use {addr_linear_to_segmented, call_real_mode, get16, get64, ErrorStr};
+
+extern "C" {
+    fn get_drive_parameters_16bit();
+}
+
+const DEVICE_PATH_KEY: u16 = 0xbedd;
+const VIRTIO_VENDOR_ID: u16 = 0x1af4;
+
+#[repr(C, packed)]
+struct ResultBuffer {
+    /*0x00*/    size: u16,
+    /*0x02*/    flags: u16,
+    /*0x04*/    cylinders: u32,
+    /*0x08*/    heads: u32,
+    /*0x0c*/    sectors_per_track: u32,
+    /*0x10*/    total_sectors: u64,
+    /*0x18*/    bytes_per_sector: u16,
+    // EDD 2.0+
+    /*0x1a*/    dpte: u32,              // far pointer to the DPTE
+    // EDD 3.0+
+    /*0x1e*/    key: u16,               // 0xBEDD if the device path is present
+    /*0x20*/    device_path_len: u8,    // counted from the key
+    /*0x21*/    _reserved1: [u8; 3],
+    /*0x24*/    host_bus: [u8; 4],      // e.g. "PCI " or "ISA "
+    /*0x28*/    interface: [u8; 8],     // e.g. "ATA     " or "USB     "
+    /*0x30*/    interface_path: [u8; 8],
+    // Phoenix EDD 3.0 has an 8-byte device path, followed by a reserved
+    // byte and the checksum at 0x41.  T13 EDD-3 extends the device path to
+    // 16 bytes, moving them to 0x48 and 0x49.
+    /*0x38*/    device_path: [u8; 16],
+    /*0x48*/    _reserved2: u8,
+    /*0x49*/    _checksum: u8,
+}
+
+// The device path length, counted from the key, in each layout.
+const PHOENIX_DEVICE_PATH_LEN: usize = 0x24;
+const T13_DEVICE_PATH_LEN: usize = 0x2c;
+
+#[derive(Copy, Clone)]
+pub enum HostBus {
+    Pci { bus: u8, device: u8, function: u8, channel: u8 },
+    Isa { base_address: u16 },
+    Other([u8; 4]),
+}
+
+#[derive(Copy, Clone)]
+pub enum Interface {
+    Ata { slave: bool },
+    Atapi { slave: bool, lun: u8 },
+    Scsi { target: u16, lun: u64 },
+    Usb { serial: u64 },
+    Ieee1394 { guid: u64 },
+    FibreChannel { wwn: u64, lun: u64 },
+    Sata { port: u8 },
+    Sas { address: u64, lun: u64 },
+    // virtio-blk has no EDD interface name.  BIOSes report it as SCSI or as
+    // an unknown type, so we recognize it by its PCI vendor ID.
+    Virtio,
+    Other([u8; 8]),
+}
+
+#[derive(Copy, Clone)]
+pub struct DevicePath {
+    pub host_bus: HostBus,
+    pub interface: Interface,
+}
+
+pub struct DriveParameters {
+    pub total_sectors: u64,
+    pub bytes_per_sector: u16,
+    pub device_path: Option<DevicePath>,
+}
+
+fn parse_device_path(buf: &ResultBuffer) -> Option<DevicePath> {
+    if buf.size < 0x1e + PHOENIX_DEVICE_PATH_LEN as u16 || buf.key != DEVICE_PATH_KEY {
+        return None;
+    }
+
+    // The checksum covers the device path information, starting at the key.
+    let path_len = buf.device_path_len as usize;
+    let bytes: &[u8; 0x4a] = unsafe { mem::transmute(buf) };
+    if (path_len != PHOENIX_DEVICE_PATH_LEN && path_len != T13_DEVICE_PATH_LEN) ||
+            0x1e + path_len > buf.size as usize {
+        return None;
+    }
+    let mut sum = 0u8;
+    for b in bytes[0x1e..0x1e + path_len].iter() {
+        sum = sum.wrapping_add(*b);
+    }
+    if sum != 0 {
+        return None;
+    }
+
+    let ipath = &buf.interface_path;
+    let dpath = &buf.device_path;
+
+    let host_bus = match &buf.host_bus {
+        b"PCI " | b"PCIX" | b"XPRS" => HostBus::Pci {
+            bus: ipath[0], device: ipath[1], function: ipath[2], channel: ipath[3]
+        },
+        b"ISA " => HostBus::Isa { base_address: get16(ipath, 0) },
+        other => HostBus::Other(*other),
+    };
+
+    let is_virtio = match host_bus {
+        HostBus::Pci { bus, device, function, .. } => {
+            pci::vendor_id(pci::Address {
+                bus: bus, device: device, function: function
+            }) == VIRTIO_VENDOR_ID
+        },
+        _ => false,
+    };
+
+    let interface = if is_virtio {
+        Interface::Virtio
+    } else {
+        match &buf.interface {
+            b"ATA     " => Interface::Ata { slave: dpath[0] != 0 },
+            b"ATAPI   " => Interface::Atapi { slave: dpath[0] != 0, lun: dpath[1] },
+            b"SCSI    " => Interface::Scsi {
+                target: get16(dpath, 0),
+                lun: get64(dpath, 2) & 0xffff_ffff_ffff,
+            },
+            b"USB     " => Interface::Usb { serial: get64(dpath, 0) },
+            b"1394    " => Interface::Ieee1394 { guid: get64(dpath, 0) },
+            b"FIBRE   " => Interface::FibreChannel {
+                wwn: get64(dpath, 0),
+                lun: 0,
+            },
+            b"SATA    " => Interface::Sata { port: dpath[0] },
+            b"SAS     " => Interface::Sas { address: get64(dpath, 0), lun: 0 },
+            other => Interface::Other(*other),
+        }
+    };
+
+    Some(DevicePath { host_bus: host_bus, interface: interface })
+}
+
+pub fn get_drive_parameters(bios_disk_number: u8) ->
+        Result<DriveParameters, ErrorStr> {
+    let mut buf: ResultBuffer = unsafe { mem::zeroed() };
+    buf.size = core::mem::size_of::<ResultBuffer>() as u16;
+    let buf_ptr = addr_linear_to_segmented(&mut buf as *mut ResultBuffer as u32);
+    unsafe {
+        if call_real_mode(
+                get_drive_parameters_16bit,
+                bios_disk_number as u32,
+                buf_ptr) as u8 == 0 {
+            return Err(ErrorStr::new(strlit!("cannot read drive parameters")));
+        }
+    }
+    Ok(DriveParameters {
+        total_sectors: buf.total_sectors,
+        bytes_per_sector: buf.bytes_per_sector,
+        device_path: parse_device_path(&buf),
+    })
+}
+
+fn trim_spaces(text: &[u8]) -> &str {
+    let mut end = text.len();
+    while end > 0 && (text[end - 1] == b' ' || text[end - 1] == 0) {
+        end -= 1;
+    }
+    core::str::from_utf8(&text[..end]).unwrap_or("?")
+}
+
+// Format the path in the style of Linux's /dev/disk/by-path names, e.g.
+// "pci-0000:00:01.1-ata-0".
+impl fmt::Display for DevicePath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.host_bus {
+            HostBus::Pci { bus, device, function, .. } =>
+                try!(write!(f, "pci-0000:{:02x}:{:02x}.{:x}", bus, device, function)),
+            HostBus::Isa { base_address } =>
+                try!(write!(f, "isa-0x{:x}", base_address)),
+            HostBus::Other(ref name) =>
+                try!(write!(f, "{}", trim_spaces(name))),
+        }
+        match self.interface {
+            Interface::Ata { slave } =>
+                write!(f, "-ata-{}", if slave { 1 } else { 0 }),
+            Interface::Atapi { slave, lun } =>
+                write!(f, "-atapi-{}-lun-{}", if slave { 1 } else { 0 }, lun),
+            Interface::Scsi { target, lun } =>
+                write!(f, "-scsi-0:0:{}:{}", target, lun),
+            Interface::Usb { serial } =>
+                write!(f, "-usb-{:x}", serial),
+            Interface::Ieee1394 { guid } =>
+                write!(f, "-ieee1394-0x{:016x}", guid),
+            Interface::FibreChannel { wwn, lun } =>
+                write!(f, "-fc-0x{:016x}-lun-{}", wwn, lun),
+            Interface::Sata { port } =>
+                write!(f, "-sata-{}", port),
+            Interface::Sas { address, lun } =>
+                write!(f, "-sas-0x{:016x}-lun-{}", address, lun),
+            Interface::Virtio =>
+                write!(f, "-virtio"),
+            Interface::Other(ref name) =>
+                write!(f, "-{}", trim_spaces(name)),
+        }
+    }
+}
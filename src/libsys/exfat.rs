@@ -0,0 +1,702 @@
+// exFAT filesystem reader.
+//
+// The volume starts with a 12-sector boot region whose last sector holds a
+// checksum of the other eleven, and a backup copy of the region follows it.
+// If the main boot region is damaged, we use the backup.
+//
+// Files live in the cluster heap.  A file's clusters are either chained
+// through the FAT, as on FAT32, or, when its stream extension entry has the
+// NoFatChain flag, contiguous, in which case its FAT entries are meaningless.
+// Every cluster of a file is checked against the allocation bitmap.
+//
+// A file is described by a directory entry set: a file entry, a stream
+// extension entry, and file name entries holding 15 UTF-16 units each, all
+// covered by a set checksum.  Names are compared after mapping both sides
+// through the volume's up-case table, as Windows does.
+//
+// Only 512-byte device sectors are supported.  exFAT sectors may be larger,
+// but everything here is addressed in device sectors.
+
+use core::cmp;
+
+use unicode::{utf16_to_utf8, utf8_to_utf16};
+use {copy_bytes, get16, get32, get64, BlockDevice, ErrorStr, FileSource, SectorIndex,
+     SECTOR_SIZE};
+
+const SECTOR_SHIFT: u32 = 9;
+const MIN_SECTOR_SHIFT: u8 = 9;
+const MAX_SECTOR_SHIFT: u8 = 12;
+const MAX_CLUSTER_SHIFT: u8 = 25;   // bytes per cluster, as a shift
+const BOOT_REGION_SECTORS: u32 = 12;
+const FIRST_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0xffff_ffff;
+
+const ENTRY_SIZE: usize = 32;
+const ENTRY_SHIFT: u32 = 5;
+const ENTRY_END: u8 = 0x00;
+const ENTRY_IN_USE: u8 = 0x80;
+const ENTRY_BITMAP: u8 = 0x81;
+const ENTRY_UPCASE: u8 = 0x82;
+const ENTRY_FILE: u8 = 0x85;
+const ENTRY_STREAM: u8 = 0xc0;
+const ENTRY_NAME: u8 = 0xc1;
+
+const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+const ATTR_DIRECTORY: u16 = 0x10;
+const VOLUME_FLAG_ACTIVE_FAT: u16 = 0x01;
+
+const MAX_SECONDARY_ENTRIES: usize = 18;    // a stream entry and 17 name entries
+const NAME_UNITS_PER_ENTRY: usize = 15;
+pub const MAX_NAME_UNITS: usize = 255;
+const MAX_NAME_BYTES: usize = MAX_NAME_UNITS * 3;
+
+// The up-case table is stored compressed, with 0xFFFF followed by a count
+// standing for that many identity mappings.  The usual table is 5836 bytes; a
+// volume with a larger one falls back to ASCII case folding.  The caller
+// supplies the space for it, so each open volume has its own.
+pub const MAX_UPCASE_SIZE: usize = 8192;
+
+pub type UpcaseBuffer = [u8; MAX_UPCASE_SIZE];
+
+// The rotating checksum used for entry sets and name hashes.
+fn checksum16(sum: u16, byte: u8) -> u16 {
+    ((sum & 1) << 15).wrapping_add(sum >> 1).wrapping_add(byte as u16)
+}
+
+// The rotating checksum used for the boot region and the up-case table.
+fn checksum32(sum: u32, byte: u8) -> u32 {
+    ((sum & 1) << 31).wrapping_add(sum >> 1).wrapping_add(byte as u32)
+}
+
+#[derive(Copy, Clone)]
+pub struct File {
+    first_cluster: u32,
+    pub size: u64,              // in bytes
+    valid_size: u64,            // bytes past this read as zero
+    pub is_dir: bool,
+    contiguous: bool,           // NoFatChain: the FAT is not used
+}
+
+impl File {
+    fn new(first_cluster: u32, size: u64) -> File {
+        File {
+            first_cluster: first_cluster,
+            size: size,
+            valid_size: size,
+            is_dir: false,
+            contiguous: false,
+        }
+    }
+}
+
+pub struct Volume<'a> {
+    device: &'a BlockDevice,
+    start_sector: SectorIndex,
+    fat_sector: u32,            // the active FAT, relative to the volume
+    heap_sector: u32,
+    cluster_count: u32,
+    cluster_shift: u32,         // device sectors per cluster, as a shift
+    root: File,
+    bitmap: Option<File>,
+    upcase: &'a [u8],           // empty to fold ASCII only
+    pub serial_number: u32,
+}
+
+pub struct DirEntry {
+    pub file: File,
+    name: [u8; MAX_NAME_BYTES],
+    name_len: usize,
+}
+
+impl DirEntry {
+    // The name, converted to UTF-8.
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+// A one-sector read cache.
+struct SectorBuffer {
+    lba: Option<SectorIndex>,
+    data: [u8; SECTOR_SIZE],
+}
+
+impl SectorBuffer {
+    fn new() -> SectorBuffer {
+        SectorBuffer { lba: None, data: [0u8; SECTOR_SIZE] }
+    }
+
+    fn load(&mut self, device: &BlockDevice, lba: SectorIndex) -> Result<&[u8], ErrorStr> {
+        if self.lba != Some(lba) {
+            self.lba = None;
+            try!(device.read_sectors(lba, &mut self.data));
+            self.lba = Some(lba);
+        }
+        Ok(&self.data)
+    }
+}
+
+// A position in a file's cluster chain.  Moving forward follows the FAT from
+// the current cluster; moving backward starts over from the first cluster.
+struct Chain {
+    file: File,
+    index: u32,
+    cluster: u32,
+    fat: SectorBuffer,
+}
+
+impl Chain {
+    fn new(file: File) -> Chain {
+        Chain {
+            file: file,
+            index: 0,
+            cluster: file.first_cluster,
+            fat: SectorBuffer::new(),
+        }
+    }
+}
+
+// Maps a file's sectors to device sectors, checking each cluster against the
+// allocation bitmap as the cursor reaches it.
+struct Cursor {
+    chain: Chain,
+    checked_cluster: u32,
+    bitmap_chain: Option<Chain>,
+    bitmap: SectorBuffer,
+}
+
+impl Cursor {
+    fn new(file: File, bitmap: Option<File>) -> Cursor {
+        Cursor {
+            chain: Chain::new(file),
+            checked_cluster: 0,
+            bitmap_chain: bitmap.map(Chain::new),
+            bitmap: SectorBuffer::new(),
+        }
+    }
+}
+
+impl<'a> Volume<'a> {
+    pub fn root(&self) -> File {
+        self.root
+    }
+
+    fn check_cluster(&self, cluster: u32) -> Result<(), ErrorStr> {
+        if cluster < FIRST_CLUSTER || cluster - FIRST_CLUSTER >= self.cluster_count {
+            return Err(ErrorStr::new(strlit!("exFAT cluster is out of range")));
+        }
+        Ok(())
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> SectorIndex {
+        self.start_sector + self.heap_sector + ((cluster - FIRST_CLUSTER) << self.cluster_shift)
+    }
+
+    fn fat_entry(&self, cluster: u32, cache: &mut SectorBuffer) -> Result<u32, ErrorStr> {
+        let lba = self.start_sector + self.fat_sector + (cluster >> (SECTOR_SHIFT - 2));
+        let sector = try!(cache.load(self.device, lba));
+        Ok(get32(sector, (cluster as usize * 4) % SECTOR_SIZE))
+    }
+
+    // Move the chain to the cluster with the given index in the file.
+    // Returns false if the chain ends first.
+    fn seek_cluster(&self, chain: &mut Chain, index: u32) -> Result<bool, ErrorStr> {
+        if chain.file.contiguous {
+            if index >= self.cluster_count {
+                return Err(ErrorStr::new(strlit!("exFAT file extends past the cluster heap")));
+            }
+            chain.index = index;
+            chain.cluster = chain.file.first_cluster.wrapping_add(index);
+        } else {
+            if index < chain.index {
+                chain.index = 0;
+                chain.cluster = chain.file.first_cluster;
+            }
+            while chain.index < index {
+                try!(self.check_cluster(chain.cluster));
+                let next = try!(self.fat_entry(chain.cluster, &mut chain.fat));
+                if next == END_OF_CHAIN {
+                    return Ok(false);
+                }
+                chain.cluster = next;
+                chain.index += 1;
+                // A chain longer than the cluster heap must contain a loop.
+                if chain.index >= self.cluster_count {
+                    return Err(ErrorStr::new(strlit!("exFAT cluster chain contains a loop")));
+                }
+            }
+        }
+        try!(self.check_cluster(chain.cluster));
+        Ok(true)
+    }
+
+    fn chain_sector(&self, chain: &mut Chain, index: u32) ->
+            Result<Option<SectorIndex>, ErrorStr> {
+        if !try!(self.seek_cluster(chain, index >> self.cluster_shift)) {
+            return Ok(None);
+        }
+        let offset = index & ((1 << self.cluster_shift) - 1);
+        Ok(Some(self.cluster_sector(chain.cluster) + offset))
+    }
+
+    fn is_allocated(&self, cursor: &mut Cursor, cluster: u32) -> Result<bool, ErrorStr> {
+        let bitmap_chain = match cursor.bitmap_chain {
+            None => return Ok(true),
+            Some(ref mut chain) => chain,
+        };
+        let bit = cluster - FIRST_CLUSTER;
+        let byte = bit >> 3;
+        let lba = match try!(self.chain_sector(bitmap_chain, byte >> SECTOR_SHIFT)) {
+            None => return Err(ErrorStr::new(strlit!("exFAT allocation bitmap is too small"))),
+            Some(lba) => lba,
+        };
+        let sector = try!(cursor.bitmap.load(self.device, lba));
+        Ok(sector[byte as usize % SECTOR_SIZE] & (1 << (bit & 7)) != 0)
+    }
+
+    // Map a sector index within the cursor's file to a device sector.
+    fn file_sector(&self, cursor: &mut Cursor, index: u32) -> Result<SectorIndex, ErrorStr> {
+        let lba = match try!(self.chain_sector(&mut cursor.chain, index)) {
+            None => return Err(ErrorStr::new(strlit!("exFAT cluster chain ends early"))),
+            Some(lba) => lba,
+        };
+        let cluster = cursor.chain.cluster;
+        if cursor.checked_cluster != cluster {
+            if !try!(self.is_allocated(cursor, cluster)) {
+                return Err(ErrorStr::new(strlit!("exFAT file uses a free cluster")));
+            }
+            cursor.checked_cluster = cluster;
+        }
+        Ok(lba)
+    }
+
+    pub fn iterate_dir<'b>(&'b self, dir: &File) -> DirIterator<'b, 'a> {
+        DirIterator {
+            volume: self,
+            dir: *dir,
+            cursor: Cursor::new(*dir, self.bitmap),
+            sector: SectorBuffer::new(),
+            entry_index: 0,
+            done: false,
+        }
+    }
+
+    fn upcase(&self, ch: u16) -> u16 {
+        let table = self.upcase;
+        if table.is_empty() {
+            return if ch >= b'a' as u16 && ch <= b'z' as u16 { ch - 32 } else { ch };
+        }
+        let mut code = 0u32;
+        let mut pos = 0;
+        while pos + 2 <= table.len() && code <= ch as u32 {
+            let mapped = get16(table, pos);
+            if mapped == 0xffff && pos + 4 <= table.len() {
+                code += get16(table, pos + 2) as u32;
+                pos += 4;
+            } else {
+                if code == ch as u32 {
+                    return mapped;
+                }
+                code += 1;
+                pos += 2;
+            }
+        }
+        ch
+    }
+
+    fn name_hash(&self, name: &[u16]) -> u16 {
+        let mut hash = 0u16;
+        for unit in name.iter() {
+            let upper = self.upcase(*unit);
+            hash = checksum16(hash, upper as u8);
+            hash = checksum16(hash, (upper >> 8) as u8);
+        }
+        hash
+    }
+
+    pub fn find(&self, dir: &File, name: &[u8]) -> Result<Option<File>, ErrorStr> {
+        let mut units = [0u16; MAX_NAME_UNITS];
+        let len = match utf8_to_utf16(name, &mut units) {
+            None => return Ok(None),
+            Some(len) => len,
+        };
+        let units = &units[..len];
+        let hash = self.name_hash(units);
+        let mut iter = self.iterate_dir(dir);
+        while let Some(set) = try!(iter.next_set()) {
+            if set.name_hash != hash || set.name_len != len {
+                continue;
+            }
+            let same = set.name[..len].iter().zip(units.iter())
+                .all(|(a, b)| self.upcase(*a) == self.upcase(*b));
+            if same {
+                return Ok(Some(set.file));
+            }
+        }
+        Ok(None)
+    }
+
+    // Look up a '/'-separated path, relative to the root directory.
+    pub fn lookup(&self, path: &[u8]) -> Result<Option<File>, ErrorStr> {
+        let mut file = self.root;
+        for component in path.split(|c| *c == b'/') {
+            if component.is_empty() {
+                continue;
+            }
+            if !file.is_dir {
+                return Ok(None);
+            }
+            file = match try!(self.find(&file, component)) {
+                None => return Ok(None),
+                Some(file) => file,
+            };
+        }
+        Ok(Some(file))
+    }
+
+    // Read a whole file into the front of the buffer and return its size.
+    // Bytes past the file's valid data length read as zero.
+    pub fn read_contents(&self, file: &File, buffer: &mut [u8]) -> Result<u32, ErrorStr> {
+        if file.size > buffer.len() as u64 {
+            return Err(ErrorStr::new(strlit!("file is too large for the buffer")));
+        }
+        let size = file.size as usize;
+        let valid = cmp::min(file.valid_size, file.size) as usize;
+        let mut cursor = Cursor::new(*file, self.bitmap);
+        let cluster_sectors = 1u32 << self.cluster_shift;
+
+        // Read whole sectors straight into the buffer, at most a cluster at a
+        // time, since the next cluster may be anywhere.
+        let whole = (valid >> SECTOR_SHIFT) as u32;
+        let mut index = 0;
+        while index < whole {
+            let lba = try!(self.file_sector(&mut cursor, index));
+            let count = cmp::min(cluster_sectors - (index & (cluster_sectors - 1)),
+                                 whole - index);
+            let start = index as usize * SECTOR_SIZE;
+            let end = start + count as usize * SECTOR_SIZE;
+            try!(self.device.read_sectors(lba, &mut buffer[start..end]));
+            index += count;
+        }
+        let mut done = whole as usize * SECTOR_SIZE;
+        if done < valid {
+            let lba = try!(self.file_sector(&mut cursor, index));
+            let mut tail = [0u8; SECTOR_SIZE];
+            try!(self.device.read_sectors(lba, &mut tail));
+            copy_bytes(&mut buffer[done..valid], &tail);
+            done = valid;
+        }
+        for b in buffer[done..size].iter_mut() {
+            *b = 0;
+        }
+        Ok(size as u32)
+    }
+}
+
+impl<'a> FileSource for Volume<'a> {
+    fn file_size(&self, path: &[u8]) -> Result<Option<u32>, ErrorStr> {
+        match try!(self.lookup(path)) {
+            None => Ok(None),
+            Some(file) => {
+                if file.size > 0xffff_ffff {
+                    return Err(ErrorStr::new(strlit!("file is too large")));
+                }
+                Ok(Some(file.size as u32))
+            }
+        }
+    }
+
+    fn read_file(&self, path: &[u8], buffer: &mut [u8]) -> Result<u32, ErrorStr> {
+        match try!(self.lookup(path)) {
+            None => Err(ErrorStr::new(strlit!("file not found"))),
+            Some(file) => self.read_contents(&file, buffer),
+        }
+    }
+}
+
+// A directory entry set whose checksum is valid.
+struct EntrySet {
+    file: File,
+    name_hash: u16,
+    name: [u16; MAX_NAME_UNITS],
+    name_len: usize,
+}
+
+pub struct DirIterator<'a, 'b: 'a> {
+    volume: &'a Volume<'b>,
+    dir: File,
+    cursor: Cursor,
+    sector: SectorBuffer,
+    entry_index: u32,
+    done: bool,
+}
+
+impl<'a, 'b> DirIterator<'a, 'b> {
+    // Return the next raw entry, or None at the end of the directory.
+    fn next_entry(&mut self) -> Result<Option<[u8; ENTRY_SIZE]>, ErrorStr> {
+        let offset = (self.entry_index as u64) << ENTRY_SHIFT;
+        if self.done || offset >= self.dir.size {
+            return Ok(None);
+        }
+        let lba = try!(self.volume.file_sector(&mut self.cursor,
+                                               (offset >> SECTOR_SHIFT) as u32));
+        let sector = try!(self.sector.load(self.volume.device, lba));
+        let start = offset as usize % SECTOR_SIZE;
+        let mut entry = [0u8; ENTRY_SIZE];
+        copy_bytes(&mut entry, &sector[start..]);
+        self.entry_index += 1;
+        if entry[0] == ENTRY_END {
+            self.done = true;
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+
+    // Return the next file entry set, skipping damaged ones with a warning.
+    fn next_set(&mut self) -> Result<Option<EntrySet>, ErrorStr> {
+        loop {
+            let primary = match try!(self.next_entry()) {
+                None => return Ok(None),
+                Some(entry) => entry,
+            };
+            if primary[0] != ENTRY_FILE {
+                continue;
+            }
+            let secondary_count = primary[1] as usize;
+            if secondary_count < 2 || secondary_count > MAX_SECONDARY_ENTRIES {
+                log_warn!("skipping a damaged exFAT directory entry set");
+                continue;
+            }
+
+            let mut set = EntrySet {
+                file: File::new(0, 0),
+                name_hash: 0,
+                name: [0u16; MAX_NAME_UNITS],
+                name_len: 0,
+            };
+            set.file.is_dir = get16(&primary, 4) & ATTR_DIRECTORY != 0;
+            let mut checksum = 0u16;
+            for (i, b) in primary.iter().enumerate() {
+                if i != 2 && i != 3 {
+                    checksum = checksum16(checksum, *b);
+                }
+            }
+            let mut name_total = 0;
+            let mut valid = true;
+            for i in 0..secondary_count {
+                let entry = match try!(self.next_entry()) {
+                    None => return Ok(None),
+                    Some(entry) => entry,
+                };
+                for b in entry.iter() {
+                    checksum = checksum16(checksum, *b);
+                }
+                if entry[0] & ENTRY_IN_USE == 0 {
+                    valid = false;
+                } else if i == 0 {
+                    valid = entry[0] == ENTRY_STREAM;
+                    set.file.contiguous = entry[1] & FLAG_NO_FAT_CHAIN != 0;
+                    name_total = entry[3] as usize;
+                    set.name_hash = get16(&entry, 4);
+                    set.file.valid_size = get64(&entry, 8);
+                    set.file.first_cluster = get32(&entry, 20);
+                    set.file.size = get64(&entry, 24);
+                } else if entry[0] == ENTRY_NAME {
+                    for unit in 0..NAME_UNITS_PER_ENTRY {
+                        if set.name_len < name_total {
+                            set.name[set.name_len] = get16(&entry, 2 + unit * 2);
+                            set.name_len += 1;
+                        }
+                    }
+                }
+            }
+            if !valid || checksum != get16(&primary, 2) || name_total == 0 ||
+                    set.name_len != name_total {
+                log_warn!("skipping a damaged exFAT directory entry set");
+                continue;
+            }
+            return Ok(Some(set));
+        }
+    }
+
+    pub fn next(&mut self) -> Result<Option<DirEntry>, ErrorStr> {
+        let set = match try!(self.next_set()) {
+            None => return Ok(None),
+            Some(set) => set,
+        };
+        let mut entry = DirEntry {
+            file: set.file,
+            name: [0u8; MAX_NAME_BYTES],
+            name_len: 0,
+        };
+        utf16_to_utf8(&set.name[..set.name_len], &mut entry.name, &mut entry.name_len);
+        Ok(Some(entry))
+    }
+}
+
+// Read and check the boot region starting at `lba`, and return its boot
+// sector.
+fn read_boot_region(device: &BlockDevice, lba: SectorIndex) ->
+        Result<[u8; SECTOR_SIZE], ErrorStr> {
+    let mut boot = [0u8; SECTOR_SIZE];
+    try!(device.read_sectors(lba, &mut boot));
+    let sector_shift = boot[108];
+    if &boot[0..11] != &b"\xeb\x76\x90EXFAT   "[..] || boot[510] != 0x55 ||
+            boot[511] != 0xaa || sector_shift < MIN_SECTOR_SHIFT ||
+            sector_shift > MAX_SECTOR_SHIFT {
+        return Err(ErrorStr::new(strlit!("not an exFAT boot sector")));
+    }
+
+    // The checksum covers the first eleven exFAT sectors, except for the
+    // volume flags and percent-in-use fields, which change while mounted.
+    // The twelfth sector is filled with copies of it.
+    let scale = 1u32 << (sector_shift - MIN_SECTOR_SHIFT);
+    let mut checksum = 0u32;
+    let mut sector = [0u8; SECTOR_SIZE];
+    for index in 0..BOOT_REGION_SECTORS * scale {
+        try!(device.read_sectors(lba + index, &mut sector));
+        if index >= (BOOT_REGION_SECTORS - 1) * scale {
+            if sector.chunks(4).any(|word| get32(word, 0) != checksum) {
+                return Err(ErrorStr::new(strlit!("exFAT boot region checksum mismatch")));
+            }
+            continue;
+        }
+        for (i, b) in sector.iter().enumerate() {
+            if index == 0 && (i == 106 || i == 107 || i == 112) {
+                continue;
+            }
+            checksum = checksum32(checksum, *b);
+        }
+    }
+    Ok(boot)
+}
+
+// The backup boot region starts at exFAT sector 12, whose location depends on
+// the exFAT sector size recorded in it.
+fn read_backup_boot_region(device: &BlockDevice, start_sector: SectorIndex) ->
+        Result<[u8; SECTOR_SIZE], ErrorStr> {
+    for shift in MIN_SECTOR_SHIFT..MAX_SECTOR_SHIFT + 1 {
+        let lba = start_sector + (BOOT_REGION_SECTORS << (shift - MIN_SECTOR_SHIFT));
+        if let Ok(boot) = read_boot_region(device, lba) {
+            if boot[108] == shift {
+                return Ok(boot);
+            }
+        }
+    }
+    Err(ErrorStr::new(strlit!("no valid exFAT boot region")))
+}
+
+// Load the up-case table into `buffer` and check its checksum.
+fn load_upcase_table<'a>(volume: &Volume, file: File, expected: u32,
+                         buffer: &'a mut UpcaseBuffer) -> Result<&'a [u8], ErrorStr> {
+    if file.size > MAX_UPCASE_SIZE as u64 {
+        log_warn!("exFAT up-case table is too large; only ASCII case is ignored");
+        return Ok(&[]);
+    }
+    let table = &mut buffer[..file.size as usize];
+    try!(volume.read_contents(&file, table));
+    let checksum = table.iter().fold(0u32, |sum, b| checksum32(sum, *b));
+    if checksum != expected {
+        return Err(ErrorStr::new(strlit!("exFAT up-case table checksum mismatch")));
+    }
+    Ok(table)
+}
+
+// Open the exFAT volume starting at `start_sector` on the device.  Its
+// up-case table is loaded into `upcase_buffer`.
+pub fn open_volume<'a>(device: &'a BlockDevice, start_sector: SectorIndex,
+                       upcase_buffer: &'a mut UpcaseBuffer) -> Result<Volume<'a>, ErrorStr> {
+    if device.sector_size() != SECTOR_SIZE {
+        return Err(ErrorStr::new(strlit!("exFAT support needs 512-byte sectors")));
+    }
+    let boot = match read_boot_region(device, start_sector) {
+        Ok(boot) => boot,
+        Err(err) => {
+            let boot = try!(read_backup_boot_region(device, start_sector));
+            log_warn!("exFAT main boot region: {}; using the backup", err);
+            boot
+        }
+    };
+    if boot[11..64].iter().any(|b| *b != 0) || boot[105] != 1 {
+        return Err(ErrorStr::new(strlit!("unsupported exFAT version")));
+    }
+
+    let sector_shift = (boot[108] - MIN_SECTOR_SHIFT) as u32;
+    let cluster_shift = sector_shift + boot[109] as u32;
+    let fat_count = boot[110] as u32;
+    let fat_offset = get32(&boot, 80) as u64;
+    let fat_length = get32(&boot, 84) as u64;
+    let heap_offset = get32(&boot, 88) as u64;
+    let cluster_count = get32(&boot, 92);
+    if boot[108] as u32 + boot[109] as u32 > MAX_CLUSTER_SHIFT as u32 ||
+            fat_count == 0 || fat_count > 2 || cluster_count == 0 ||
+            fat_length << (sector_shift + SECTOR_SHIFT) < (cluster_count as u64 + 2) * 4 ||
+            fat_offset + fat_length * fat_count as u64 > heap_offset {
+        return Err(ErrorStr::new(strlit!("bad exFAT boot sector fields")));
+    }
+    let heap_end = start_sector as u64 + (heap_offset << sector_shift) +
+        ((cluster_count as u64) << cluster_shift);
+    if heap_end > (1 << 32) {
+        return Err(ErrorStr::new(strlit!("exFAT volume extends beyond 2TiB")));
+    }
+
+    // With two FATs (TexFAT), the volume flags say which one is active, and
+    // each FAT has its own allocation bitmap.
+    let active_fat = if fat_count == 2 && get16(&boot, 106) & VOLUME_FLAG_ACTIVE_FAT != 0 {
+        1
+    } else {
+        0
+    };
+    let mut volume = Volume {
+        device: device,
+        start_sector: start_sector,
+        fat_sector: ((fat_offset + active_fat * fat_length) << sector_shift) as u32,
+        heap_sector: (heap_offset << sector_shift) as u32,
+        cluster_count: cluster_count,
+        cluster_shift: cluster_shift,
+        root: File::new(get32(&boot, 96), 0),
+        bitmap: None,
+        upcase: &[],
+        serial_number: get32(&boot, 100),
+    };
+
+    // The root directory has no recorded size, so measure its chain.
+    let mut chain = Chain::new(volume.root);
+    let mut clusters = 1;
+    while try!(volume.seek_cluster(&mut chain, clusters)) {
+        clusters += 1;
+    }
+    volume.root.size = (clusters as u64) << (cluster_shift + SECTOR_SHIFT);
+    volume.root.is_dir = true;
+
+    // The root directory holds the allocation bitmap and up-case table
+    // entries.  Its own clusters are only checked against the bitmap once
+    // the bitmap has been found.
+    let mut upcase = None;
+    let bitmap = {
+        let mut iter = volume.iterate_dir(&volume.root);
+        while let Some(entry) = try!(iter.next_entry()) {
+            let file = File::new(get32(&entry, 20), get64(&entry, 24));
+            match entry[0] {
+                ENTRY_BITMAP if (entry[1] & 1) as u64 == active_fat => {
+                    if file.size < (cluster_count as u64 + 7) >> 3 {
+                        return Err(ErrorStr::new(
+                            strlit!("exFAT allocation bitmap is too small")));
+                    }
+                    iter.cursor.bitmap_chain = Some(Chain::new(file));
+                },
+                ENTRY_UPCASE => upcase = Some((file, get32(&entry, 4))),
+                _ => {},
+            }
+        }
+        iter.cursor.bitmap_chain.map(|chain| chain.file)
+    };
+    if bitmap.is_none() {
+        return Err(ErrorStr::new(strlit!("exFAT allocation bitmap not found")));
+    }
+    volume.bitmap = bitmap;
+    if let Some((file, checksum)) = upcase {
+        volume.upcase = try!(load_upcase_table(&volume, file, checksum, upcase_buffer));
+    }
+    Ok(volume)
+}
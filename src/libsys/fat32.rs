@@ -0,0 +1,2271 @@
+// FAT filesystem reader, for FAT12, FAT16, and FAT32 volumes.
+//
+// The FAT type is determined by the cluster count, as the specification
+// requires.  FAT12 and FAT16 volumes keep their root directory in a fixed
+// region between the FATs and the data area rather than in a cluster chain;
+// internally, that region is "cluster 0", which is also what a ".." entry
+// holds when its parent is the root directory.
+//
+// stage1 uses this to load STAGE2.BIN from the root of the pcboot volume, so
+// the code it needs must stay small.  stage2 can also resolve paths such as
+// "/boot/linux/vmlinuz" through subdirectories.  Path components match either
+// the VFAT long name or the 8.3 short name of an entry, ignoring case.  Files
+// too large for one buffer can be read in pieces through a `File` handle.
+//
+// Reads use the active FAT, which is FAT #1 unless a FAT32 volume has
+// mirroring disabled.  On a mirrored volume, a FAT block that cannot be read,
+// or a chain entry that makes no sense, is taken from another copy instead.
+//
+// stage2 can also create, overwrite, extend, and delete files.  Each update is
+// ordered so that losing power part way through leaves, at worst, allocated
+// clusters that nothing refers to, which a disk check reclaims, and never a
+// directory entry that refers to unwritten data or to free clusters:
+//  - New data is written to newly allocated clusters, and their FAT entries
+//    are written to every FAT copy, before a directory entry points at them.
+//  - An overwritten file's old clusters are freed only after its entry points
+//    at the new ones, and a deleted file's clusters only after its entries
+//    are marked deleted.
+//  - The FSInfo free-cluster count and next-free hint are written last; they
+//    are only hints.
+
+use core;
+use core::cmp;
+use core::convert::From;
+use core::fmt;
+
+use rtc;
+use rtc::DateTime;
+use unicode::{next_utf8_char, push_utf8, utf16_to_utf8, utf8_to_utf16};
+use {copy_bytes, get16, get32, BlockDevice, ErrorStr, SectorIndex, StrLit, StrRef};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+#[allow(dead_code)]
+pub struct Fat32Volume<'a> {
+    disk: &'a BlockDevice,
+    fat_type: FatType,
+    fsinfo_sec: Option<u32>,        // FAT32 only
+    start_fat_sector: u32,
+    start_data_sector: u32,
+    fat_count: u8,
+    active_fat: u8,                 // the copy that reads use
+    mirrored: bool,                 // whether writes go to every copy
+    sec_per_fat: u32,
+    root_dir_clust: u32,            // 0 for FAT12/FAT16
+    root_dir_sector: u32,           // FAT12/FAT16 only
+    root_dir_sectors: u32,          // FAT12/FAT16 only
+    sec_per_clust: u8,
+    total_clusters: u32,
+    oem_name: [u8; 8],
+    label: Option<[u8; 11]>,
+    serial_number: Option<u32>,
+}
+
+impl<'a> Fat32Volume<'a> {
+    pub fn fat_type(&self) -> FatType {
+        self.fat_type
+    }
+}
+
+// Volume metadata, as reported by `volume_info`.
+pub struct VolumeInfo {
+    oem_name: [u8; 8],
+    label: Option<[u8; 11]>,
+    pub serial_number: Option<u32>,
+    pub cluster_size: u32,          // in bytes
+    pub total_clusters: u32,
+    pub free_clusters: u32,
+    pub dirty: bool,                // not cleanly unmounted
+}
+
+fn trim_spaces(text: &[u8]) -> &[u8] {
+    &text[..text.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1)]
+}
+
+impl VolumeInfo {
+    pub fn oem_name(&self) -> &[u8] {
+        trim_spaces(&self.oem_name)
+    }
+
+    pub fn label(&self) -> Option<&[u8]> {
+        self.label.as_ref().map(|label| trim_spaces(label))
+    }
+
+    pub fn total_bytes(&self) -> u64 {
+        self.total_clusters as u64 * self.cluster_size as u64
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_clusters as u64 * self.cluster_size as u64
+    }
+}
+
+// Everything that can go wrong with a FAT volume.  Damage is reported rather
+// than halting, so that stage2 can carry on with other files and volumes.
+pub enum Error {
+    Io(ErrorStr),
+    BadBpb(StrLit),                 // names the offending VBR field
+    ClusterOutOfRange(u32),
+    ChainLoop(u32),                 // the chain's first cluster
+    TruncatedChain,                 // a chain ends before its file does
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    ReadOnly,
+    InvalidName,
+    VolumeFull,
+    DirectoryFull,
+    InvalidOffset,
+    BufferTooSmall,
+}
+
+impl From<ErrorStr> for Error {
+    fn from(err: ErrorStr) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl Error {
+    // The error without its details, for stage1, which cannot format them.
+    pub fn description(&self) -> StrLit {
+        match *self {
+            Error::Io(ref err) => err.msg(),
+            Error::BadBpb(_) => strlit!("FAT BPB field is invalid"),
+            Error::ClusterOutOfRange(_) => strlit!("FAT cluster is out of range"),
+            Error::ChainLoop(_) => strlit!("FAT chain loops"),
+            Error::TruncatedChain => strlit!("FAT chain is shorter than its file"),
+            Error::NotFound => strlit!("file not found"),
+            Error::NotADirectory => strlit!("not a directory"),
+            Error::IsADirectory => strlit!("path is a directory"),
+            Error::ReadOnly => strlit!("file is read-only"),
+            Error::InvalidName => strlit!("invalid file name"),
+            Error::VolumeFull => strlit!("FAT volume is full"),
+            Error::DirectoryFull => strlit!("directory is full"),
+            Error::InvalidOffset => strlit!("write offset is out of range"),
+            Error::BufferTooSmall => strlit!("buffer is too small for the file"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref err) => write!(f, "FAT I/O error: {}", err),
+            Error::BadBpb(field) => write!(f, "FAT BPB field is invalid: {}", field),
+            Error::ClusterOutOfRange(cluster) =>
+                write!(f, "FAT cluster {} is out of range", cluster),
+            Error::ChainLoop(cluster) =>
+                write!(f, "FAT chain starting at cluster {} loops", cluster),
+            _ => f.write_str(self.description()),
+        }
+    }
+}
+
+// FAT32 BPB flags.  When mirroring is disabled, only the active FAT is
+// maintained, and the others may be stale.
+const FLAGS_NO_MIRRORING: u16 = 0x0080;
+const FLAGS_ACTIVE_FAT_MASK: u16 = 0x000f;
+
+// The extended BPB follows the FAT32 fields on FAT32 volumes, and the common
+// fields on FAT12 and FAT16 volumes.  Signature 0x28 has only the serial
+// number; 0x29 adds the label and type.
+const EBPB_OFFSET_FAT16: usize = 36;
+const EBPB_OFFSET_FAT32: usize = 64;
+const EBPB_SIGNATURE: usize = 2;
+const EBPB_SERIAL_NUMBER: usize = 3;
+const EBPB_LABEL: usize = 7;
+
+// FAT entry 1 bits that Windows clears while a volume is mounted or after it
+// finds an error.
+const FAT32_CLEAN_SHUTDOWN: u32 = 0x0800_0000;
+const FAT16_CLEAN_SHUTDOWN: u32 = 0x8000;
+
+// Volumes with fewer clusters than these are FAT12 and FAT16, respectively.
+const FAT12_MAX_CLUSTERS: u32 = 4085;
+const FAT16_MAX_CLUSTERS: u32 = 65525;
+
+#[repr(C, packed)]
+// The integer fields are little-endian on disk and in memory.
+struct Fat32VBR {
+    // These fields are the same for all of FAT12, FAT16, and FAT32.
+    /*0*/   jmp: [u8; 3],               // unneeded
+    /*3*/   oem_name: [u8; 8],          // padded with spaces
+    /*11*/  bytes_per_sec: u16,         // XXX: Can this *really* be non-512?
+    /*13*/  sec_per_clust: u8,
+    /*14*/  reserved_sec_cnt: u16,
+    /*16*/  fat_count: u8,
+    /*17*/  num_of_dir_entries: u16,    // XXX: Is this used with FAT32?
+    /*19*/  total_sectors_16: u16,
+    /*21*/  media_descriptor_type: u8,
+    /*22*/  sec_per_fat_16: u16,        // FAT12/FAT16 only (0 on FAT32)
+    /*24*/  sec_per_track: u16,
+    /*26*/  num_heads: u16,
+    /*28*/  hidden_sec_cnt: u32,
+    /*32*/  total_sectors_32: u32,
+    // These fields are FAT32-specific.
+    /*36*/  sec_per_fat_32: u32,
+    /*40*/  flags: u16,                 // FAT32 mirroring flags
+    /*42*/  fat_version_number: u16,    // XXX: What version numbers are there?
+    /*44*/  root_dir_clust: u32,        // cluster # of root directory
+    /*48*/  fsinfo_sec: u16,            // sector # of fsinfo struct (within reserved area)
+    /*50*/  backup_vbr_sec: u16,        // sector # of backup VBR (within reserved area)
+    /*52*/  _reserved: [u8; 12],
+    /*64*/  drive_number: u8,
+    /*65*/  winnt_flags: u8,            // XXX: what is this?
+    /*66*/  signature: u8,              // backward-compat?  osdev says: must be 0x28 or 0x29
+    /*67*/  serial_number: u32,
+    /*71*/  volume_label: [u8; 11],     // padded with spaces
+    /*82*/  fs_type: [u8; 8],           // the string "FAT32   "
+    /*90*/  boot_code: [u8; 420],
+    /*510*/ boot_signature: u16,        // 0xaa55
+}
+
+#[repr(C, packed)]
+#[allow(dead_code)]
+struct DirEntry {
+    /*0*/   name: [u8; 11],             // 8.3 filename, padded with spaces
+    /*11*/  attr: u8,                   // attributes
+    /*12*/  winnt_reserved: u8,         // reserved for Windows NT
+    // ctime == creation time, atime == last accessed, mtime == last modified
+    /*13*/  ctime_sec10: u8,
+    /*14*/  ctime_time: u16,
+    /*16*/  ctime_date: u16,
+    /*18*/  atime_date: u16,
+    /*20*/  cluster_hi: u16,            // high 16 bits of cluster number
+    /*22*/  mtime_time: u16,
+    /*24*/  mtime_date: u16,
+    /*26*/  cluster_lo: u16,
+    /*28*/  size: u32,                  // size in bytes
+}
+
+#[allow(dead_code)] const ATTR_READ_ONLY: u8    = 0x01;
+#[allow(dead_code)] const ATTR_HIDDEN: u8       = 0x02;
+#[allow(dead_code)] const ATTR_SYSTEM: u8       = 0x04;
+#[allow(dead_code)] const ATTR_VOLUME_ID: u8    = 0x08;
+#[allow(dead_code)] const ATTR_DIRECTORY: u8    = 0x10;
+#[allow(dead_code)] const ATTR_ARCHIVE: u8      = 0x20;
+
+#[allow(dead_code)]
+const ALL_FILE_ATTRIBUTES: u8 =
+    ATTR_READ_ONLY | ATTR_HIDDEN | ATTR_SYSTEM | ATTR_ARCHIVE;
+
+pub fn open_volume<'a>(disk: &'a BlockDevice, sector: SectorIndex) ->
+        Result<Fat32Volume<'a>, Error> {
+    let mut vbr_data = [0u8; 512];
+    try!(disk.read_sectors(sector, &mut vbr_data));
+    let vbr: Fat32VBR = unsafe { core::mem::transmute(vbr_data) };
+
+    if vbr.boot_signature != 0xaa55 {
+        return Err(Error::BadBpb(strlit!("boot signature")));
+    }
+    if vbr.bytes_per_sec != 512 {
+        return Err(Error::BadBpb(strlit!("bytes per sector")));
+    }
+    if vbr.sec_per_clust == 0 || !vbr.sec_per_clust.is_power_of_two() {
+        return Err(Error::BadBpb(strlit!("sectors per cluster")));
+    }
+    if vbr.reserved_sec_cnt == 0 {
+        return Err(Error::BadBpb(strlit!("reserved sector count")));
+    }
+    if vbr.fat_count == 0 {
+        return Err(Error::BadBpb(strlit!("FAT count")));
+    }
+
+    let sector_lba = sector as u32;
+    let sec_per_fat = if vbr.sec_per_fat_16 != 0 {
+        vbr.sec_per_fat_16 as u32
+    } else {
+        vbr.sec_per_fat_32
+    };
+    let total_sectors = if vbr.total_sectors_16 != 0 {
+        vbr.total_sectors_16 as u32
+    } else {
+        vbr.total_sectors_32
+    };
+    if sec_per_fat == 0 {
+        return Err(Error::BadBpb(strlit!("sectors per FAT")));
+    }
+    let fat_area_sectors = match (vbr.fat_count as u32).checked_mul(sec_per_fat) {
+        None => return Err(Error::BadBpb(strlit!("sectors per FAT"))),
+        Some(sectors) => sectors,
+    };
+    let reserved_sec_cnt = vbr.reserved_sec_cnt as u32;
+    // Always 0 on FAT32.
+    let root_dir_sectors = (vbr.num_of_dir_entries as u32 * 32 + 511) / 512;
+    let meta_sectors = reserved_sec_cnt as u64 + fat_area_sectors as u64 +
+        root_dir_sectors as u64;
+    if meta_sectors >= total_sectors as u64 {
+        return Err(Error::BadBpb(strlit!("total sectors")));
+    }
+    let meta_sectors = meta_sectors as u32;
+    if sector_lba.checked_add(total_sectors).is_none() {
+        return Err(Error::BadBpb(strlit!("total sectors")));
+    }
+    let total_clusters = (total_sectors - meta_sectors) / (vbr.sec_per_clust as u32);
+
+    let fat_type = if total_clusters < FAT12_MAX_CLUSTERS {
+        FatType::Fat12
+    } else if total_clusters < FAT16_MAX_CLUSTERS {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    };
+
+    // The FAT must have an entry for every cluster.
+    let entries_per_sector = match fat_type {
+        FatType::Fat12 => 341,
+        FatType::Fat16 => 256,
+        FatType::Fat32 => 128,
+    };
+    let fat_entries = sec_per_fat as u64 * entries_per_sector;
+    if fat_entries < total_clusters as u64 + 2 {
+        return Err(Error::BadBpb(strlit!("sectors per FAT")));
+    }
+
+    let (active_fat, mirrored) =
+        if fat_type == FatType::Fat32 && vbr.flags & FLAGS_NO_MIRRORING != 0 {
+            let active_fat = (vbr.flags & FLAGS_ACTIVE_FAT_MASK) as u8;
+            if active_fat >= vbr.fat_count {
+                return Err(Error::BadBpb(strlit!("active FAT")));
+            }
+            (active_fat, false)
+        } else {
+            (0, true)
+        };
+
+    let (root_dir_clust, fsinfo_sec) = if fat_type == FatType::Fat32 {
+        if vbr.root_dir_clust < 2 || vbr.root_dir_clust - 2 >= total_clusters {
+            return Err(Error::BadBpb(strlit!("root directory cluster")));
+        }
+        (vbr.root_dir_clust, Some(sector_lba + (vbr.fsinfo_sec as u32)))
+    } else {
+        if root_dir_sectors == 0 {
+            return Err(Error::BadBpb(strlit!("root directory entry count")));
+        }
+        (0, None)
+    };
+
+    let ebpb = if fat_type == FatType::Fat32 { EBPB_OFFSET_FAT32 } else { EBPB_OFFSET_FAT16 };
+    let ebpb_signature = vbr_data[ebpb + EBPB_SIGNATURE];
+    let serial_number = if ebpb_signature == 0x28 || ebpb_signature == 0x29 {
+        Some(get32(&vbr_data, ebpb + EBPB_SERIAL_NUMBER))
+    } else {
+        None
+    };
+    let mut label = [0u8; 11];
+    copy_bytes(&mut label, &vbr_data[ebpb + EBPB_LABEL..]);
+    // Formatters write "NO NAME" when there is no label.
+    let label = if ebpb_signature == 0x29 && &label[..] != &b"NO NAME    "[..] &&
+            trim_spaces(&label).len() > 0 {
+        Some(label)
+    } else {
+        None
+    };
+
+    Ok(Fat32Volume {
+        disk: disk,
+        fat_type: fat_type,
+        fsinfo_sec: fsinfo_sec,
+        start_fat_sector: sector_lba + reserved_sec_cnt,
+        start_data_sector: sector_lba + meta_sectors,
+        fat_count: vbr.fat_count,
+        active_fat: active_fat,
+        mirrored: mirrored,
+        sec_per_fat: sec_per_fat,
+        root_dir_clust: root_dir_clust,
+        root_dir_sector: sector_lba + reserved_sec_cnt + fat_area_sectors,
+        root_dir_sectors: root_dir_sectors,
+        sec_per_clust: vbr.sec_per_clust,
+        total_clusters: total_clusters,
+        oem_name: vbr.oem_name,
+        label: label,
+        serial_number: serial_number,
+    })
+}
+
+const FAT_TABLE_CACHE_SIZE: usize = 1024;
+
+// FAT entry values, in FAT32 form.
+const FAT_FREE: u32 = 0;
+const FAT_END_OF_CHAIN: u32 = 0x0fff_ffff;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+const FSINFO_TRAIL_SIGNATURE: u32 = 0xaa55_0000;
+const FSINFO_FREE_COUNT: usize = 488;
+const FSINFO_NEXT_FREE: usize = 492;
+const FSINFO_UNKNOWN: u32 = 0xffff_ffff;
+
+fn put16(buffer: &mut [u8], offset: usize, value: u16) {
+    buffer[offset] = value as u8;
+    buffer[offset + 1] = (value >> 8) as u8;
+}
+
+fn put32(buffer: &mut [u8], offset: usize, value: u32) {
+    put16(buffer, offset, value as u16);
+    put16(buffer, offset + 2, (value >> 16) as u16);
+}
+
+// The byte offset of a cluster's entry within the FAT.
+fn entry_offset(volume: &Fat32Volume, cluster: u32) -> u32 {
+    match volume.fat_type {
+        FatType::Fat32 => cluster * 4,
+        FatType::Fat16 => cluster * 2,
+        FatType::Fat12 => cluster + cluster / 2,
+    }
+}
+
+// Decode a cluster's entry from the (up to) four little-endian bytes at its
+// offset.  FAT12 and FAT16 entries are widened so that their bad-cluster and
+// end-of-chain values match the FAT32 ones.
+fn decode_entry(volume: &Fat32Volume, raw: u32, cluster: u32) -> u32 {
+    let (value, widen) = match volume.fat_type {
+        FatType::Fat32 => return raw & 0x0fff_ffff,
+        FatType::Fat16 => (raw & 0xffff, 0x0fff_0000),
+        FatType::Fat12 => {
+            let pair = raw & 0xffff;
+            (if cluster & 1 == 0 { pair & 0xfff } else { pair >> 4 }, 0x0fff_f000)
+        },
+    };
+    if value >= (0x0fff_fff7 & !widen) { value | widen } else { value }
+}
+
+// The sector of the given FAT copy that holds the sector index `sector`.
+fn fat_sector(volume: &Fat32Volume, copy: u32, sector: u32) -> u32 {
+    volume.start_fat_sector + copy * volume.sec_per_fat + sector
+}
+
+fn is_valid_cluster(volume: &Fat32Volume, cluster: u32) -> bool {
+    cluster >= 2 && cluster - 2 < volume.total_clusters
+}
+
+fn cluster_sector(volume: &Fat32Volume, cluster: u32) -> u32 {
+    volume.start_data_sector + (cluster - 2) * (volume.sec_per_clust as u32)
+}
+
+// Read the FSInfo sector and return its LBA, or None if the volume has none
+// or its signatures are wrong.
+fn read_fsinfo(volume: &Fat32Volume, sector: &mut [u8; 512]) -> Result<Option<u32>, Error> {
+    let lba = match volume.fsinfo_sec {
+        None => return Ok(None),
+        Some(lba) => lba,
+    };
+    try!(volume.disk.read_sectors(lba, sector));
+    let valid = get32(sector, 0) == FSINFO_LEAD_SIGNATURE &&
+        get32(sector, 484) == FSINFO_STRUCT_SIGNATURE &&
+        get32(sector, 508) == FSINFO_TRAIL_SIGNATURE;
+    Ok(if valid { Some(lba) } else { None })
+}
+
+struct FatTable<'a> {
+    volume: &'a Fat32Volume<'a>,
+    cache_lba: Option<u32>,
+    cache_buffer: [u8; FAT_TABLE_CACHE_SIZE],
+    dirty: bool,
+    // Allocation state for updates.
+    next_free: u32,                 // where to look for a free cluster, or 0
+    allocated: u32,
+    freed: u32,
+}
+
+impl<'a> FatTable<'a> {
+    // Load the part of the active FAT containing the byte offset, and return
+    // the offset within the cache buffer.  If the block cannot be read and
+    // the FATs are mirrored, it is read from another copy instead.
+    fn load(&mut self, fat_offset: u32) -> Result<usize, Error> {
+        let cache_size = FAT_TABLE_CACHE_SIZE as u32;
+        let sector = fat_offset / cache_size * (cache_size / 512);
+        let offset = fat_offset % cache_size;
+
+        let cache_hit = match self.cache_lba {
+            None => false,
+            Some(cache_lba) => cache_lba == sector
+        };
+
+        if !cache_hit {
+            try!(self.flush());
+            self.cache_lba = None;
+            let volume = self.volume;
+            let active = volume.active_fat as u32;
+            let mut result = volume.disk.read_sectors(
+                fat_sector(volume, active, sector), &mut self.cache_buffer);
+            if result.is_err() && volume.mirrored {
+                for copy in 0..volume.fat_count as u32 {
+                    if copy == active {
+                        continue;
+                    }
+                    // stage1 links this, so avoid formatting.
+                    log_str!(::log::Level::Warn,
+                             "a FAT sector is unreadable; trying another FAT copy");
+                    result = volume.disk.read_sectors(
+                        fat_sector(volume, copy, sector), &mut self.cache_buffer);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+            }
+            try!(result);
+            self.cache_lba = Some(sector);
+        }
+        Ok(offset as usize)
+    }
+
+    fn get16(&mut self, fat_offset: u32) -> Result<u32, Error> {
+        // A FAT12 entry can straddle two cache blocks, so read it bytewise.
+        let low_offset = try!(self.load(fat_offset));
+        let low = self.cache_buffer[low_offset] as u32;
+        let high_offset = try!(self.load(fat_offset + 1));
+        let high = self.cache_buffer[high_offset] as u32;
+        Ok(low | (high << 8))
+    }
+
+    // Return the FAT entry for the cluster, in FAT32 form.
+    fn entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        if !is_valid_cluster(self.volume, cluster) {
+            return Err(Error::ClusterOutOfRange(cluster));
+        }
+        let raw = try!(self.raw_entry(cluster));
+        Ok(decode_entry(self.volume, raw, cluster))
+    }
+
+    // Return the bytes at a cluster's entry, which for FAT12 and FAT16 hold
+    // neighbouring entries as well.  Entries 0 and 1 are not clusters.
+    fn raw_entry(&mut self, cluster: u32) -> Result<u32, Error> {
+        let fat_offset = entry_offset(self.volume, cluster);
+        if self.volume.fat_type == FatType::Fat32 {
+            let offset = try!(self.load(fat_offset));
+            Ok(get32(&self.cache_buffer, offset))
+        } else {
+            self.get16(fat_offset)
+        }
+    }
+
+    // Read a cluster's entry from a given FAT copy, bypassing the cache.
+    fn entry_in_copy(&mut self, copy: u32, cluster: u32) -> Result<u32, Error> {
+        let volume = self.volume;
+        let fat_offset = entry_offset(volume, cluster);
+        let sector = fat_offset / 512;
+        // A FAT12 entry can straddle two sectors.
+        let count = cmp::min(2, volume.sec_per_fat - sector) as usize;
+        let mut buffer = [0u8; 1024];
+        try!(volume.disk.read_sectors(fat_sector(volume, copy, sector),
+                                      &mut buffer[..count * 512]));
+        let raw = get32(&buffer, (fat_offset % 512) as usize);
+        Ok(decode_entry(volume, raw, cluster))
+    }
+
+    // Return the cluster after this one in its chain, or None at the end of
+    // the chain.  A free or bad cluster in a chain means it was cut short.
+    // If the FATs are mirrored, an entry that cannot be part of a chain is
+    // checked against FAT #2, and its value is used if that one can.
+    fn next_cluster(&mut self, cluster: u32) -> Result<Option<u32>, Error> {
+        let volume = self.volume;
+        let mut next = try!(self.entry(cluster));
+        let usable = |next: u32| is_valid_cluster(volume, next) || next >= 0x0fff_fff8;
+        if !usable(next) && volume.mirrored && volume.fat_count > 1 {
+            match self.entry_in_copy(1, cluster) {
+                Ok(mirror) if usable(mirror) => {
+                    log_str!(::log::Level::Warn, "a FAT entry is bad; using FAT #2");
+                    next = mirror;
+                },
+                _ => {},
+            }
+        }
+        if is_valid_cluster(volume, next) {
+            Ok(Some(next))
+        } else if next >= 0x0fff_fff8 {
+            Ok(None)
+        } else if next < 2 || next == 0x0fff_fff7 {
+            Err(Error::TruncatedChain)
+        } else {
+            Err(Error::ClusterOutOfRange(next))
+        }
+    }
+
+    // Write the cached block back to every copy of the FAT, or only to the
+    // active one if mirroring is disabled.
+    fn flush(&mut self) -> Result<(), Error> {
+        let sector = match self.cache_lba {
+            Some(sector) if self.dirty => sector,
+            _ => return Ok(()),
+        };
+        let volume = self.volume;
+        // The last block of a FAT with an odd sector count extends past it.
+        let count = cmp::min((FAT_TABLE_CACHE_SIZE / 512) as u32, volume.sec_per_fat - sector);
+        for copy in 0..volume.fat_count as u32 {
+            if !volume.mirrored && copy != volume.active_fat as u32 {
+                continue;
+            }
+            try!(volume.disk.write_sectors(
+                fat_sector(volume, copy, sector),
+                &self.cache_buffer[..count as usize * 512]));
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn set_byte(&mut self, fat_offset: u32, value: u8) -> Result<(), Error> {
+        let offset = try!(self.load(fat_offset));
+        self.cache_buffer[offset] = value;
+        self.dirty = true;
+        Ok(())
+    }
+
+    // Set the FAT entry for the cluster to a FAT32-style value.  The change
+    // stays in the cache until it is flushed.
+    fn set_entry(&mut self, cluster: u32, value: u32) -> Result<(), Error> {
+        if !is_valid_cluster(self.volume, cluster) {
+            return Err(Error::ClusterOutOfRange(cluster));
+        }
+        match self.volume.fat_type {
+            FatType::Fat32 => {
+                // The top four bits are reserved and must be preserved.
+                let offset = try!(self.load(cluster * 4));
+                let old = get32(&self.cache_buffer, offset);
+                put32(&mut self.cache_buffer, offset, (old & 0xf000_0000) | (value & 0x0fff_ffff));
+                self.dirty = true;
+            },
+            FatType::Fat16 => {
+                try!(self.set_byte(cluster * 2, value as u8));
+                try!(self.set_byte(cluster * 2 + 1, (value >> 8) as u8));
+            },
+            FatType::Fat12 => {
+                let offset = cluster + cluster / 2;
+                let pair = try!(self.get16(offset));
+                let pair = if cluster & 1 == 0 {
+                    (pair & 0xf000) | (value & 0xfff)
+                } else {
+                    (pair & 0x000f) | ((value & 0xfff) << 4)
+                };
+                try!(self.set_byte(offset, pair as u8));
+                try!(self.set_byte(offset + 1, (pair >> 8) as u8));
+            },
+        }
+        Ok(())
+    }
+
+    // Find a free cluster and mark it as the end of a chain.  The search
+    // starts at the FSInfo next-free hint.
+    fn allocate_cluster(&mut self) -> Result<u32, Error> {
+        let volume = self.volume;
+        if self.next_free == 0 {
+            let mut sector = [0u8; 512];
+            let hint = if try!(read_fsinfo(volume, &mut sector)).is_some() {
+                get32(&sector, FSINFO_NEXT_FREE)
+            } else {
+                FSINFO_UNKNOWN
+            };
+            self.next_free = if is_valid_cluster(volume, hint) { hint } else { 2 };
+        }
+        let start = self.next_free - 2;
+        for i in 0..volume.total_clusters {
+            let index = if start + i < volume.total_clusters {
+                start + i
+            } else {
+                start + i - volume.total_clusters
+            };
+            let cluster = index + 2;
+            if try!(self.entry(cluster)) == FAT_FREE {
+                try!(self.set_entry(cluster, FAT_END_OF_CHAIN));
+                self.allocated += 1;
+                self.next_free = if index + 1 < volume.total_clusters { cluster + 1 } else { 2 };
+                return Ok(cluster);
+            }
+        }
+        Err(Error::VolumeFull)
+    }
+
+    // Allocate a chain of clusters, returning its first cluster, or 0 for
+    // an empty chain.
+    fn allocate_chain(&mut self, count: u32) -> Result<u32, Error> {
+        let mut first = 0;
+        let mut last = 0;
+        for _ in 0..count {
+            let linked = self.allocate_cluster().and_then(|cluster| {
+                if last != 0 {
+                    try!(self.set_entry(last, cluster));
+                }
+                Ok(cluster)
+            });
+            let cluster = match linked {
+                Ok(cluster) => cluster,
+                Err(err) => {
+                    let _ = self.free_chain(first);
+                    return Err(err);
+                }
+            };
+            if last == 0 {
+                first = cluster;
+            }
+            last = cluster;
+        }
+        Ok(first)
+    }
+
+    // Free every cluster of a chain.  A chain that loops back on itself
+    // stops at the first cluster already freed.
+    fn free_chain(&mut self, first: u32) -> Result<(), Error> {
+        let mut cluster = first;
+        while is_valid_cluster(self.volume, cluster) {
+            let next = try!(self.entry(cluster));
+            try!(self.set_entry(cluster, FAT_FREE));
+            self.freed += 1;
+            cluster = next;
+        }
+        Ok(())
+    }
+
+    fn chain_length(&mut self, first: u32, max_clusters: u32) -> Result<u32, Error> {
+        let mut count = 0;
+        let mut chain = iterate_cluster_chain(self, first);
+        chain.max_clusters = max_clusters;
+        while let Some(_) = try!(chain.next()) {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn last_cluster(&mut self, first: u32, max_clusters: u32) -> Result<u32, Error> {
+        let mut last = first;
+        let mut chain = iterate_cluster_chain(self, first);
+        chain.max_clusters = max_clusters;
+        while let Some(cluster) = try!(chain.next()) {
+            last = cluster;
+        }
+        Ok(last)
+    }
+
+    // Flush the FAT, then bring the FSInfo hints up to date.
+    fn commit(&mut self) -> Result<(), Error> {
+        try!(self.flush());
+        if self.allocated == 0 && self.freed == 0 {
+            return Ok(());
+        }
+        let volume = self.volume;
+        let mut sector = [0u8; 512];
+        let lba = match try!(read_fsinfo(volume, &mut sector)) {
+            None => return Ok(()),
+            Some(lba) => lba,
+        };
+        let free_count = get32(&sector, FSINFO_FREE_COUNT);
+        if free_count != FSINFO_UNKNOWN {
+            // A count that would go out of range was already wrong.
+            let free_count = free_count as u64 + self.freed as u64;
+            let free_count = if free_count >= self.allocated as u64 &&
+                    free_count - (self.allocated as u64) <= volume.total_clusters as u64 {
+                (free_count - self.allocated as u64) as u32
+            } else {
+                FSINFO_UNKNOWN
+            };
+            put32(&mut sector, FSINFO_FREE_COUNT, free_count);
+        }
+        if self.allocated > 0 {
+            put32(&mut sector, FSINFO_NEXT_FREE, self.next_free);
+        }
+        try!(volume.disk.write_sectors(lba, &sector));
+        self.allocated = 0;
+        self.freed = 0;
+        Ok(())
+    }
+}
+
+fn fat_table<'a>(volume: &'a Fat32Volume<'a>) -> FatTable<'a> {
+    FatTable {
+        volume: volume,
+        cache_lba: None,
+        cache_buffer: [0u8; FAT_TABLE_CACHE_SIZE],
+        dirty: false,
+        next_free: 0,
+        allocated: 0,
+        freed: 0,
+    }
+}
+
+// Following a chain that loops back on itself would never end, so a chain
+// is cut off once it is longer than its file could be, or than the volume.
+struct ClusterIterator<'a, 'b:'a> {
+    fat_table: &'a mut FatTable<'b>,
+    first: u32,
+    next : Option<u32>,
+    count: u32,
+    max_clusters: u32,
+}
+
+impl<'a, 'b> ClusterIterator<'a, 'b> {
+    fn next(&mut self) -> Result<Option<u32>, Error> {
+        match self.next {
+            None => Ok(None),
+            Some(cluster) => {
+                // Only the first cluster comes from a directory entry rather
+                // than from next_cluster, which checks the range.
+                if !is_valid_cluster(self.fat_table.volume, cluster) {
+                    return Err(Error::ClusterOutOfRange(cluster));
+                }
+                if self.count == self.max_clusters {
+                    return Err(Error::ChainLoop(self.first));
+                }
+                self.count += 1;
+                self.next = try!(self.fat_table.next_cluster(cluster));
+                Ok(Some(cluster))
+            }
+        }
+    }
+}
+
+fn iterate_cluster_chain<'a, 'b>(
+        fat_table: &'a mut FatTable<'b>,
+        cluster: u32) -> ClusterIterator<'a, 'b> {
+    let max_clusters = fat_table.volume.total_clusters;
+    ClusterIterator {
+        fat_table: fat_table,
+        first: cluster,
+        next: Some(cluster),
+        count: 0,
+        max_clusters: max_clusters,
+    }
+}
+
+struct SectorIterator<'a, 'b:'a> {
+    volume: &'b Fat32Volume<'b>,
+    cluster_iterator: ClusterIterator<'a, 'b>,
+    next_count: u32,
+    next_ret: u32,
+}
+
+impl<'a, 'b> SectorIterator<'a, 'b> {
+    fn next(&mut self) -> Result<Option<u32>, Error> {
+        if self.next_count == 0 {
+            match try!(self.cluster_iterator.next()) {
+                None => { return Ok(None); },
+                Some(cluster) => {
+                    self.next_ret = cluster_sector(self.volume, cluster);
+                    self.next_count = self.volume.sec_per_clust as u32;
+                }
+            }
+        }
+        let ret = self.next_ret;
+        self.next_ret += 1;
+        self.next_count -= 1;
+        Ok(Some(ret))
+    }
+}
+
+// Cluster 0 is the fixed root directory region of a FAT12/FAT16 volume.
+fn iterate_node_sectors<'a, 'b>(
+        fat_table: &'a mut FatTable<'b>,
+        cluster: u32) -> SectorIterator<'a, 'b> {
+    let volume = fat_table.volume;
+    if cluster == 0 {
+        let mut chain = iterate_cluster_chain(fat_table, 0);
+        chain.next = None;
+        return SectorIterator {
+            volume: volume,
+            cluster_iterator: chain,
+            next_count: volume.root_dir_sectors,
+            next_ret: volume.root_dir_sector,
+        };
+    }
+    SectorIterator {
+        volume: volume,
+        cluster_iterator: iterate_cluster_chain(fat_table, cluster),
+        next_count: 0,
+        next_ret: 0,
+    }
+}
+
+fn iterate_dir_sectors<'a, 'b>(
+        fat_table: &'a mut FatTable<'b>,
+        dir_cluster: u32) -> SectorIterator<'a, 'b> {
+    let max_clusters = max_dir_clusters(fat_table.volume);
+    let mut sectors = iterate_node_sectors(fat_table, dir_cluster);
+    sectors.cluster_iterator.max_clusters = max_clusters;
+    sectors
+}
+
+struct FragmentIterator<'a, 'b:'a> {
+    sector_iterator: SectorIterator<'a, 'b>,
+    max_sectors: u32,
+    queued: Option<u32>,
+}
+
+#[derive(Copy, Clone)]
+struct Fragment {
+    start_sector: u32,
+    sector_count: u32,
+}
+
+impl<'a, 'b> FragmentIterator<'a, 'b> {
+    fn next(&mut self) -> Result<Option<Fragment>, Error> {
+        let start_sector = match self.queued.take() {
+            Some(sector) => sector,
+            None => match try!(self.sector_iterator.next()) {
+                None => { return Ok(None); },
+                Some(sector) => sector
+            },
+        };
+        let mut last_sector = start_sector;
+        while (last_sector - start_sector + 1) < self.max_sectors {
+            match try!(self.sector_iterator.next()) {
+                None => {
+                    break;
+                },
+                Some(sector) => {
+                    if sector == last_sector + 1 {
+                        last_sector = sector;
+                    } else {
+                        self.queued = Some(sector);
+                        break;
+                    }
+                },
+            }
+        }
+        Ok(Some(Fragment {
+            start_sector: start_sector,
+            sector_count: last_sector - start_sector + 1
+        }))
+    }
+}
+
+fn iterate_fragments<'a, 'b>(
+        fat_table: &'a mut FatTable<'b>,
+        cluster: u32,
+        max_sectors: u32) -> FragmentIterator<'a, 'b> {
+    FragmentIterator {
+        sector_iterator: iterate_node_sectors(fat_table, cluster),
+        max_sectors: max_sectors,
+        queued: None,
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct FileLocation {
+    cluster: u32,
+    pub size: u32,
+    pub is_dir: bool,
+}
+
+// A directory entry's cluster field is 0 for the root directory (e.g. in the
+// ".." entry of a top-level directory).
+fn dir_cluster(volume: &Fat32Volume, cluster: u32) -> u32 {
+    if cluster == 0 { volume.root_dir_clust } else { cluster }
+}
+
+const DIR_ENTRY_SIZE: usize = 32;
+const MAX_DIR_ENTRIES: u32 = 65536;
+const DELETED_ENTRY: u8 = 0xe5;
+const LFN_ATTRIBUTES: u8 = 0x0f;
+const LFN_LAST_ENTRY: u8 = 0x40;
+const LFN_SEQUENCE_MASK: u8 = 0x1f;
+const LFN_CHARS_PER_ENTRY: usize = 13;
+const LFN_MAX_ENTRIES: usize = 20;
+const LFN_MAX_UNITS: usize = LFN_CHARS_PER_ENTRY * LFN_MAX_ENTRIES;
+const MAX_NAME_BYTES: usize = 255 * 3;
+const MAX_SHORT_NAME_BYTES: usize = 12 * 2;  // bytes above 0x7f take two
+
+// Offsets of the UCS-2 characters within an LFN entry.
+const LFN_CHAR_OFFSETS: [usize; LFN_CHARS_PER_ENTRY] =
+    [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Windows NT stores the case of all-lowercase 8.3 names in these bits of the
+// reserved byte instead of creating an LFN.
+const NT_LOWERCASE_BASE: u8 = 0x08;
+const NT_LOWERCASE_EXT: u8 = 0x10;
+
+// The checksum of the short name that an LFN entry set belongs to.
+fn lfn_checksum(short_name: &[u8]) -> u8 {
+    let mut sum = 0u8;
+    for ch in short_name.iter() {
+        sum = ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*ch);
+    }
+    sum
+}
+
+// A simple case folding that covers ASCII, Latin-1, Latin Extended-A, Greek,
+// and Cyrillic, which is enough for the names people give boot files.
+fn fold_case(ch: u32) -> u32 {
+    match ch {
+        0x41...0x5a => ch + 0x20,
+        0xc0...0xde if ch != 0xd7 => ch + 0x20,
+        0x100...0x137 | 0x14a...0x177 if ch & 1 == 0 => ch + 1,
+        0x139...0x148 | 0x179...0x17e if ch & 1 == 1 => ch + 1,
+        0x178 => 0xff,
+        0x391...0x3a9 if ch != 0x3a2 => ch + 0x20,
+        0x400...0x40f => ch + 0x50,
+        0x410...0x42f => ch + 0x20,
+        _ => ch,
+    }
+}
+
+fn eq_ignore_case(a: &[u8], b: &[u8]) -> bool {
+    let mut a_pos = 0;
+    let mut b_pos = 0;
+    while a_pos < a.len() && b_pos < b.len() {
+        if fold_case(next_utf8_char(a, &mut a_pos)) != fold_case(next_utf8_char(b, &mut b_pos)) {
+            return false;
+        }
+    }
+    a_pos == a.len() && b_pos == b.len()
+}
+
+// Decode a FAT date and time.  Returns None for a zero or impossible date.
+fn decode_timestamp(date: u16, time: u16) -> Option<DateTime> {
+    let stamp = DateTime {
+        year: 1980 + (date >> 9),
+        month: ((date >> 5) & 0x0f) as u8,
+        day: (date & 0x1f) as u8,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3f) as u8,
+        second: ((time & 0x1f) * 2) as u8,
+    };
+    if stamp.month < 1 || stamp.month > 12 || stamp.day < 1 ||
+            stamp.hour > 23 || stamp.minute > 59 || stamp.second > 59 {
+        return None;
+    }
+    Some(stamp)
+}
+
+// A directory entry, with its long name (if it has a valid one) converted to
+// UTF-8.
+pub struct DirItem {
+    short_name: [u8; 11],
+    nt_flags: u8,
+    attr: u8,
+    cluster: u32,
+    size: u32,
+    ctime_sec10: u8,
+    ctime_time: u16,
+    ctime_date: u16,
+    atime_date: u16,
+    mtime_time: u16,
+    mtime_date: u16,
+    name: [u8; MAX_NAME_BYTES],
+    name_len: usize,
+    has_long_name: bool,
+    short_display: [u8; MAX_SHORT_NAME_BYTES],
+    short_display_len: usize,
+    entry_index: u32,               // the short entry's index in the directory
+    first_index: u32,               // the index of its first LFN entry, if any
+}
+
+impl DirItem {
+    // The long name if there is one, and otherwise the short name.
+    pub fn name(&self) -> &[u8] {
+        if self.has_long_name { &self.name[..self.name_len] } else { self.short_name() }
+    }
+
+    pub fn long_name(&self) -> Option<&[u8]> {
+        if self.has_long_name { Some(&self.name[..self.name_len]) } else { None }
+    }
+
+    // The short name formatted as "NAME.EXT", in lower case where the NT
+    // flags say so.
+    pub fn short_name(&self) -> &[u8] {
+        &self.short_display[..self.short_display_len]
+    }
+
+    pub fn attributes(&self) -> u8 {
+        self.attr
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn first_cluster(&self) -> u32 {
+        self.cluster
+    }
+
+    pub fn created(&self) -> Option<DateTime> {
+        decode_timestamp(self.ctime_date, self.ctime_time).map(|mut stamp| {
+            // The extra 10ms units can add a second.
+            stamp.second += self.ctime_sec10 / 100;
+            stamp
+        })
+    }
+
+    pub fn modified(&self) -> Option<DateTime> {
+        decode_timestamp(self.mtime_date, self.mtime_time)
+    }
+
+    // Only the date of the last access is recorded.
+    pub fn accessed(&self) -> Option<DateTime> {
+        decode_timestamp(self.atime_date, 0)
+    }
+
+    pub fn location(&self) -> FileLocation {
+        FileLocation {
+            cluster: self.cluster,
+            size: self.size,
+            is_dir: self.is_dir(),
+        }
+    }
+
+    fn set_short_display_name(&mut self) {
+        self.short_display_len = 0;
+        let trimmed_len = |part: &[u8]| part.iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+        let base_len = trimmed_len(&self.short_name[..8]);
+        let ext_len = trimmed_len(&self.short_name[8..]);
+        for i in 0..base_len + ext_len {
+            if i == base_len {
+                self.short_display[self.short_display_len] = b'.';
+                self.short_display_len += 1;
+            }
+            let (ch, lowercase) = if i < base_len {
+                // 0x05 stands for a leading 0xE5 byte.
+                let ch = if i == 0 && self.short_name[0] == 0x05 {
+                    0xe5
+                } else {
+                    self.short_name[i]
+                };
+                (ch, self.nt_flags & NT_LOWERCASE_BASE != 0)
+            } else {
+                (self.short_name[8 + i - base_len], self.nt_flags & NT_LOWERCASE_EXT != 0)
+            };
+            let ch = if lowercase && ch >= b'A' && ch <= b'Z' { ch + 32 } else { ch };
+            push_utf8(&mut self.short_display, &mut self.short_display_len, ch as u32);
+        }
+    }
+}
+
+// Walks the entries of a directory, one sector at a time, assembling long
+// names from their LFN entries.  Deleted entries and volume labels are
+// skipped, and the walk stops at the end-of-directory marker.  The walker
+// does not hold on to the FAT table, so that a public iterator can own both.
+struct DirWalker<'a> {
+    volume: &'a Fat32Volume<'a>,
+    first_cluster: u32,
+    next_cluster: Option<u32>,
+    next_lba: u32,
+    sectors_left: u32,              // in the current cluster or root region
+    cluster_count: u32,
+    sector: [u8; 512],
+    offset: usize,
+    done: bool,
+    index: u32,                     // the index of the next entry
+    lfn_units: [u16; LFN_MAX_UNITS],
+    lfn_next_sequence: u8,          // 0 when no LFN set is in progress
+    lfn_checksum: u8,
+    lfn_count: usize,
+    lfn_start: u32,
+}
+
+impl<'a> DirWalker<'a> {
+    fn reset_lfn(&mut self) {
+        self.lfn_next_sequence = 0;
+        self.lfn_count = 0;
+    }
+
+    fn next_sector(&mut self, fat_table: &mut FatTable) -> Result<Option<u32>, Error> {
+        if self.sectors_left == 0 {
+            let cluster = match self.next_cluster {
+                None => return Ok(None),
+                Some(cluster) => cluster,
+            };
+            if !is_valid_cluster(self.volume, cluster) {
+                return Err(Error::ClusterOutOfRange(cluster));
+            }
+            if self.cluster_count == max_dir_clusters(self.volume) {
+                return Err(Error::ChainLoop(self.first_cluster));
+            }
+            self.cluster_count += 1;
+            self.next_cluster = try!(fat_table.next_cluster(cluster));
+            self.next_lba = cluster_sector(self.volume, cluster);
+            self.sectors_left = self.volume.sec_per_clust as u32;
+        }
+        let lba = self.next_lba;
+        self.next_lba += 1;
+        self.sectors_left -= 1;
+        Ok(Some(lba))
+    }
+
+    fn add_lfn_entry(&mut self, raw: &[u8], index: u32) {
+        let sequence = raw[0] & LFN_SEQUENCE_MASK;
+        if raw[0] & LFN_LAST_ENTRY != 0 {
+            // The last LFN entry comes first on disk.
+            if sequence == 0 || sequence as usize > LFN_MAX_ENTRIES {
+                self.reset_lfn();
+                return;
+            }
+            self.lfn_count = sequence as usize;
+            self.lfn_checksum = raw[13];
+            self.lfn_start = index;
+            for unit in self.lfn_units.iter_mut() {
+                *unit = 0xffff;
+            }
+        } else if sequence == 0 || sequence != self.lfn_next_sequence ||
+                raw[13] != self.lfn_checksum {
+            self.reset_lfn();
+            return;
+        }
+        let base = (sequence as usize - 1) * LFN_CHARS_PER_ENTRY;
+        for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+            self.lfn_units[base + i] = (raw[*offset] as u16) | ((raw[*offset + 1] as u16) << 8);
+        }
+        self.lfn_next_sequence = sequence - 1;
+    }
+
+    // Convert the assembled LFN to UTF-8 if it belongs to the short entry.
+    fn take_long_name(&mut self, item: &mut DirItem) {
+        let complete = self.lfn_count > 0 && self.lfn_next_sequence == 0 &&
+            self.lfn_checksum == lfn_checksum(&item.short_name);
+        let count = self.lfn_count;
+        self.reset_lfn();
+        if !complete {
+            return;
+        }
+        item.first_index = self.lfn_start;
+        let units = &self.lfn_units[..count * LFN_CHARS_PER_ENTRY];
+        let end = units.iter().position(|u| *u == 0 || *u == 0xffff).unwrap_or(units.len());
+        item.name_len = 0;
+        utf16_to_utf8(&units[..end], &mut item.name, &mut item.name_len);
+        item.has_long_name = end > 0;
+    }
+
+    fn next(&mut self, fat_table: &mut FatTable) -> Result<Option<DirItem>, Error> {
+        loop {
+            if self.done {
+                return Ok(None);
+            }
+            if self.offset == self.sector.len() {
+                let sector = match try!(self.next_sector(fat_table)) {
+                    None => return Ok(None),
+                    Some(sector) => sector,
+                };
+                try!(self.volume.disk.read_sectors(sector, &mut self.sector));
+                self.offset = 0;
+            }
+            let offset = self.offset;
+            self.offset += DIR_ENTRY_SIZE;
+            let index = self.index;
+            self.index += 1;
+            let mut raw = [0u8; DIR_ENTRY_SIZE];
+            copy_bytes(&mut raw, &self.sector[offset..]);
+
+            if raw[0] == 0 {
+                // The end-of-directory marker.
+                self.done = true;
+                return Ok(None);
+            }
+            if raw[0] == DELETED_ENTRY {
+                self.reset_lfn();
+                continue;
+            }
+            if raw[11] & 0x3f == LFN_ATTRIBUTES {
+                self.add_lfn_entry(&raw, index);
+                continue;
+            }
+            if raw[11] & ATTR_VOLUME_ID != 0 {
+                self.reset_lfn();
+                continue;
+            }
+
+            let entry: DirEntry = unsafe { core::mem::transmute(raw) };
+            let mut item = DirItem {
+                short_name: entry.name,
+                nt_flags: entry.winnt_reserved,
+                attr: entry.attr,
+                cluster: ((entry.cluster_hi as u32) << 16) + (entry.cluster_lo as u32),
+                size: entry.size,
+                ctime_sec10: entry.ctime_sec10,
+                ctime_time: entry.ctime_time,
+                ctime_date: entry.ctime_date,
+                atime_date: entry.atime_date,
+                mtime_time: entry.mtime_time,
+                mtime_date: entry.mtime_date,
+                name: [0; MAX_NAME_BYTES],
+                name_len: 0,
+                has_long_name: false,
+                short_display: [0; MAX_SHORT_NAME_BYTES],
+                short_display_len: 0,
+                entry_index: index,
+                first_index: index,
+            };
+            item.set_short_display_name();
+            self.take_long_name(&mut item);
+            return Ok(Some(item));
+        }
+    }
+}
+
+// A directory holds at most 65536 entries.
+fn max_dir_clusters(volume: &Fat32Volume) -> u32 {
+    cmp::min(MAX_DIR_ENTRIES * DIR_ENTRY_SIZE as u32 / (volume.sec_per_clust as u32 * 512),
+             volume.total_clusters)
+}
+
+fn walk_dir<'a>(volume: &'a Fat32Volume<'a>, dir_cluster: u32) -> DirWalker<'a> {
+    // Cluster 0 is the fixed root directory region of a FAT12/FAT16 volume.
+    let (next_cluster, next_lba, sectors_left) = if dir_cluster == 0 {
+        (None, volume.root_dir_sector, volume.root_dir_sectors)
+    } else {
+        (Some(dir_cluster), 0, 0)
+    };
+    DirWalker {
+        volume: volume,
+        first_cluster: dir_cluster,
+        next_cluster: next_cluster,
+        next_lba: next_lba,
+        sectors_left: sectors_left,
+        cluster_count: 0,
+        sector: [0; 512],
+        offset: 512,
+        done: false,
+        index: 0,
+        lfn_units: [0; LFN_MAX_UNITS],
+        lfn_next_sequence: 0,
+        lfn_checksum: 0,
+        lfn_count: 0,
+        lfn_start: 0,
+    }
+}
+
+// Lists a directory.  "." and ".." entries are included; deleted entries,
+// volume labels, and LFN entries are not.
+pub struct DirIterator<'a> {
+    table: FatTable<'a>,
+    walker: DirWalker<'a>,
+}
+
+impl<'a> DirIterator<'a> {
+    pub fn next(&mut self) -> Result<Option<DirItem>, Error> {
+        self.walker.next(&mut self.table)
+    }
+}
+
+// Iterate over a directory found by `lookup` or by an earlier iteration.  A
+// ".." entry leading to the root has cluster 0, which is mapped to the root.
+pub fn iterate_dir<'a>(volume: &'a Fat32Volume<'a>, location: FileLocation) ->
+        Result<DirIterator<'a>, Error> {
+    if !location.is_dir {
+        return Err(Error::NotADirectory);
+    }
+    Ok(DirIterator {
+        table: fat_table(volume),
+        walker: walk_dir(volume, dir_cluster(volume, location.cluster)),
+    })
+}
+
+// Find an entry by its short name (in padded 11-byte form) or by its long
+// name, ignoring case.
+fn find_item(
+    dir_cluster: u32,
+    short_name: Option<&[u8]>,
+    long_name: &[u8],
+    fat_table: &mut FatTable) -> Result<Option<DirItem>, Error>
+{
+    let mut walker = walk_dir(fat_table.volume, dir_cluster);
+    while let Some(item) = try!(walker.next(fat_table)) {
+        let short_match = match short_name {
+            None => false,
+            Some(name) => &item.short_name[..] == name,
+        };
+        if short_match || (!long_name.is_empty() && eq_ignore_case(item.name(), long_name)) {
+            return Ok(Some(item));
+        }
+    }
+    Ok(None)
+}
+
+fn find_in_dir(
+    dir_cluster: u32,
+    short_name: Option<&[u8]>,
+    long_name: &[u8],
+    fat_table: &mut FatTable) -> Result<Option<FileLocation>, Error>
+{
+    Ok(try!(find_item(dir_cluster, short_name, long_name, fat_table)).map(|item| item.location()))
+}
+
+// Convert a path component to the padded 11-byte form of an 8.3 name, in
+// upper case.  Returns None if the component cannot be a short name.
+fn to_short_name(component: &[u8]) -> Option<[u8; 11]> {
+    let mut name = [b' '; 11];
+    if component == &b"."[..] || component == &b".."[..] {
+        copy_bytes(&mut name, component);
+        return Some(name);
+    }
+    let (base, ext) = match component.iter().position(|c| *c == b'.') {
+        None => (component, &b""[..]),
+        Some(dot) => (&component[..dot], &component[dot + 1..]),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 {
+        return None;
+    }
+    for (i, ch) in base.iter().chain(ext.iter()).enumerate() {
+        let ch = if *ch >= b'a' && *ch <= b'z' { *ch - 32 } else { *ch };
+        if ch <= b' ' || ch == b'.' || ch == b'/' || ch == b'\\' {
+            return None;
+        }
+        let index = if i < base.len() { i } else { 8 + i - base.len() };
+        name[index] = ch;
+    }
+    // 0xE5 marks a deleted entry, so a name starting with it is stored as 0x05.
+    if name[0] == 0xe5 {
+        name[0] = 0x05;
+    }
+    Some(name)
+}
+
+// Resolve a '/'-separated path, starting at the root directory.  Each
+// component matches either a long name or an 8.3 name, ignoring case.  Empty
+// and "." components are skipped, and ".." moves to the parent directory (the
+// root is its own parent).
+pub fn lookup(volume: &Fat32Volume, path: &[u8]) -> Result<Option<FileLocation>, Error> {
+    let mut table = fat_table(volume);
+    let mut current = FileLocation {
+        cluster: volume.root_dir_clust,
+        size: 0,
+        is_dir: true,
+    };
+    for component in path.split(|c| *c == b'/') {
+        if component.is_empty() || component == &b"."[..] {
+            continue;
+        }
+        if !current.is_dir {
+            return Ok(None);
+        }
+        if component == &b".."[..] && current.cluster == volume.root_dir_clust {
+            continue;
+        }
+        let short_name = to_short_name(component);
+        let found = try!(find_in_dir(current.cluster,
+                                     short_name.as_ref().map(|name| &name[..]),
+                                     component, &mut table));
+        current = match found {
+            None => return Ok(None),
+            Some(location) => location,
+        };
+        if current.is_dir {
+            current.cluster = dir_cluster(volume, current.cluster);
+        }
+    }
+    Ok(Some(current))
+}
+
+fn round_up(base: u32, multiplier: u32) -> u32 {
+    (base + multiplier - 1) / multiplier * multiplier
+}
+
+fn read_node_data(
+        volume: &Fat32Volume,
+        location: FileLocation,
+        buffer: &mut [u8],
+        fat_table: &mut FatTable) -> Result<(), Error> {
+    let cluster_bytes = volume.sec_per_clust as u32 * 512;
+    let full_size = round_up(location.size, cluster_bytes) as usize;
+    if buffer.len() < full_size {
+        return Err(Error::BufferTooSmall);
+    }
+    let mut it = iterate_fragments(
+        fat_table, location.cluster, 0xffff_ffff);
+    let mut offset = 0_usize;
+    while offset < full_size {
+        let fragment = match try!(it.next()) {
+            None => return Err(Error::TruncatedChain),
+            Some(fragment) => fragment,
+        };
+        // Stop at the file's size even if the chain goes on.
+        let fragment_bytes = cmp::min((fragment.sector_count * 512) as usize,
+                                      full_size - offset);
+        try!(volume.disk.read_sectors(
+            fragment.start_sector,
+            &mut buffer[offset .. offset + fragment_bytes]));
+        offset += fragment_bytes;
+    }
+    Ok(())
+}
+
+// Read a file found by `lookup`.  The buffer must hold the file's size rounded
+// up to a whole cluster.  Returns the size of the file.
+pub fn read_file(
+        volume: &Fat32Volume,
+        location: FileLocation,
+        buffer: &mut [u8]) -> Result<u32, Error> {
+    if location.is_dir {
+        return Err(Error::IsADirectory);
+    }
+    if location.size > 0 {
+        let mut table = fat_table(volume);
+        try!(read_node_data(volume, location, buffer, &mut table));
+    }
+    Ok(location.size)
+}
+
+// Find an entry in the root directory by its padded 11-byte short name alone.
+// Unlike find_item, this skips long names and builds no DirItem, which keeps
+// stage1 small.
+fn find_short_name_in_root(
+        volume: &Fat32Volume,
+        name: &[u8],
+        fat_table: &mut FatTable) -> Result<Option<FileLocation>, Error> {
+    let mut sector = [0u8; 512];
+    let mut sectors = iterate_dir_sectors(fat_table, volume.root_dir_clust);
+    while let Some(lba) = try!(sectors.next()) {
+        try!(volume.disk.read_sectors(lba, &mut sector));
+        for raw in sector.chunks(DIR_ENTRY_SIZE) {
+            if raw[0] == 0 {
+                return Ok(None);
+            }
+            // LFN entries have the volume ID attribute bit set.
+            if raw[11] & ATTR_VOLUME_ID == 0 && &raw[..11] == name {
+                return Ok(Some(FileLocation {
+                    cluster: ((get16(raw, 20) as u32) << 16) | get16(raw, 26) as u32,
+                    size: get32(raw, 28),
+                    is_dir: raw[11] & ATTR_DIRECTORY != 0,
+                }));
+            }
+        }
+    }
+    Ok(None)
+}
+
+// Returns the size of the file returned.  `name` is the padded 11-byte 8.3
+// name of a file in the root directory.
+pub fn read_file_reusing_buffer_in_find(
+        volume: &Fat32Volume,
+        name: StrRef,
+        buffer: &mut [u8]) -> Result<u32, Error> {
+    let mut table = fat_table(volume);
+    match try!(find_short_name_in_root(volume, name.as_bytes(), &mut table)) {
+        Some(location) if !location.is_dir => {
+            try!(read_node_data(volume, location, buffer, &mut table));
+            Ok(location.size)
+        }
+        Some(_) => Err(Error::IsADirectory),
+        None => Err(Error::NotFound),
+    }
+}
+
+// Count the free clusters by scanning the FAT.
+fn count_free_clusters(fat_table: &mut FatTable) -> Result<u32, Error> {
+    let mut count = 0;
+    for cluster in 2..fat_table.volume.total_clusters + 2 {
+        if try!(fat_table.entry(cluster)) == FAT_FREE {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+// Report the volume's names, size, and state.  The free-cluster count comes
+// from FSInfo if it has one that is in range, and otherwise from scanning the
+// whole FAT, which can take a while on a large volume.
+pub fn volume_info(volume: &Fat32Volume) -> Result<VolumeInfo, Error> {
+    let mut table = fat_table(volume);
+    let mut sector = [0u8; 512];
+    let hint = if try!(read_fsinfo(volume, &mut sector)).is_some() {
+        get32(&sector, FSINFO_FREE_COUNT)
+    } else {
+        FSINFO_UNKNOWN
+    };
+    let free_clusters = if hint <= volume.total_clusters {
+        hint
+    } else {
+        try!(count_free_clusters(&mut table))
+    };
+    let dirty = match volume.fat_type {
+        FatType::Fat32 => try!(table.raw_entry(1)) & FAT32_CLEAN_SHUTDOWN == 0,
+        FatType::Fat16 => try!(table.raw_entry(1)) & FAT16_CLEAN_SHUTDOWN == 0,
+        FatType::Fat12 => false,    // FAT12 has no such flag
+    };
+    Ok(VolumeInfo {
+        oem_name: volume.oem_name,
+        label: volume.label,
+        serial_number: volume.serial_number,
+        cluster_size: volume.sec_per_clust as u32 * 512,
+        total_clusters: volume.total_clusters,
+        free_clusters: free_clusters,
+        dirty: dirty,
+    })
+}
+
+// An open file, for reading it in pieces.  The handle remembers the last
+// cluster it visited, so sequential reads and forward seeks follow the chain
+// from there rather than from the start of the file.
+pub struct File<'a> {
+    volume: &'a Fat32Volume<'a>,
+    table: FatTable<'a>,
+    first_cluster: u32,
+    size: u32,
+    position: u32,
+    cluster_index: u32,             // the index within the file of `cluster`
+    cluster: u32,
+}
+
+// Open a regular file by path.  Returns None if it does not exist.
+pub fn open<'a>(volume: &'a Fat32Volume<'a>, path: &[u8]) -> Result<Option<File<'a>>, Error> {
+    match try!(lookup(volume, path)) {
+        Some(location) if location.is_dir => Err(Error::IsADirectory),
+        Some(location) => Ok(Some(open_file(volume, location))),
+        None => Ok(None),
+    }
+}
+
+// Open a file found by `lookup`.
+pub fn open_file<'a>(volume: &'a Fat32Volume<'a>, location: FileLocation) -> File<'a> {
+    File {
+        volume: volume,
+        table: fat_table(volume),
+        first_cluster: location.cluster,
+        size: location.size,
+        position: 0,
+        cluster_index: 0,
+        cluster: location.cluster,
+    }
+}
+
+impl<'a> File<'a> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn position(&self) -> u32 {
+        self.position
+    }
+
+    // Set the position of the next `read`.  It may be past the end of the
+    // file, where reads return nothing.
+    pub fn seek(&mut self, position: u32) {
+        self.position = position;
+    }
+
+    // Return the cluster holding the given cluster-sized piece of the file.
+    fn seek_cluster(&mut self, index: u32) -> Result<u32, Error> {
+        if index < self.cluster_index {
+            self.cluster_index = 0;
+            self.cluster = self.first_cluster;
+        }
+        while self.cluster_index < index {
+            self.cluster = match try!(self.table.next_cluster(self.cluster)) {
+                None => return Err(Error::TruncatedChain),
+                Some(next) => next,
+            };
+            self.cluster_index += 1;
+        }
+        if !is_valid_cluster(self.volume, self.cluster) {
+            return Err(Error::ClusterOutOfRange(self.cluster));
+        }
+        Ok(self.cluster)
+    }
+
+    // Read from the given offset into the buffer, without moving the
+    // position.  Returns the number of bytes read, which is less than the
+    // buffer's size only at the end of the file.
+    pub fn read_at(&mut self, offset: u32, buffer: &mut [u8]) -> Result<usize, Error> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+        let len = cmp::min(buffer.len() as u32, self.size - offset) as usize;
+        let cluster_bytes = self.volume.sec_per_clust as u32 * 512;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u32;
+            let cluster = try!(self.seek_cluster(pos / cluster_bytes));
+            let within = pos % cluster_bytes;
+            let lba = cluster_sector(self.volume, cluster) + within / 512;
+            let sector_offset = (within % 512) as usize;
+            let remaining = len - done;
+            // Whole sectors go straight into the buffer, up to the end of the
+            // cluster, if the BIOS could transfer to it.  Otherwise (e.g. for
+            // a buffer above 0x80000), each sector is bounced.
+            let count = cmp::min((cluster_bytes - within) as usize, remaining) / 512;
+            if sector_offset == 0 && count > 0 &&
+                    ::is_bios_buffer(&buffer[done..done + count * 512]) {
+                try!(self.volume.disk.read_sectors(
+                    lba, &mut buffer[done..done + count * 512]));
+                done += count * 512;
+            } else {
+                let mut sector = [0u8; 512];
+                try!(self.volume.disk.read_sectors(lba, &mut sector));
+                let count = cmp::min(512 - sector_offset, remaining);
+                copy_bytes(&mut buffer[done..done + count], &sector[sector_offset..]);
+                done += count;
+            }
+        }
+        Ok(len)
+    }
+
+    // Read from the current position and advance it.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        let position = self.position;
+        let count = try!(self.read_at(position, buffer));
+        self.position += count as u32;
+        Ok(count)
+    }
+}
+
+// Sequential read-modify-write access to the raw entries of a directory, by
+// index.  Entries must be visited in increasing order.
+struct DirSlots<'a, 'b: 'a> {
+    sectors: SectorIterator<'a, 'b>,
+    disk: &'b BlockDevice,
+    lba: u32,
+    sector: [u8; 512],
+    base: u32,                      // the index of the sector's first entry
+    loaded: bool,
+    dirty: bool,
+}
+
+impl<'a, 'b> DirSlots<'a, 'b> {
+    fn entry(&mut self, index: u32) -> Result<&mut [u8], Error> {
+        let per_sector = (512 / DIR_ENTRY_SIZE) as u32;
+        while !self.loaded || index >= self.base + per_sector {
+            try!(self.flush());
+            if self.loaded {
+                self.base += per_sector;
+            }
+            self.lba = match try!(self.sectors.next()) {
+                None => return Err(Error::TruncatedChain),
+                Some(lba) => lba,
+            };
+            try!(self.disk.read_sectors(self.lba, &mut self.sector));
+            self.loaded = true;
+        }
+        assert!(index >= self.base);
+        let offset = (index - self.base) as usize * DIR_ENTRY_SIZE;
+        self.dirty = true;
+        Ok(&mut self.sector[offset..offset + DIR_ENTRY_SIZE])
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            try!(self.disk.write_sectors(self.lba, &self.sector));
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+fn dir_slots<'a, 'b>(fat_table: &'a mut FatTable<'b>, dir_cluster: u32) -> DirSlots<'a, 'b> {
+    DirSlots {
+        disk: fat_table.volume.disk,
+        sectors: iterate_dir_sectors(fat_table, dir_cluster),
+        lba: 0,
+        sector: [0; 512],
+        base: 0,
+        loaded: false,
+        dirty: false,
+    }
+}
+
+// Find `count` consecutive free entries in a directory.  Every entry after
+// the end-of-directory marker is free.
+fn find_free_slots(fat_table: &mut FatTable, dir_cluster: u32, count: u32) ->
+        Result<Option<u32>, Error> {
+    let disk = fat_table.volume.disk;
+    let mut sectors = iterate_dir_sectors(fat_table, dir_cluster);
+    let mut sector = [0u8; 512];
+    let mut index = 0;
+    let mut run_start = 0;
+    let mut run_length = 0;
+    let mut end_seen = false;
+    while let Some(lba) = try!(sectors.next()) {
+        try!(disk.read_sectors(lba, &mut sector));
+        for raw in sector.chunks(DIR_ENTRY_SIZE) {
+            end_seen = end_seen || raw[0] == 0;
+            if end_seen || raw[0] == DELETED_ENTRY {
+                if run_length == 0 {
+                    run_start = index;
+                }
+                run_length += 1;
+                if run_length == count {
+                    return Ok(Some(run_start));
+                }
+            } else {
+                run_length = 0;
+            }
+            index += 1;
+        }
+    }
+    Ok(None)
+}
+
+// Add a zeroed cluster to the end of a directory.  The FAT12/FAT16 root
+// directory has a fixed size and cannot grow.
+fn extend_dir(fat_table: &mut FatTable, dir_cluster: u32) -> Result<(), Error> {
+    if dir_cluster == 0 {
+        return Err(Error::DirectoryFull);
+    }
+    let volume = fat_table.volume;
+    let max_clusters = max_dir_clusters(volume);
+    if try!(fat_table.chain_length(dir_cluster, max_clusters)) == max_clusters {
+        return Err(Error::DirectoryFull);
+    }
+    let last = try!(fat_table.last_cluster(dir_cluster, max_clusters));
+    let cluster = try!(fat_table.allocate_cluster());
+    // Zero the cluster before linking it in, so that the directory never
+    // contains stale entries.
+    let zero = [0u8; 512];
+    let start = cluster_sector(volume, cluster);
+    for i in 0..volume.sec_per_clust as u32 {
+        if let Err(err) = volume.disk.write_sectors(start + i, &zero) {
+            let _ = fat_table.free_chain(cluster);
+            return Err(Error::from(err));
+        }
+    }
+    try!(fat_table.set_entry(last, cluster));
+    fat_table.flush()
+}
+
+// The current time as a FAT (date, time, 10ms units) triple.  Without a
+// usable clock, this is the FAT epoch, 1980-01-01.
+fn fat_timestamp() -> (u16, u16, u8) {
+    match rtc::read() {
+        Some(now) if now.year >= 1980 && now.year <= 2107 => {
+            let date = ((now.year - 1980) << 9) | ((now.month as u16) << 5) | now.day as u16;
+            let time = ((now.hour as u16) << 11) | ((now.minute as u16) << 5) |
+                (now.second as u16 >> 1);
+            (date, time, (now.second & 1) * 100)
+        },
+        _ => ((1 << 5) | 1, 0, 0),
+    }
+}
+
+// Set a short entry's modification and access times, and its creation time
+// too if it is new.
+fn set_timestamps(raw: &mut [u8], created: bool) {
+    let (date, time, tenths) = fat_timestamp();
+    if created {
+        raw[13] = tenths;
+        put16(raw, 14, time);
+        put16(raw, 16, date);
+    }
+    put16(raw, 18, date);
+    put16(raw, 22, time);
+    put16(raw, 24, date);
+}
+
+fn set_entry_data(raw: &mut [u8], cluster: u32, size: u32) {
+    put16(raw, 20, (cluster >> 16) as u16);
+    put16(raw, 26, cluster as u16);
+    put32(raw, 28, size);
+}
+
+// Characters that may appear in a short name, besides letters and digits.
+const SHORT_NAME_SYMBOLS: &'static [u8] = b"$%'-_@~`!(){}^#&";
+
+fn is_short_name_char(ch: u8) -> bool {
+    (ch >= b'A' && ch <= b'Z') || (ch >= b'0' && ch <= b'9') || SHORT_NAME_SYMBOLS.contains(&ch)
+}
+
+fn to_upper(ch: u8) -> u8 {
+    if ch >= b'a' && ch <= b'z' { ch - 32 } else { ch }
+}
+
+// Long names may not contain control characters or any of "*/:<>?\|, nor
+// end with a dot or a space.
+fn is_valid_long_name(name: &[u8]) -> bool {
+    if name.is_empty() || name == &b"."[..] || name == &b".."[..] {
+        return false;
+    }
+    let last = name[name.len() - 1];
+    last != b'.' && last != b' ' &&
+        name.iter().all(|ch| *ch >= 0x20 && !b"\"*/:<>?\\|".contains(ch))
+}
+
+// If a name can be stored as a short name alone, return that name with the
+// NT flags that record an all-lowercase base or extension.
+fn exact_short_name(name: &[u8]) -> Option<([u8; 11], u8)> {
+    let short_name = match to_short_name(name) {
+        None => return None,
+        Some(short_name) => short_name,
+    };
+    let (base, ext) = match name.iter().position(|c| *c == b'.') {
+        None => (name, &b""[..]),
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+    };
+    let mut nt_flags = 0;
+    for &(part, flag) in [(base, NT_LOWERCASE_BASE), (ext, NT_LOWERCASE_EXT)].iter() {
+        let has_lower = part.iter().any(|c| *c >= b'a' && *c <= b'z');
+        let has_upper = part.iter().any(|c| *c >= b'A' && *c <= b'Z');
+        if (has_lower && has_upper) || !part.iter().all(|c| is_short_name_char(to_upper(*c))) {
+            return None;
+        }
+        if has_lower {
+            nt_flags |= flag;
+        }
+    }
+    Some((short_name, nt_flags))
+}
+
+// The basis of a generated short name: the long name in upper case, without
+// spaces or leading dots, with other unusable characters replaced by '_'.
+fn short_name_basis(name: &[u8]) -> [u8; 11] {
+    let mut short_name = [b' '; 11];
+    let start = name.iter().position(|c| *c != b'.').unwrap_or(name.len());
+    let name = &name[start..];
+    let (base, ext) = match name.iter().rposition(|c| *c == b'.') {
+        None => (name, &b""[..]),
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+    };
+    for &(part, offset, max_len) in [(base, 0, 8), (ext, 8, 3)].iter() {
+        let mut len = 0;
+        let mut pos = 0;
+        while pos < part.len() && len < max_len {
+            let ch = next_utf8_char(part, &mut pos);
+            if ch == b' ' as u32 || ch == b'.' as u32 {
+                continue;
+            }
+            let ch = if ch < 0x80 { to_upper(ch as u8) } else { b'_' };
+            short_name[offset + len] = if is_short_name_char(ch) { ch } else { b'_' };
+            len += 1;
+        }
+    }
+    if short_name[0] == b' ' {
+        short_name[0] = b'_';
+    }
+    short_name
+}
+
+// Replace the end of a short name's base with "~N".
+fn with_numeric_tail(basis: &[u8; 11], number: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut digit_count = 0;
+    let mut value = number;
+    while value > 0 {
+        digits[digit_count] = b'0' + (value % 10) as u8;
+        value /= 10;
+        digit_count += 1;
+    }
+    let base_len = basis[..8].iter().rposition(|c| *c != b' ').map_or(0, |i| i + 1);
+    let keep = cmp::min(base_len, 8 - 1 - digit_count);
+    let mut short_name = *basis;
+    for ch in short_name[keep..8].iter_mut() {
+        *ch = b' ';
+    }
+    short_name[keep] = b'~';
+    for i in 0..digit_count {
+        short_name[keep + 1 + i] = digits[digit_count - 1 - i];
+    }
+    short_name
+}
+
+fn short_name_exists(fat_table: &mut FatTable, dir_cluster: u32, short_name: &[u8; 11]) ->
+        Result<bool, Error> {
+    let mut walker = walk_dir(fat_table.volume, dir_cluster);
+    while let Some(item) = try!(walker.next(fat_table)) {
+        if &item.short_name == short_name {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// Convert a long name to the UTF-16 units stored in LFN entries.
+fn to_lfn_units(name: &[u8], units: &mut [u16; LFN_MAX_UNITS]) -> Option<usize> {
+    utf8_to_utf16(name, &mut units[..255])
+}
+
+// Build LFN entry `sequence` (1-based) of a long name.  The name is
+// terminated by a NUL unit if there is room, and padded with 0xFFFF.
+fn lfn_entry(units: &[u16], sequence: usize, is_last: bool, checksum: u8) -> [u8; DIR_ENTRY_SIZE] {
+    let mut raw = [0u8; DIR_ENTRY_SIZE];
+    raw[0] = sequence as u8 | if is_last { LFN_LAST_ENTRY } else { 0 };
+    raw[11] = LFN_ATTRIBUTES;
+    raw[13] = checksum;
+    let base = (sequence - 1) * LFN_CHARS_PER_ENTRY;
+    for (i, offset) in LFN_CHAR_OFFSETS.iter().enumerate() {
+        let unit = if base + i < units.len() {
+            units[base + i]
+        } else if base + i == units.len() {
+            0
+        } else {
+            0xffff
+        };
+        put16(&mut raw, *offset, unit);
+    }
+    raw
+}
+
+// Create the entries for a new file: LFN entries if the name needs them,
+// then the short entry, which is written last.
+fn create_entry(fat_table: &mut FatTable, dir_cluster: u32, name: &[u8],
+                cluster: u32, size: u32) -> Result<(), Error> {
+    let mut units = [0u16; LFN_MAX_UNITS];
+    let (short_name, nt_flags, unit_count) = match exact_short_name(name) {
+        Some((short_name, nt_flags)) => (short_name, nt_flags, 0),
+        None => {
+            let unit_count = match to_lfn_units(name, &mut units) {
+                None => return Err(Error::InvalidName),
+                Some(count) => count,
+            };
+            let basis = short_name_basis(name);
+            let mut found = None;
+            for number in 1..1000000 {
+                let candidate = with_numeric_tail(&basis, number);
+                if !try!(short_name_exists(fat_table, dir_cluster, &candidate)) {
+                    found = Some(candidate);
+                    break;
+                }
+            }
+            match found {
+                None => return Err(Error::DirectoryFull),
+                Some(short_name) => (short_name, 0, unit_count),
+            }
+        }
+    };
+    let lfn_count = (unit_count + LFN_CHARS_PER_ENTRY - 1) / LFN_CHARS_PER_ENTRY;
+    let slot_count = lfn_count as u32 + 1;
+
+    let start = match try!(find_free_slots(fat_table, dir_cluster, slot_count)) {
+        Some(start) => start,
+        None => {
+            try!(extend_dir(fat_table, dir_cluster));
+            match try!(find_free_slots(fat_table, dir_cluster, slot_count)) {
+                None => return Err(Error::DirectoryFull),
+                Some(start) => start,
+            }
+        }
+    };
+
+    let checksum = lfn_checksum(&short_name);
+    let mut slots = dir_slots(fat_table, dir_cluster);
+    for i in 0..lfn_count {
+        let sequence = lfn_count - i;
+        let raw = lfn_entry(&units[..unit_count], sequence, i == 0, checksum);
+        copy_bytes(try!(slots.entry(start + i as u32)), &raw);
+    }
+    {
+        let raw = try!(slots.entry(start + lfn_count as u32));
+        for b in raw.iter_mut() {
+            *b = 0;
+        }
+        copy_bytes(&mut raw[..11], &short_name);
+        raw[11] = ATTR_ARCHIVE;
+        raw[12] = nt_flags;
+        set_timestamps(raw, true);
+        set_entry_data(raw, cluster, size);
+    }
+    slots.flush()
+}
+
+fn update_entry(fat_table: &mut FatTable, dir_cluster: u32, index: u32,
+                cluster: u32, size: u32) -> Result<(), Error> {
+    let mut slots = dir_slots(fat_table, dir_cluster);
+    {
+        let raw = try!(slots.entry(index));
+        raw[11] |= ATTR_ARCHIVE;
+        set_timestamps(raw, false);
+        set_entry_data(raw, cluster, size);
+    }
+    slots.flush()
+}
+
+// Split a path into the cluster of its parent directory and its last
+// component.
+fn resolve_parent<'p>(volume: &Fat32Volume, path: &'p [u8]) -> Result<(u32, &'p [u8]), Error> {
+    let (dir_path, name) = match path.iter().rposition(|c| *c == b'/') {
+        None => (&path[..0], path),
+        Some(slash) => (&path[..slash], &path[slash + 1..]),
+    };
+    if !is_valid_long_name(name) {
+        return Err(Error::InvalidName);
+    }
+    match try!(lookup(volume, dir_path)) {
+        Some(location) if location.is_dir => Ok((location.cluster, name)),
+        Some(_) => Err(Error::NotADirectory),
+        None => Err(Error::NotFound),
+    }
+}
+
+// Find an existing file that may be modified.
+fn find_writable_file(fat_table: &mut FatTable, dir_cluster: u32, name: &[u8]) ->
+        Result<Option<DirItem>, Error> {
+    let short_name = to_short_name(name);
+    match try!(find_item(dir_cluster, short_name.as_ref().map(|name| &name[..]), name,
+                         fat_table)) {
+        Some(ref item) if item.attr & ATTR_DIRECTORY != 0 =>
+            Err(Error::IsADirectory),
+        Some(ref item) if item.attr & ATTR_READ_ONLY != 0 =>
+            Err(Error::ReadOnly),
+        item => Ok(item),
+    }
+}
+
+fn clusters_for_size(volume: &Fat32Volume, size: u32) -> u32 {
+    let cluster_bytes = volume.sec_per_clust as u32 * 512;
+    size / cluster_bytes + if size % cluster_bytes != 0 { 1 } else { 0 }
+}
+
+// Write `data` at byte `offset` of the chain starting at `cluster`.  Whole
+// sectors are written straight from `data` when the BIOS could transfer from
+// it, and bounced otherwise; partly covered ones are read first.
+fn write_node_data(
+        volume: &Fat32Volume,
+        fat_table: &mut FatTable,
+        cluster: u32,
+        offset: u32,
+        data: &[u8]) -> Result<(), Error> {
+    let end = offset + data.len() as u32;
+    let mut it = iterate_fragments(fat_table, cluster, 0xffff_ffff);
+    let mut pos = 0;
+    while pos < end {
+        let fragment = match try!(it.next()) {
+            None => return Err(Error::TruncatedChain),
+            Some(fragment) => fragment,
+        };
+        let fragment_end = pos + fragment.sector_count * 512;
+        if fragment_end > offset {
+            let mut sector_pos = cmp::max(pos, offset) / 512 * 512;
+            while sector_pos < cmp::min(fragment_end, end) {
+                let lba = fragment.start_sector + (sector_pos - pos) / 512;
+                let whole = sector_pos >= offset && sector_pos + 512 <= end;
+                let direct = if whole {
+                    let start = (sector_pos - offset) as usize;
+                    let count = (cmp::min(fragment_end, end) - sector_pos) as usize / 512;
+                    let run = &data[start..start + count * 512];
+                    if ::is_bios_buffer(run) { Some(run) } else { None }
+                } else {
+                    None
+                };
+                if let Some(run) = direct {
+                    try!(volume.disk.write_sectors(lba, run));
+                    sector_pos += run.len() as u32;
+                } else {
+                    let mut sector = [0u8; 512];
+                    if !whole {
+                        try!(volume.disk.read_sectors(lba, &mut sector));
+                    }
+                    let low = cmp::max(sector_pos, offset);
+                    let high = cmp::min(sector_pos + 512, end);
+                    copy_bytes(&mut sector[(low - sector_pos) as usize..],
+                               &data[(low - offset) as usize..(high - offset) as usize]);
+                    try!(volume.disk.write_sectors(lba, &sector));
+                    sector_pos += 512;
+                }
+            }
+        }
+        pos = fragment_end;
+    }
+    Ok(())
+}
+
+// Create a file, or replace the contents of an existing one.  The new
+// contents go to newly allocated clusters, so an existing file keeps its old
+// contents until its entry points at the new ones.
+pub fn write_file(volume: &Fat32Volume, path: &[u8], data: &[u8]) ->
+        Result<FileLocation, Error> {
+    let (dir_cluster, name) = try!(resolve_parent(volume, path));
+    let mut table = fat_table(volume);
+    let existing = try!(find_writable_file(&mut table, dir_cluster, name));
+
+    let size = data.len() as u32;
+    let first = try!(table.allocate_chain(clusters_for_size(volume, size)));
+    let mut result = Ok(());
+    if size > 0 {
+        result = write_node_data(volume, &mut table, first, 0, data);
+    }
+    if result.is_ok() {
+        result = table.flush();
+    }
+    if result.is_ok() {
+        result = match existing {
+            Some(ref item) => update_entry(&mut table, dir_cluster, item.entry_index, first, size),
+            None => create_entry(&mut table, dir_cluster, name, first, size),
+        };
+    }
+    if let Err(err) = result {
+        let _ = table.free_chain(first);
+        let _ = table.commit();
+        return Err(err);
+    }
+    if let Some(ref item) = existing {
+        try!(table.free_chain(item.cluster));
+    }
+    try!(table.commit());
+    Ok(FileLocation { cluster: first, size: size, is_dir: false })
+}
+
+// Write `data` into an existing file at byte `offset`, which may be at most
+// the file's size, extending the file if the data runs past its end.  The
+// new size is written last, so clusters added for it are lost space, not
+// garbage in the file, if the update is cut short.
+pub fn write_at(volume: &Fat32Volume, path: &[u8], offset: u32, data: &[u8]) ->
+        Result<FileLocation, Error> {
+    let (dir_cluster, name) = try!(resolve_parent(volume, path));
+    let mut table = fat_table(volume);
+    let item = match try!(find_writable_file(&mut table, dir_cluster, name)) {
+        None => return Err(Error::NotFound),
+        Some(item) => item,
+    };
+    if offset > item.size {
+        return Err(Error::InvalidOffset);
+    }
+    let end = match offset.checked_add(data.len() as u32) {
+        None => return Err(Error::InvalidOffset),
+        Some(end) => end,
+    };
+    let size = cmp::max(item.size, end);
+
+    let mut first = item.cluster;
+    let mut last = 0;
+    let mut added = 0;
+    // The chain may not be longer than the file's size needs.
+    let max_clusters = cmp::max(clusters_for_size(volume, item.size), 1);
+    let have = if first == 0 { 0 } else { try!(table.chain_length(first, max_clusters)) };
+    let need = clusters_for_size(volume, size);
+    if need > have {
+        added = try!(table.allocate_chain(need - have));
+        if first == 0 {
+            first = added;
+        } else {
+            let linked = table.last_cluster(first, max_clusters).and_then(|cluster| {
+                last = cluster;
+                table.set_entry(last, added)
+            });
+            if let Err(err) = linked {
+                let _ = table.free_chain(added);
+                return Err(err);
+            }
+        }
+    }
+    let mut result = Ok(());
+    if !data.is_empty() {
+        result = write_node_data(volume, &mut table, first, offset, data);
+    }
+    if result.is_ok() {
+        result = table.flush();
+    }
+    if result.is_ok() {
+        result = update_entry(&mut table, dir_cluster, item.entry_index, first, size);
+    }
+    if let Err(err) = result {
+        // Give back the clusters added for the new size.
+        if last != 0 {
+            let _ = table.set_entry(last, FAT_END_OF_CHAIN);
+        }
+        let _ = table.free_chain(added);
+        let _ = table.commit();
+        return Err(err);
+    }
+    try!(table.commit());
+    Ok(FileLocation { cluster: first, size: size, is_dir: false })
+}
+
+// Delete a file.  Its entries are marked deleted before its clusters are
+// freed.
+pub fn delete_file(volume: &Fat32Volume, path: &[u8]) -> Result<(), Error> {
+    let (dir_cluster, name) = try!(resolve_parent(volume, path));
+    let mut table = fat_table(volume);
+    let item = match try!(find_writable_file(&mut table, dir_cluster, name)) {
+        None => return Err(Error::NotFound),
+        Some(item) => item,
+    };
+    {
+        let mut slots = dir_slots(&mut table, dir_cluster);
+        for index in item.first_index..item.entry_index + 1 {
+            try!(slots.entry(index))[0] = DELETED_ENTRY;
+        }
+        try!(slots.flush());
+    }
+    try!(table.free_chain(item.cluster));
+    table.commit()
+}
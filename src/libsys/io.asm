@@ -0,0 +1,111 @@
+        section .data
+        align 8
+null_idt:
+        ; LIDT operand with a zero limit.  Any interrupt or exception then
+        ; escalates to a triple fault.
+        dw 0
+        dd 0
+
+
+        section .text
+        bits 32
+
+
+        ;
+        ; Port I/O.  C prototypes:
+        ;
+        ;     void io_out8(u32 port, u32 value);
+        ;     void io_out16(u32 port, u32 value);
+        ;     void io_out32(u32 port, u32 value);
+        ;     u32 io_in8(u32 port);
+        ;     u32 io_in16(u32 port);
+        ;     u32 io_in32(u32 port);
+        ;
+
+        global io_out8
+io_out8:
+        mov edx, [esp + 4]
+        mov eax, [esp + 8]
+        out dx, al
+        ret
+
+        global io_out16
+io_out16:
+        mov edx, [esp + 4]
+        mov eax, [esp + 8]
+        out dx, ax
+        ret
+
+        global io_out32
+io_out32:
+        mov edx, [esp + 4]
+        mov eax, [esp + 8]
+        out dx, eax
+        ret
+
+        global io_in8
+io_in8:
+        mov edx, [esp + 4]
+        xor eax, eax
+        in al, dx
+        ret
+
+        global io_in16
+io_in16:
+        mov edx, [esp + 4]
+        xor eax, eax
+        in ax, dx
+        ret
+
+        global io_in32
+io_in32:
+        mov edx, [esp + 4]
+        in eax, dx
+        ret
+
+
+        ;
+        ; String port I/O of 16-bit words.  C prototypes:
+        ;
+        ;     void io_in16_rep(u32 port, u16 *buffer, u32 count);
+        ;     void io_out16_rep(u32 port, const u16 *buffer, u32 count);
+        ;
+
+        global io_in16_rep
+io_in16_rep:
+        push edi
+        mov edx, [esp + 8]
+        mov edi, [esp + 12]
+        mov ecx, [esp + 16]
+        cld
+        rep insw
+        pop edi
+        ret
+
+        global io_out16_rep
+io_out16_rep:
+        push esi
+        mov edx, [esp + 8]
+        mov esi, [esp + 12]
+        mov ecx, [esp + 16]
+        cld
+        rep outsw
+        pop esi
+        ret
+
+
+        ;
+        ; Reset the CPU by loading an empty IDT and raising an exception.
+        ;
+        ; C prototype:
+        ;
+        ;     void triple_fault(void);    /* does not return */
+        ;
+        global triple_fault
+triple_fault:
+        cli
+        lidt [null_idt]
+        int3
+.loop:
+        hlt
+        jmp .loop
@@ -0,0 +1,99 @@
+// x86 port I/O, implemented in io.asm, and memory-mapped I/O.
+
+use core::intrinsics;
+
+extern "C" {
+    fn io_out8(port: u32, value: u32);
+    fn io_out16(port: u32, value: u32);
+    fn io_out32(port: u32, value: u32);
+    fn io_in8(port: u32) -> u32;
+    fn io_in16(port: u32) -> u32;
+    fn io_in32(port: u32) -> u32;
+    fn io_in16_rep(port: u32, buffer: *mut u8, count: u32);
+    fn io_out16_rep(port: u32, buffer: *const u8, count: u32);
+    fn triple_fault() -> !;
+}
+
+#[inline]
+pub fn outb(port: u16, value: u8) {
+    unsafe { io_out8(port as u32, value as u32) }
+}
+
+#[inline]
+pub fn outw(port: u16, value: u16) {
+    unsafe { io_out16(port as u32, value as u32) }
+}
+
+#[inline]
+pub fn outl(port: u16, value: u32) {
+    unsafe { io_out32(port as u32, value) }
+}
+
+#[inline]
+pub fn inb(port: u16) -> u8 {
+    unsafe { io_in8(port as u32) as u8 }
+}
+
+#[inline]
+pub fn inw(port: u16) -> u16 {
+    unsafe { io_in16(port as u32) as u16 }
+}
+
+#[inline]
+pub fn inl(port: u16) -> u32 {
+    unsafe { io_in32(port as u32) }
+}
+
+// Read buffer.len() / 2 words from a port into the buffer.
+pub fn insw(port: u16, buffer: &mut [u8]) {
+    assert!(buffer.len() % 2 == 0);
+    unsafe { io_in16_rep(port as u32, buffer.as_mut_ptr(), (buffer.len() / 2) as u32) }
+}
+
+// Write the buffer to a port, a word at a time.
+pub fn outsw(port: u16, buffer: &[u8]) {
+    assert!(buffer.len() % 2 == 0);
+    unsafe { io_out16_rep(port as u32, buffer.as_ptr(), (buffer.len() / 2) as u32) }
+}
+
+// Write to an unused port to give slow ISA devices time to settle.
+#[inline]
+pub fn wait() {
+    outb(0x80, 0);
+}
+
+pub fn reset_by_triple_fault() -> ! {
+    unsafe { triple_fault() }
+}
+
+// Memory-mapped I/O.  These are volatile accesses at a physical address.
+
+#[inline]
+pub fn mmio_read8(addr: u32) -> u8 {
+    unsafe { intrinsics::volatile_load(addr as *const u8) }
+}
+
+#[inline]
+pub fn mmio_read16(addr: u32) -> u16 {
+    unsafe { intrinsics::volatile_load(addr as *const u16) }
+}
+
+#[inline]
+pub fn mmio_read32(addr: u32) -> u32 {
+    unsafe { intrinsics::volatile_load(addr as *const u32) }
+}
+
+#[inline]
+pub fn mmio_write8(addr: u32, value: u8) {
+    unsafe { intrinsics::volatile_store(addr as *mut u8, value) }
+}
+
+#[inline]
+pub fn mmio_write16(addr: u32, value: u16) {
+    unsafe { intrinsics::volatile_store(addr as *mut u16, value) }
+}
+
+#[inline]
+pub fn mmio_write32(addr: u32, value: u32) {
+    unsafe { intrinsics::volatile_store(addr as *mut u32, value) }
+}
@@ -0,0 +1,370 @@
+// ISO 9660 filesystem reader, with Joliet and Rock Ridge names.
+//
+// The volume is read in 2048-byte logical blocks.  On a CD, a block is one
+// device sector; on a hard disk or USB stick holding an ISO image, it is four.
+//
+// When a volume has Rock Ridge extensions, we use the Rock Ridge (NM) names of
+// the primary directory tree.  Otherwise, if it has a Joliet supplementary
+// volume descriptor, we use the Joliet tree and its UCS-2 names, converted to
+// UTF-8.  Otherwise, we use the primary names, without the ";1" version
+// suffix.  Name lookup ignores ASCII case in every case.
+//
+// Rock Ridge continuation areas (CE entries) are not followed, so a name that
+// does not fit in its directory record falls back to the primary name.
+
+use core::cmp;
+
+use {copy_bytes, get32, BlockDevice, ErrorStr, FileSource, SectorIndex};
+
+pub const BLOCK_SIZE: usize = 2048;
+
+const FIRST_DESCRIPTOR_BLOCK: u32 = 16;
+const MAX_DESCRIPTOR_BLOCKS: u32 = 32;
+
+const DESCRIPTOR_PRIMARY: u8 = 1;
+const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+const DESCRIPTOR_TERMINATOR: u8 = 255;
+
+const ROOT_RECORD_OFFSET: usize = 156;
+const JOLIET_ESCAPE_OFFSET: usize = 88;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+
+const NM_CONTINUE: u8 = 0x01;
+const NM_CURRENT: u8 = 0x02;
+const NM_PARENT: u8 = 0x04;
+
+pub const MAX_NAME_LEN: usize = 255;
+
+#[derive(Copy, Clone)]
+pub struct File {
+    pub extent: u32,        // first logical block
+    pub size: u32,          // in bytes
+    pub is_dir: bool,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Names {
+    Primary,
+    Joliet,
+    RockRidge { skip: usize },
+}
+
+pub struct Volume<'a> {
+    device: &'a BlockDevice,
+    start_sector: SectorIndex,
+    sectors_per_block: u32,
+    root: File,
+    names: Names,
+}
+
+pub struct DirEntry {
+    pub file: File,
+    name: [u8; MAX_NAME_LEN],
+    name_len: usize,
+}
+
+impl DirEntry {
+    pub fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    fn push(&mut self, ch: u8) {
+        if self.name_len < MAX_NAME_LEN {
+            self.name[self.name_len] = ch;
+            self.name_len += 1;
+        }
+    }
+}
+
+fn parse_record(record: &[u8]) -> File {
+    File {
+        extent: get32(record, 2),
+        size: get32(record, 10),
+        is_dir: record[25] & FLAG_DIRECTORY != 0,
+    }
+}
+
+// The System Use area follows the name and its padding byte.
+fn system_use_area(record: &[u8], skip: usize) -> &[u8] {
+    let name_len = record[32] as usize;
+    let start = 33 + name_len + (1 - name_len % 2) + skip;
+    if start >= record.len() { &record[..0] } else { &record[start..] }
+}
+
+// Call `f` with each SUSP entry (signature, entry bytes) in a System Use area.
+fn for_each_susp_entry<F: FnMut(&[u8], &[u8])>(area: &[u8], mut f: F) {
+    let mut pos = 0;
+    while pos + 4 <= area.len() {
+        let len = area[pos + 2] as usize;
+        if len < 4 || pos + len > area.len() {
+            break;
+        }
+        f(&area[pos..pos + 2], &area[pos..pos + len]);
+        pos += len;
+    }
+}
+
+fn eq_ignore_ascii_case(a: &[u8], b: &[u8]) -> bool {
+    let lower = |c: u8| if c >= b'A' && c <= b'Z' { c + 32 } else { c };
+    a.len() == b.len() && a.iter().zip(b.iter()).all(|(x, y)| lower(*x) == lower(*y))
+}
+
+impl<'a> Volume<'a> {
+    pub fn read_blocks(&self, block: u32, buffer: &mut [u8]) -> Result<(), ErrorStr> {
+        assert!(buffer.len() % BLOCK_SIZE == 0);
+        self.device.read_sectors(self.start_sector + block * self.sectors_per_block, buffer)
+    }
+
+    pub fn root(&self) -> File {
+        self.root
+    }
+
+    pub fn iterate_dir<'b>(&'b self, dir: &File) -> DirIterator<'b, 'a> {
+        DirIterator {
+            volume: self,
+            dir: *dir,
+            offset: 0,
+            block: [0u8; BLOCK_SIZE],
+            loaded_block: None,
+        }
+    }
+
+    pub fn find(&self, dir: &File, name: &[u8]) -> Result<Option<File>, ErrorStr> {
+        let mut iter = self.iterate_dir(dir);
+        while let Some(entry) = try!(iter.next()) {
+            if eq_ignore_ascii_case(entry.name(), name) {
+                return Ok(Some(entry.file));
+            }
+        }
+        Ok(None)
+    }
+
+    // Look up a '/'-separated path, relative to the root directory.
+    pub fn lookup(&self, path: &[u8]) -> Result<Option<File>, ErrorStr> {
+        let mut file = self.root;
+        for component in path.split(|c| *c == b'/') {
+            if component.is_empty() {
+                continue;
+            }
+            if !file.is_dir {
+                return Ok(None);
+            }
+            file = match try!(self.find(&file, component)) {
+                None => return Ok(None),
+                Some(file) => file,
+            };
+        }
+        Ok(Some(file))
+    }
+
+    // Read a whole file into the front of the buffer and return its size.
+    // Whole blocks go straight into the buffer if the BIOS could transfer to
+    // it.  Otherwise (e.g. for a buffer above 0x80000), each block is bounced,
+    // as is a partial last block.
+    pub fn read_contents(&self, file: &File, buffer: &mut [u8]) -> Result<u32, ErrorStr> {
+        let size = file.size as usize;
+        if buffer.len() < size {
+            return Err(ErrorStr::new(strlit!("file is too large for the buffer")));
+        }
+        let whole = size / BLOCK_SIZE * BLOCK_SIZE;
+        let mut done = 0;
+        if whole > 0 && ::is_bios_buffer(&buffer[..whole]) {
+            try!(self.read_blocks(file.extent, &mut buffer[..whole]));
+            done = whole;
+        }
+        while done < size {
+            let mut block = [0u8; BLOCK_SIZE];
+            try!(self.read_blocks(file.extent + (done / BLOCK_SIZE) as u32, &mut block));
+            let count = cmp::min(BLOCK_SIZE, size - done);
+            copy_bytes(&mut buffer[done..done + count], &block);
+            done += count;
+        }
+        Ok(file.size)
+    }
+}
+
+impl<'a> FileSource for Volume<'a> {
+    fn file_size(&self, path: &[u8]) -> Result<Option<u32>, ErrorStr> {
+        Ok(try!(self.lookup(path)).map(|file| file.size))
+    }
+
+    fn read_file(&self, path: &[u8], buffer: &mut [u8]) -> Result<u32, ErrorStr> {
+        match try!(self.lookup(path)) {
+            None => Err(ErrorStr::new(strlit!("file not found"))),
+            Some(file) => self.read_contents(&file, buffer),
+        }
+    }
+}
+
+pub struct DirIterator<'a, 'b: 'a> {
+    volume: &'a Volume<'b>,
+    dir: File,
+    offset: u32,
+    block: [u8; BLOCK_SIZE],
+    loaded_block: Option<u32>,
+}
+
+impl<'a, 'b> DirIterator<'a, 'b> {
+    fn decode_name(&self, record: &[u8], entry: &mut DirEntry) {
+        let raw = &record[33..33 + record[32] as usize];
+
+        if let Names::RockRidge { skip } = self.volume.names {
+            let mut found = false;
+            for_each_susp_entry(system_use_area(record, skip), |sig, data| {
+                if sig == &b"NM"[..] && data.len() >= 5 &&
+                        data[4] & (NM_CURRENT | NM_PARENT) == 0 {
+                    for ch in data[5..].iter() {
+                        entry.push(*ch);
+                    }
+                    found = found || data[4] & NM_CONTINUE == 0;
+                }
+            });
+            if found {
+                return;
+            }
+            entry.name_len = 0;
+        }
+
+        if self.volume.names == Names::Joliet {
+            // UCS-2, big-endian.
+            for pair in raw.chunks(2) {
+                if pair.len() < 2 {
+                    break;
+                }
+                let ch = ((pair[0] as u32) << 8) | pair[1] as u32;
+                if ch == b';' as u32 {
+                    break;
+                }
+                if ch < 0x80 {
+                    entry.push(ch as u8);
+                } else if ch < 0x800 {
+                    entry.push(0xc0 | (ch >> 6) as u8);
+                    entry.push(0x80 | (ch & 0x3f) as u8);
+                } else {
+                    entry.push(0xe0 | (ch >> 12) as u8);
+                    entry.push(0x80 | ((ch >> 6) & 0x3f) as u8);
+                    entry.push(0x80 | (ch & 0x3f) as u8);
+                }
+            }
+            return;
+        }
+
+        // Primary names: drop the version and a trailing dot ("README.;1").
+        let mut end = raw.iter().position(|c| *c == b';').unwrap_or(raw.len());
+        if end > 0 && raw[end - 1] == b'.' {
+            end -= 1;
+        }
+        for ch in raw[..end].iter() {
+            entry.push(*ch);
+        }
+    }
+
+    // Return the next entry, skipping the "." and ".." entries.
+    pub fn next(&mut self) -> Result<Option<DirEntry>, ErrorStr> {
+        loop {
+            if self.offset >= self.dir.size {
+                return Ok(None);
+            }
+            let block = self.dir.extent + self.offset / BLOCK_SIZE as u32;
+            if self.loaded_block != Some(block) {
+                try!(self.volume.read_blocks(block, &mut self.block));
+                self.loaded_block = Some(block);
+            }
+            let pos = self.offset as usize % BLOCK_SIZE;
+            let len = self.block[pos] as usize;
+            if len == 0 {
+                // Records do not cross block boundaries; the rest of this
+                // block is padding.
+                self.offset = (self.offset / BLOCK_SIZE as u32 + 1) * BLOCK_SIZE as u32;
+                continue;
+            }
+            if len < 34 || pos + len > BLOCK_SIZE || 33 + self.block[pos + 32] as usize > len {
+                return Err(ErrorStr::new(strlit!("corrupt ISO 9660 directory")));
+            }
+            self.offset += len as u32;
+
+            let record = &self.block[pos..pos + len];
+            let name_len = record[32];
+            if name_len == 1 && (record[33] == 0 || record[33] == 1) {
+                continue;
+            }
+            let mut entry = DirEntry {
+                file: parse_record(record),
+                name: [0u8; MAX_NAME_LEN],
+                name_len: 0,
+            };
+            self.decode_name(record, &mut entry);
+            return Ok(Some(entry));
+        }
+    }
+}
+
+// Check the root directory's "." entry for the SUSP "SP" entry, which marks
+// a volume with Rock Ridge (or other SUSP) extensions.
+fn detect_rock_ridge(volume: &Volume) -> Result<Option<usize>, ErrorStr> {
+    let mut block = [0u8; BLOCK_SIZE];
+    try!(volume.read_blocks(volume.root.extent, &mut block));
+    let len = block[0] as usize;
+    if len < 34 {
+        return Ok(None);
+    }
+    let mut skip = None;
+    for_each_susp_entry(system_use_area(&block[..len], 0), |sig, data| {
+        if sig == &b"SP"[..] && data.len() >= 7 && data[4] == 0xbe && data[5] == 0xef {
+            skip = Some(data[6] as usize);
+        }
+    });
+    Ok(skip)
+}
+
+// Open the ISO 9660 volume starting at `start_sector` on the device.
+pub fn open_volume<'a>(device: &'a BlockDevice, start_sector: SectorIndex) ->
+        Result<Volume<'a>, ErrorStr> {
+    let sector_size = device.sector_size();
+    if sector_size > BLOCK_SIZE || BLOCK_SIZE % sector_size != 0 {
+        return Err(ErrorStr::new(strlit!("unsupported sector size for ISO 9660")));
+    }
+    let mut volume = Volume {
+        device: device,
+        start_sector: start_sector,
+        sectors_per_block: (BLOCK_SIZE / sector_size) as u32,
+        root: File { extent: 0, size: 0, is_dir: true },
+        names: Names::Primary,
+    };
+
+    let mut primary_root = None;
+    let mut joliet_root = None;
+    let mut block = [0u8; BLOCK_SIZE];
+    for index in FIRST_DESCRIPTOR_BLOCK..FIRST_DESCRIPTOR_BLOCK + MAX_DESCRIPTOR_BLOCKS {
+        try!(volume.read_blocks(index, &mut block));
+        if &block[1..6] != &b"CD001"[..] {
+            break;
+        }
+        let root = parse_record(&block[ROOT_RECORD_OFFSET..ROOT_RECORD_OFFSET + 34]);
+        match block[0] {
+            DESCRIPTOR_PRIMARY => primary_root = Some(root),
+            DESCRIPTOR_SUPPLEMENTARY => {
+                let escape = &block[JOLIET_ESCAPE_OFFSET..JOLIET_ESCAPE_OFFSET + 3];
+                if escape == &b"%/@"[..] || escape == &b"%/C"[..] ||
+                        escape == &b"%/E"[..] {
+                    joliet_root = Some(root);
+                }
+            },
+            DESCRIPTOR_TERMINATOR => break,
+            _ => {},
+        }
+    }
+
+    volume.root = match primary_root {
+        None => return Err(ErrorStr::new(strlit!("no ISO 9660 primary volume descriptor"))),
+        Some(root) => root,
+    };
+    if let Some(skip) = try!(detect_rock_ridge(&volume)) {
+        volume.names = Names::RockRidge { skip: skip };
+    } else if let Some(root) = joliet_root {
+        volume.root = root;
+        volume.names = Names::Joliet;
+    }
+    Ok(volume)
+}
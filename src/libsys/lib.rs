@@ -1,5 +1,6 @@
 #![crate_name = "sys"]
 #![crate_type = "rlib"]
+#![feature(core_intrinsics)]
 #![feature(lang_items)]
 #![no_std]
 
@@ -7,6 +8,13 @@ use core::mem;
 use core::cmp;
 use core::fmt;
 
+// Define a dummy std module that contains libcore's fmt module.  The std::fmt
+// module is needed to satisfy the std::fmt::Arguments reference created by the
+// format_args! and format_args_method! built-in macros.
+mod std {
+    pub use core::fmt;
+}
+
 #[macro_use] mod macros;
 
 pub mod num_to_str;
@@ -16,8 +24,12 @@ extern "C" {
     fn print_char_16bit();
     fn check_for_int13_extensions();
     fn get_disk_geometry();
+    fn get_drive_parameters_16bit();
     fn read_disk_lba();
     fn read_disk_chs();
+    fn write_disk_lba();
+    fn write_disk_chs();
+    fn wait_16bit();
     fn halt_16bit();
 }
 
@@ -39,14 +51,30 @@ extern "C" {
 macro_rules! assert {
     ($cond:expr) => (
         if !$cond {
-            simple_panic(strlit!(file!()),
-                         line!(),
-                         strlit!("assert fail: "),
-                         strlit!(stringify!($cond)))
+            ::simple_panic(strlit!(file!()),
+                           line!(),
+                           strlit!("assert fail: "),
+                           strlit!(stringify!($cond)))
         }
     );
 }
 
+pub mod acpi;
+pub mod cpuid;
+pub mod crc32;
+pub mod edd;
+pub mod exfat;
+pub mod fat32;
+pub mod io;
+pub mod iso9660;
+pub mod log;
+pub mod pci;
+mod power;
+pub mod rtc;
+pub mod unicode;
+
+pub use power::{power_off, reboot, reboot_after_timeout};
+
 #[inline(never)]
 pub fn print_char(ch: u8) {
     unsafe {
@@ -93,6 +121,14 @@ pub enum IoMethod {
 pub struct Disk {
     bios_number: u8,
     io_method: IoMethod,
+    sector_size: usize,
+}
+
+impl Disk {
+    // 512 for hard disks, 2048 for CDs.
+    pub fn sector_size(&self) -> usize {
+        self.sector_size
+    }
 }
 
 // TODO: Can we change this to an enum?  What's the representation overhead of
@@ -106,6 +142,10 @@ impl ErrorStr {
     pub fn new(msg: StrLit) -> ErrorStr {
         ErrorStr { msg: msg }
     }
+
+    pub fn msg(&self) -> StrLit {
+        self.msg
+    }
 }
 
 impl fmt::Display for ErrorStr {
@@ -120,6 +160,44 @@ impl fmt::Debug for ErrorStr {
     }
 }
 
+// Ask INT 13h/48h for the sector size.  CD drives booted with El Torito "no
+// emulation" use 2048-byte sectors.  Assume 512 if the BIOS does not say.
+fn get_lba_sector_size(bios_disk_number: u8) -> usize {
+    #[repr(C)]
+    struct DriveParameters {
+        size: u16,
+        flags: u16,
+        geometry: [u32; 3],
+        total_sectors: u64,
+        bytes_per_sector: u16,
+    }
+    let mut params = DriveParameters {
+        size: mem::size_of::<DriveParameters>() as u16,
+        flags: 0,
+        geometry: [0; 3],
+        total_sectors: 0,
+        bytes_per_sector: 0,
+    };
+    let params_ptr = addr_linear_to_segmented(&mut params as *mut DriveParameters as u32);
+    let ok = unsafe {
+        call_real_mode(
+            get_drive_parameters_16bit,
+            bios_disk_number as u32,
+            params_ptr) as u8 != 0
+    };
+    if ok && params.bytes_per_sector == 2048 {
+        2048
+    } else {
+        SECTOR_SIZE
+    }
+}
+
+// The number of hard disks the BIOS found, from the BIOS data area.  They are
+// numbered 0x80 and up.
+pub fn hard_disk_count() -> u8 {
+    unsafe { *(0x475 as *const u8) }
+}
+
 pub fn open_disk(bios_disk_number: u8) -> Result<Disk, ErrorStr> {
     let has_int13_extensions = unsafe {
         call_real_mode(
@@ -129,7 +207,8 @@ pub fn open_disk(bios_disk_number: u8) -> Result<Disk, ErrorStr> {
     if has_int13_extensions {
         Ok(Disk {
             bios_number: bios_disk_number,
-            io_method: IoMethod::Lba
+            io_method: IoMethod::Lba,
+            sector_size: get_lba_sector_size(bios_disk_number),
         })
     } else {
         let mut geometry = Chs { cylinder: 0, head: 0, sector: 0 };
@@ -145,7 +224,8 @@ pub fn open_disk(bios_disk_number: u8) -> Result<Disk, ErrorStr> {
         }
         Ok(Disk {
             bios_number: bios_disk_number,
-            io_method: IoMethod::Chs(geometry)
+            io_method: IoMethod::Chs(geometry),
+            sector_size: SECTOR_SIZE,
         })
     }
 }
@@ -166,12 +246,15 @@ pub fn convert_lba_to_chs(lba: SectorIndex, geometry: &Chs) ->
     })
 }
 
+// Memory past 0x80000 is reserved[1] anyway, so BIOS buffers must end below
+// it.
+// [1] http://wiki.osdev.org/Memory_Map_(x86)#Overview
+const BIOS_ADDRESS_LIMIT: u32 = 0x80000;
+
 pub fn addr_linear_to_segmented(linear: u32) -> u32 {
     // Ensure that the address if convertable to a 16-bit segment:offset far
-    // pointer.  Memory past 0x80000 is reserved[1] anyway, so use that as the
-    // limit for simplicity.
-    // [1] http://wiki.osdev.org/Memory_Map_(x86)#Overview
-    assert!(linear as u32 <= 0x80000);
+    // pointer.  Use the reserved-memory limit for simplicity.
+    assert!(linear as u32 <= BIOS_ADDRESS_LIMIT);
     let offset = linear % 16;
     let segment = linear / 16;
     (segment << 16) | offset
@@ -182,31 +265,68 @@ pub fn read_disk_sectors(
         start_sector: SectorIndex,
         buffer: &mut [u8]) ->
         Result<(), ErrorStr> {
+    transfer_disk_sectors(disk, start_sector, buffer.as_mut_ptr() as u32, buffer.len(), false)
+}
+
+pub fn write_disk_sectors(
+        disk: &Disk,
+        start_sector: SectorIndex,
+        buffer: &[u8]) ->
+        Result<(), ErrorStr> {
+    transfer_disk_sectors(disk, start_sector, buffer.as_ptr() as u32, buffer.len(), true)
+}
+
+// Whether the BIOS can transfer sectors directly to or from the buffer.
+// Callers must bounce other buffers through one that passes this check.
+pub fn is_bios_buffer(buffer: &[u8]) -> bool {
+    let addr = buffer.as_ptr() as u32;
+    addr % 2 == 0 && addr < BIOS_ADDRESS_LIMIT &&
+        BIOS_ADDRESS_LIMIT - addr >= buffer.len() as u32
+}
+
+fn transfer_disk_sectors(
+        disk: &Disk,
+        start_sector: SectorIndex,
+        buffer_addr: u32,
+        buffer_len: usize,
+        write: bool) ->
+        Result<(), ErrorStr> {
+
+    let sector_size = disk.sector_size;
 
-    // Only allow reads of integral count of sectors.
-    assert!(buffer.len() % SECTOR_SIZE == 0);
+    // Only allow transfers of integral count of sectors.
+    assert!(buffer_len % sector_size == 0);
 
     // osdev claims that the buffer address must be 2-byte aligned.
     // http://wiki.osdev.org/ATA_in_x86_RealMode_(BIOS)
-    assert!(buffer.as_mut_ptr() as u32 % 2 == 0);
+    assert!(buffer_addr % 2 == 0);
 
-    let sector_count = (buffer.len() / SECTOR_SIZE) as SectorIndex;
+    let sector_count = (buffer_len / sector_size) as SectorIndex;
 
     let mut loop_count: SectorIndex = sector_count;
     let mut loop_sector: SectorIndex = start_sector;
-    let mut loop_buffer: u32 = buffer.as_mut_ptr() as u32;
+    let mut loop_buffer: u32 = buffer_addr;
 
     // Ensure that even the byte past the end of the buffer is addressable
     // using a 16-bit segment:offset far pointer.  (The function asserts that
     // the linear address is convertible.)
-    let _ = addr_linear_to_segmented(loop_buffer + buffer.len() as u32);
+    let _ = addr_linear_to_segmented(loop_buffer + buffer_len as u32);
+
+    let (lba_func, chs_func): (unsafe extern "C" fn(), unsafe extern "C" fn()) = if write {
+        (write_disk_lba, write_disk_chs)
+    } else {
+        (read_disk_lba, read_disk_chs)
+    };
+    let error = if write { strlit!("disk write error") } else { strlit!("disk read error") };
 
     while loop_count > 0 {
         let iter_count: i8;
 
         match disk.io_method {
             IoMethod::Lba => {
-                iter_count = cmp::min(loop_count, 127) as i8;
+                // Keep each transfer within a 64KiB segment.
+                iter_count = cmp::min(loop_count,
+                                      (127 * SECTOR_SIZE / sector_size) as SectorIndex) as i8;
                 #[repr(C)]
                 struct DiskAccessPacket {
                     size: u8,
@@ -228,10 +348,10 @@ pub fn read_disk_sectors(
                 };
                 unsafe {
                     if call_real_mode(
-                            read_disk_lba,
+                            lba_func,
                             disk.bios_number as u32,
                             dap) as u8 == 0 {
-                        return Err(ErrorStr::new(strlit!("disk read error")));
+                        return Err(ErrorStr::new(error));
                     }
                 }
             },
@@ -239,21 +359,21 @@ pub fn read_disk_sectors(
             IoMethod::Chs(ref geometry) => {
                 match convert_lba_to_chs(loop_sector, &geometry) {
                     Ok(chs) => {
-                        // For maximum compatibility, avoid doing a read that
-                        // crosses a track boundary.
+                        // For maximum compatibility, avoid doing a transfer
+                        // that crosses a track boundary.
                         iter_count =
                             cmp::min(loop_count,
                                 (geometry.sector - chs.sector) as SectorIndex)
                                     as i8;
                         unsafe {
                             if call_real_mode(
-                                    read_disk_chs,
+                                    chs_func,
                                     disk.bios_number as u32,
                                     chs,
                                     iter_count as u32,
                                     addr_linear_to_segmented(loop_buffer))
                                     as u8 == 0 {
-                                return Err(ErrorStr::new(strlit!("disk read error")));
+                                return Err(ErrorStr::new(error));
                             }
                         }
                     },
@@ -264,12 +384,59 @@ pub fn read_disk_sectors(
 
         loop_sector += iter_count as SectorIndex;
         loop_count -= iter_count as SectorIndex;
-        loop_buffer += iter_count as u32 * SECTOR_SIZE as u32;
+        loop_buffer += iter_count as u32 * sector_size as u32;
     }
 
     Ok(())
 }
 
+// A disk addressed in sectors, usually of 512 bytes.  The BIOS-backed Disk
+// implements this, and so do stage2's native disk drivers, so that code above
+// the disk layer does not care which one it is using.
+pub trait BlockDevice {
+    fn sector_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+        Result<(), ErrorStr>;
+
+    fn write_sectors(&self, _start_sector: SectorIndex, _buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        Err(ErrorStr::new(strlit!("disk writes are not supported")))
+    }
+}
+
+impl BlockDevice for Disk {
+    fn sector_size(&self) -> usize {
+        self.sector_size
+    }
+
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        read_disk_sectors(self, start_sector, buffer)
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        write_disk_sectors(self, start_sector, buffer)
+    }
+}
+
+// A source of whole files, such as a filesystem volume or a TFTP server.
+// Paths are '/'-separated byte strings.
+pub trait FileSource {
+    // Returns None if the file does not exist.
+    fn file_size(&self, path: &[u8]) -> Result<Option<u32>, ErrorStr>;
+
+    // Read a whole file into the front of the buffer and return its size.
+    fn read_file(&self, path: &[u8], buffer: &mut [u8]) -> Result<u32, ErrorStr>;
+}
+
+pub fn get16(buffer: &[u8], offset: usize) -> u16 {
+    (buffer[offset] as u16) | ((buffer[offset + 1] as u16) << 8)
+}
+
 pub fn get32(buffer: &[u8], offset: usize) -> u32 {
     let buffer = buffer;
     assert!(offset < offset + 4 && offset + 4 <= buffer.len());
@@ -278,6 +445,44 @@ pub fn get32(buffer: &[u8], offset: usize) -> u32 {
     }
 }
 
+pub fn get64(buffer: &[u8], offset: usize) -> u64 {
+    (get32(buffer, offset) as u64) | ((get32(buffer, offset + 4) as u64) << 32)
+}
+
+// Copy bytes from `src` to `dst`, stopping at the end of the shorter slice.
+// (The supported compiler's libcore has no slice::copy_from_slice.)
+pub fn copy_bytes(dst: &mut [u8], src: &[u8]) {
+    for (dst, src) in dst.iter_mut().zip(src.iter()) {
+        *dst = *src;
+    }
+}
+
+pub fn wait_microseconds(microseconds: u32) {
+    unsafe {
+        call_real_mode(wait_16bit, microseconds);
+    }
+}
+
+// An optional hook run before halting on a fatal error.  stage2 can use it to
+// reboot after a timeout instead of hanging.  stage1 leaves it unset so that
+// the reboot code is not linked in.
+static mut FATAL_ERROR_HANDLER: Option<fn()> = None;
+
+pub fn set_fatal_error_handler(handler: fn()) {
+    unsafe {
+        FATAL_ERROR_HANDLER = Some(handler);
+    }
+}
+
+pub fn fatal_error_halt() -> ! {
+    // Clear the handler first, in case it fails too.
+    let handler = unsafe { FATAL_ERROR_HANDLER.take() };
+    if let Some(handler) = handler {
+        handler();
+    }
+    halt();
+}
+
 pub fn halt() -> ! {
     unsafe {
         call_real_mode(halt_16bit);
@@ -308,9 +513,10 @@ pub fn simple_panic(file: StrRef, line: u32, err1: StrRef, err2: StrRef) -> ! {
     print_str(strlit!(": "));
     print_str(err1);
     print_str(err2);
-    halt();
+    fatal_error_halt();
 }
 
 mod sys {
     pub use StrLit;
+    pub use log;
 }
@@ -0,0 +1,241 @@
+// Leveled logging.
+//
+// Messages at or below the current level are written to each enabled sink and
+// appended to an in-memory ring buffer, if one has been set.  The ring buffer
+// keeps the most recent output so that stage2 can display it and pass it on
+// to the booted OS.  stage1 sets none, so that it does not carry the buffer.
+//
+// The level can be overridden per module.  A filter for "sys::fat32"
+// applies to that module and to any module nested inside it.
+//
+// Use the log_error!, log_warn!, log_info!, log_debug!, and log_trace! macros
+// rather than calling write() directly.  Code that stage1 links must not use
+// them, since they pull in core::fmt; it uses log_str! for messages without
+// arguments instead.
+
+use core::fmt;
+use core::slice;
+
+use io;
+use print_char;
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+pub const SINK_SCREEN: u32      = 1 << 0;
+pub const SINK_SERIAL: u32      = 1 << 1;
+pub const SINK_DEBUG_PORT: u32  = 1 << 2;       // QEMU/Bochs port 0xE9
+
+const SERIAL_PORT: u16 = 0x3f8;                 // COM1
+const DEBUG_PORT: u16 = 0xe9;
+
+const MAX_MODULE_FILTERS: usize = 8;
+
+static mut SINKS: u32 = SINK_SCREEN;
+static mut MAX_LEVEL: Level = Level::Info;
+static mut MODULE_FILTERS: [Option<(&'static str, Level)>; MAX_MODULE_FILTERS] =
+    [None; MAX_MODULE_FILTERS];
+
+static mut RING_START: *mut u8 = 0 as *mut u8;
+static mut RING_SIZE: usize = 0;
+static mut RING_NEXT: usize = 0;
+static mut RING_WRAPPED: bool = false;
+
+pub fn set_sinks(sinks: u32) {
+    if sinks & SINK_SERIAL != 0 {
+        init_serial();
+    }
+    unsafe { SINKS = sinks; }
+}
+
+// Keep the most recent output in the given buffer.  Earlier output is lost.
+pub fn set_ring_buffer(buffer: &'static mut [u8]) {
+    unsafe {
+        RING_START = buffer.as_mut_ptr();
+        RING_SIZE = buffer.len();
+        RING_NEXT = 0;
+        RING_WRAPPED = false;
+    }
+}
+
+fn ring() -> &'static mut [u8] {
+    unsafe {
+        if RING_SIZE == 0 {
+            &mut []
+        } else {
+            slice::from_raw_parts_mut(RING_START, RING_SIZE)
+        }
+    }
+}
+
+pub fn set_level(level: Level) {
+    unsafe { MAX_LEVEL = level; }
+}
+
+// Override the level for a module (e.g. "sys::fat32") and its children.
+// Returns false if the filter table is full.
+pub fn set_module_level(module: &'static str, level: Level) -> bool {
+    unsafe {
+        for filter in MODULE_FILTERS.iter_mut() {
+            let replace = match *filter {
+                None => true,
+                Some((name, _)) => name == module,
+            };
+            if replace {
+                *filter = Some((module, level));
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn module_matches(module: &str, prefix: &str) -> bool {
+    let module = module.as_bytes();
+    let prefix = prefix.as_bytes();
+    module.len() >= prefix.len() &&
+        &module[..prefix.len()] == prefix &&
+        (module.len() == prefix.len() ||
+            (module.len() >= prefix.len() + 2 &&
+                &module[prefix.len()..prefix.len() + 2] == b"::"))
+}
+
+pub fn enabled(level: Level, module: &str) -> bool {
+    unsafe {
+        // The most specific (longest) matching filter wins.
+        let mut max_level = MAX_LEVEL;
+        let mut best_len = 0;
+        for filter in MODULE_FILTERS.iter() {
+            if let Some((name, filter_level)) = *filter {
+                if name.len() >= best_len && module_matches(module, name) {
+                    max_level = filter_level;
+                    best_len = name.len();
+                }
+            }
+        }
+        level <= max_level
+    }
+}
+
+fn init_serial() {
+    io::outb(SERIAL_PORT + 1, 0x00);        // Disable interrupts.
+    io::outb(SERIAL_PORT + 3, 0x80);        // Set DLAB to program the divisor.
+    io::outb(SERIAL_PORT + 0, 0x01);        // 115200 baud
+    io::outb(SERIAL_PORT + 1, 0x00);
+    io::outb(SERIAL_PORT + 3, 0x03);        // 8 data bits, no parity, 1 stop
+    io::outb(SERIAL_PORT + 2, 0xc7);        // Enable and clear the FIFOs.
+    io::outb(SERIAL_PORT + 4, 0x03);        // Assert DTR and RTS.
+}
+
+fn serial_write(ch: u8) {
+    // Wait for the transmit holding register to empty.  Give up eventually,
+    // in case there is no UART.
+    let mut tries = 0;
+    while io::inb(SERIAL_PORT + 5) & 0x20 == 0 && tries < 100000 {
+        tries += 1;
+    }
+    io::outb(SERIAL_PORT, ch);
+}
+
+fn emit(ch: u8) {
+    unsafe {
+        if RING_SIZE != 0 {
+            ring()[RING_NEXT] = ch;
+            RING_NEXT += 1;
+            if RING_NEXT == RING_SIZE {
+                RING_NEXT = 0;
+                RING_WRAPPED = true;
+            }
+        }
+        let sinks = SINKS;
+        if sinks & SINK_SCREEN != 0 {
+            print_char(ch);
+        }
+        if sinks & SINK_SERIAL != 0 {
+            serial_write(ch);
+        }
+        if sinks & SINK_DEBUG_PORT != 0 {
+            io::outb(DEBUG_PORT, ch);
+        }
+    }
+}
+
+struct LogWriter;
+
+impl fmt::Write for LogWriter {
+    fn write_str(&mut self, x: &str) -> fmt::Result {
+        for ch in x.as_bytes().iter() {
+            emit(*ch);
+        }
+        Ok(())
+    }
+}
+
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+#[inline(never)]
+pub fn write(level: Level, module: &str, args: fmt::Arguments) {
+    use core::fmt::Write;
+    let mut writer = LogWriter;
+    let _ = writer.write_str(level_name(level));
+    let _ = writer.write_str(": ");
+    let _ = writer.write_str(module);
+    let _ = writer.write_str(": ");
+    let _ = fmt::write(&mut writer, args);
+    let _ = writer.write_str("\r\n");
+}
+
+// Like write(), but for a message without arguments.
+#[inline(never)]
+pub fn write_str(level: Level, module: &str, msg: &str) {
+    for part in [level_name(level), ": ", module, ": ", msg, "\r\n"].iter() {
+        for ch in part.as_bytes().iter() {
+            emit(*ch);
+        }
+    }
+}
+
+// Return the ring buffer contents as two slices, oldest first.
+pub fn ring_buffer() -> (&'static [u8], &'static [u8]) {
+    let ring = ring();
+    unsafe {
+        if RING_WRAPPED {
+            (&ring[RING_NEXT..], &ring[..RING_NEXT])
+        } else {
+            (&ring[..RING_NEXT], &ring[..0])
+        }
+    }
+}
+
+// Rotate the ring buffer in place so that its contents are contiguous, oldest
+// first, and return them.  Used to hand the log to the booted OS.  Further
+// logging continues after the returned bytes.
+pub fn linearize_ring_buffer() -> &'static [u8] {
+    let ring = ring();
+    unsafe {
+        if RING_WRAPPED {
+            // Rotate left by RING_NEXT using three reversals.
+            ring[..RING_NEXT].reverse();
+            ring[RING_NEXT..].reverse();
+            ring.reverse();
+            RING_NEXT = 0;
+            &ring[..]
+        } else {
+            &ring[..RING_NEXT]
+        }
+    }
+}
@@ -21,3 +21,50 @@ macro_rules! strref {
 
 #[cfg(not(strref))] #[macro_export] macro_rules! strlit { ($x:expr) => { $x } }
 #[cfg(not(strref))] #[macro_export] macro_rules! strref { ($x:expr) => { $x } }
+
+// Logging macros.  See log.rs.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ({
+        let level = $level;
+        if ::sys::log::enabled(level, module_path!()) {
+            ::sys::log::write(level, module_path!(), format_args!($($arg)*))
+        }
+    });
+}
+
+// Log a message without arguments, without using core::fmt.
+#[macro_export]
+macro_rules! log_str {
+    ($level:expr, $msg:expr) => ({
+        let level = $level;
+        if ::sys::log::enabled(level, module_path!()) {
+            ::sys::log::write_str(level, module_path!(), $msg)
+        }
+    });
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)*) => (log!(::sys::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)*) => (log!(::sys::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)*) => (log!(::sys::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => (log!(::sys::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! log_trace {
+    ($($arg:tt)*) => (log!(::sys::log::Level::Trace, $($arg)*));
+}
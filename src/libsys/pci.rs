@@ -0,0 +1,143 @@
+// PCI configuration space access using configuration mechanism #1 (ports
+// 0xCF8 and 0xCFC).
+
+use io;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+
+#[derive(Copy, Clone, PartialEq)]
+pub struct Address {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+fn select(addr: Address, offset: u8) {
+    io::outl(CONFIG_ADDRESS,
+             0x8000_0000 |
+             ((addr.bus as u32) << 16) |
+             (((addr.device & 0x1f) as u32) << 11) |
+             (((addr.function & 0x7) as u32) << 8) |
+             ((offset & 0xfc) as u32));
+}
+
+pub fn read32(addr: Address, offset: u8) -> u32 {
+    select(addr, offset);
+    io::inl(CONFIG_DATA)
+}
+
+pub fn read16(addr: Address, offset: u8) -> u16 {
+    (read32(addr, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read8(addr: Address, offset: u8) -> u8 {
+    (read32(addr, offset) >> ((offset & 3) * 8)) as u8
+}
+
+pub fn write32(addr: Address, offset: u8, value: u32) {
+    select(addr, offset);
+    io::outl(CONFIG_DATA, value);
+}
+
+pub fn write16(addr: Address, offset: u8, value: u16) {
+    select(addr, offset);
+    io::outw(CONFIG_DATA + (offset & 2) as u16, value);
+}
+
+pub fn vendor_id(addr: Address) -> u16 {
+    read16(addr, 0x00)
+}
+
+pub fn device_id(addr: Address) -> u16 {
+    read16(addr, 0x02)
+}
+
+pub fn header_type(addr: Address) -> u8 {
+    read8(addr, 0x0e)
+}
+
+// Returns (class, subclass, programming interface).
+pub fn class_code(addr: Address) -> (u8, u8, u8) {
+    let value = read32(addr, 0x08);
+    ((value >> 24) as u8, (value >> 16) as u8, (value >> 8) as u8)
+}
+
+// Read a 32-bit memory BAR, stripping the flag bits.  Returns None for an I/O
+// BAR, an unassigned BAR, or a 64-bit BAR placed above 4GiB.
+pub fn memory_bar(addr: Address, index: u8) -> Option<u32> {
+    let offset = 0x10 + index * 4;
+    let value = read32(addr, offset);
+    if value & 1 != 0 {
+        return None;
+    }
+    if (value >> 1) & 3 == 2 && index < 5 && read32(addr, offset + 4) != 0 {
+        return None;
+    }
+    let base = value & !0xf;
+    if base == 0 { None } else { Some(base) }
+}
+
+// Read an I/O BAR.  Returns None for a memory BAR or an unassigned BAR.
+pub fn io_bar(addr: Address, index: u8) -> Option<u16> {
+    let value = read32(addr, 0x10 + index * 4);
+    if value & 1 == 0 {
+        return None;
+    }
+    let base = (value & !0x3) as u16;
+    if base == 0 { None } else { Some(base) }
+}
+
+// Enable memory and I/O space decoding and bus mastering (DMA).
+pub fn enable_device(addr: Address) {
+    let command = read16(addr, 0x04);
+    write16(addr, 0x04, command | 0x0007);
+}
+
+// Enumerate the functions present on every bus by brute force.
+pub struct DeviceIterator {
+    next: Option<Address>,
+}
+
+pub fn devices() -> DeviceIterator {
+    DeviceIterator { next: Some(Address { bus: 0, device: 0, function: 0 }) }
+}
+
+impl DeviceIterator {
+    fn advance(&mut self, current: Address, multi_function: bool) {
+        self.next = if multi_function && current.function < 7 {
+            Some(Address { function: current.function + 1, ..current })
+        } else if current.device < 31 {
+            Some(Address { device: current.device + 1, function: 0, ..current })
+        } else if current.bus < 255 {
+            Some(Address { bus: current.bus + 1, device: 0, function: 0 })
+        } else {
+            None
+        };
+    }
+
+    pub fn next(&mut self) -> Option<Address> {
+        while let Some(current) = self.next {
+            let function0 = Address { function: 0, ..current };
+            let multi_function = header_type(function0) & 0x80 != 0;
+            let present = vendor_id(current) != 0xffff;
+            self.advance(current, multi_function && vendor_id(function0) != 0xffff);
+            if present {
+                return Some(current);
+            }
+        }
+        None
+    }
+}
+
+// Find the first function with the given class and subclass.
+pub fn find_by_class(class: u8, subclass: u8) -> Option<Address> {
+    let mut iter = devices();
+    while let Some(addr) = iter.next() {
+        let (c, s, _) = class_code(addr);
+        if c == class && s == subclass {
+            return Some(addr);
+        }
+    }
+    None
+}
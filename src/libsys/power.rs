@@ -0,0 +1,68 @@
+// Rebooting and powering off the machine.
+
+use acpi;
+use io;
+use {call_real_mode, print_str, print_u32, wait_microseconds};
+
+extern "C" {
+    fn apm_power_off_16bit();
+}
+
+// Give a reset method time to take effect before trying the next one.
+fn settle() {
+    wait_microseconds(500_000);
+}
+
+fn reset_by_keyboard_controller() {
+    // Wait for the controller's input buffer to drain, then pulse the CPU
+    // reset line.
+    let mut tries = 0;
+    while io::inb(0x64) & 0x02 != 0 && tries < 100000 {
+        io::wait();
+        tries += 1;
+    }
+    io::outb(0x64, 0xfe);
+}
+
+fn reset_by_port_cf9() {
+    // Select a hard reset (SYS_RST), then trigger it (RST_CPU) with a full
+    // reset (FULL_RST).
+    io::outb(0xcf9, 0x02);
+    io::wait();
+    io::outb(0xcf9, 0x0e);
+}
+
+// Reset the machine.  Tries the ACPI reset register, the keyboard
+// controller, the PCI reset control register, and finally a triple fault.
+pub fn reboot() -> ! {
+    if acpi::reset() {
+        settle();
+    }
+    reset_by_keyboard_controller();
+    settle();
+    reset_by_port_cf9();
+    settle();
+    io::reset_by_triple_fault();
+}
+
+// Turn the machine off using APM, then ACPI.  Returns only if both methods
+// failed.
+pub fn power_off() {
+    unsafe { call_real_mode(apm_power_off_16bit); }
+    if acpi::power_off() {
+        settle();
+    }
+}
+
+// Count down, then reboot.  Used to recover from a fatal error without
+// requiring someone to press the reset button.
+pub fn reboot_after_timeout(seconds: u32) -> ! {
+    for remaining in (1..seconds + 1).rev() {
+        print_str(strlit!("\rRebooting in "));
+        print_u32(remaining);
+        print_str(strlit!(" seconds... "));
+        wait_microseconds(1_000_000);
+    }
+    print_str(strlit!("\r\n"));
+    reboot();
+}
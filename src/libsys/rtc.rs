@@ -0,0 +1,105 @@
+// The CMOS real-time clock.
+//
+// The clock's registers may be in BCD or binary, and the hour in 12- or
+// 24-hour form, as status register B says.  The clock updates itself once a
+// second, and reading during an update can return a torn value, so we wait
+// for the update-in-progress flag to clear and read until two consecutive
+// readings agree.
+
+use io;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECOND: u8 = 0x00;
+const REG_MINUTE: u8 = 0x02;
+const REG_HOUR: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_CENTURY: u8 = 0x32;       // not standard, but widely present
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+const MAX_READ_ATTEMPTS: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,          // 1-12
+    pub day: u8,            // 1-31
+    pub hour: u8,           // 0-23
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    io::outb(CMOS_ADDRESS, reg);
+    io::inb(CMOS_DATA)
+}
+
+fn read_raw() -> [u8; 7] {
+    let mut tries = 0;
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 && tries < 100000 {
+        io::wait();
+        tries += 1;
+    }
+    [read_register(REG_SECOND), read_register(REG_MINUTE), read_register(REG_HOUR),
+     read_register(REG_DAY), read_register(REG_MONTH), read_register(REG_YEAR),
+     read_register(REG_CENTURY)]
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+fn decode(raw: [u8; 7], status_b: u8) -> Option<DateTime> {
+    let convert = |value: u8| if status_b & STATUS_B_BINARY != 0 { value } else { from_bcd(value) };
+    let mut hour = convert(raw[2] & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12-hour form: 12 AM is midnight, and 12 PM is noon.
+        let pm = raw[2] & HOUR_PM != 0;
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let year = convert(raw[5]) as u16;
+    let century = convert(raw[6]) as u16;
+    let year = if century >= 19 && century <= 21 {
+        century * 100 + year
+    } else if year < 80 {
+        2000 + year
+    } else {
+        1900 + year
+    };
+    let time = DateTime {
+        year: year,
+        month: convert(raw[4]),
+        day: convert(raw[3]),
+        hour: hour,
+        minute: convert(raw[1]),
+        second: convert(raw[0]),
+    };
+    if time.month < 1 || time.month > 12 || time.day < 1 || time.day > 31 ||
+            time.hour > 23 || time.minute > 59 || time.second > 59 || year > 2099 {
+        return None;
+    }
+    Some(time)
+}
+
+// Read the current date and time.  Returns None if the clock does not settle
+// or holds an impossible value.
+pub fn read() -> Option<DateTime> {
+    let mut last = read_raw();
+    for _ in 0..MAX_READ_ATTEMPTS {
+        let raw = read_raw();
+        if raw == last {
+            return decode(raw, read_register(REG_STATUS_B));
+        }
+        last = raw;
+    }
+    None
+}
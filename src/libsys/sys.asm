@@ -108,6 +108,12 @@ get_disk_geometry:
         global read_disk_lba
 read_disk_lba:
         mov ah, 0x42
+        jmp transfer_disk_lba
+
+        global write_disk_lba
+write_disk_lba:
+        mov ax, 0x4300                  ; AL=0: write without verify
+transfer_disk_lba:
         mov dl, [bp + 0]
         push ss
         pop ds
@@ -121,6 +127,30 @@ read_disk_lba:
         ret
 
 
+        ;
+        ; Arguments:
+        ; [bp+0] disk: u8
+        ; [bp+4] buffer: far *mut edd::ResultBuffer
+        ;
+        ; The first word of the buffer must be initialized to its size.
+        ;
+        ; Return: 1 on success, 0 on failure
+        ;
+        global get_drive_parameters_16bit
+get_drive_parameters_16bit:
+        mov ah, 0x48
+        mov dl, [bp + 0]
+        mov si, [bp + 4]
+        mov ds, [bp + 6]
+        int 0x13
+        jc .fail
+        mov eax, 1
+        ret
+.fail:
+        xor eax, eax
+        ret
+
+
         ;
         ; Arguments:
         ; [bp+0] disk: u8
@@ -140,7 +170,13 @@ read_disk_lba:
         global read_disk_chs
 read_disk_chs:
         mov ah, 2
-        mov al, byte [bp + 12]          ; read count
+        jmp transfer_disk_chs
+
+        global write_disk_chs
+write_disk_chs:
+        mov ah, 3
+transfer_disk_chs:
+        mov al, byte [bp + 12]          ; sector count
         mov ch, byte [(bp + 4) + 0]     ; cylinder's low 8 bits
         mov cl, byte [(bp + 4) + 1]     ; cylinder's high 2 bits
         shl cl, 6                       ; shift cylinder high bits
@@ -161,6 +197,66 @@ read_disk_chs:
         ret
 
 
+        ;
+        ; Arguments:
+        ; [bp+0] microseconds: u32
+        ;
+        ; Waits using INT 15h/86h.  Interrupts are enabled while in real mode,
+        ; so the BIOS timer keeps running.
+        ;
+        global wait_16bit
+wait_16bit:
+        mov ah, 0x86
+        mov dx, [bp + 0]
+        mov cx, [bp + 2]
+        int 0x15
+        ret
+
+
+        ;
+        ; Turn the machine off using the APM real-mode interface.  Returns only
+        ; on failure.
+        ;
+        global apm_power_off_16bit
+apm_power_off_16bit:
+        ; APM installation check.
+        mov ax, 0x5300
+        xor bx, bx
+        int 0x15
+        jc .fail
+
+        ; Disconnect any existing interface (ignoring errors), then connect
+        ; the real-mode interface.
+        mov ax, 0x5304
+        xor bx, bx
+        int 0x15
+        mov ax, 0x5301
+        xor bx, bx
+        int 0x15
+        jc .fail
+
+        ; Declare that we are an APM 1.2 driver.  APM 1.0 BIOSes lack this
+        ; call, so ignore errors.
+        mov ax, 0x530e
+        xor bx, bx
+        mov cx, 0x0102
+        int 0x15
+
+        ; Enable power management for all devices, then set the power state
+        ; of all devices to "off".
+        mov ax, 0x5308
+        mov bx, 1
+        mov cx, 1
+        int 0x15
+        mov ax, 0x5307
+        mov bx, 1
+        mov cx, 3
+        int 0x15
+.fail:
+        xor eax, eax
+        ret
+
+
         ;
         ; halt_16bit.  Halts the CPU without affecting the interrupt state.
         ;
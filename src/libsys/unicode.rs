@@ -0,0 +1,107 @@
+// UTF-8 and UTF-16 conversions for file and partition names.
+//
+// Malformed input decodes to U+FFFD rather than failing, so that a damaged
+// name can still be displayed and compared.
+
+use copy_bytes;
+
+pub const REPLACEMENT_CHAR: u32 = 0xfffd;
+
+// Append a character to a UTF-8 buffer, dropping it if it does not fit.
+pub fn push_utf8(buffer: &mut [u8], len: &mut usize, ch: u32) {
+    let mut bytes = [0u8; 4];
+    let count = if ch < 0x80 {
+        bytes[0] = ch as u8;
+        1
+    } else if ch < 0x800 {
+        bytes[0] = 0xc0 | (ch >> 6) as u8;
+        bytes[1] = 0x80 | (ch & 0x3f) as u8;
+        2
+    } else if ch < 0x10000 {
+        bytes[0] = 0xe0 | (ch >> 12) as u8;
+        bytes[1] = 0x80 | ((ch >> 6) & 0x3f) as u8;
+        bytes[2] = 0x80 | (ch & 0x3f) as u8;
+        3
+    } else {
+        bytes[0] = 0xf0 | (ch >> 18) as u8;
+        bytes[1] = 0x80 | ((ch >> 12) & 0x3f) as u8;
+        bytes[2] = 0x80 | ((ch >> 6) & 0x3f) as u8;
+        bytes[3] = 0x80 | (ch & 0x3f) as u8;
+        4
+    };
+    if *len + count <= buffer.len() {
+        copy_bytes(&mut buffer[*len..*len + count], &bytes);
+        *len += count;
+    }
+}
+
+// Decode one character of UTF-8 text, returning U+FFFD for malformed input.
+pub fn next_utf8_char(text: &[u8], pos: &mut usize) -> u32 {
+    let first = text[*pos] as u32;
+    *pos += 1;
+    let (extra, mut ch) = if first < 0x80 {
+        return first;
+    } else if first & 0xe0 == 0xc0 {
+        (1, first & 0x1f)
+    } else if first & 0xf0 == 0xe0 {
+        (2, first & 0x0f)
+    } else if first & 0xf8 == 0xf0 {
+        (3, first & 0x07)
+    } else {
+        return REPLACEMENT_CHAR;
+    };
+    for _ in 0..extra {
+        if *pos >= text.len() || text[*pos] & 0xc0 != 0x80 {
+            return REPLACEMENT_CHAR;
+        }
+        ch = (ch << 6) | (text[*pos] & 0x3f) as u32;
+        *pos += 1;
+    }
+    ch
+}
+
+// Decode one character of UTF-16, returning U+FFFD for an unpaired
+// surrogate.
+pub fn next_utf16_char(units: &[u16], pos: &mut usize) -> u32 {
+    let unit = units[*pos] as u32;
+    *pos += 1;
+    if unit >= 0xd800 && unit < 0xdc00 && *pos < units.len() &&
+            units[*pos] >= 0xdc00 && units[*pos] < 0xe000 {
+        let low = units[*pos] as u32;
+        *pos += 1;
+        0x10000 + ((unit - 0xd800) << 10) + (low - 0xdc00)
+    } else if unit >= 0xd800 && unit < 0xe000 {
+        REPLACEMENT_CHAR
+    } else {
+        unit
+    }
+}
+
+// Convert UTF-8 text to UTF-16.  Returns None if it does not fit in `out`.
+pub fn utf8_to_utf16(text: &[u8], out: &mut [u16]) -> Option<usize> {
+    let mut len = 0;
+    let mut pos = 0;
+    while pos < text.len() {
+        let ch = next_utf8_char(text, &mut pos);
+        let needed = if ch >= 0x10000 { 2 } else { 1 };
+        if len + needed > out.len() {
+            return None;
+        }
+        if ch >= 0x10000 {
+            out[len] = (0xd800 + ((ch - 0x10000) >> 10)) as u16;
+            out[len + 1] = (0xdc00 + ((ch - 0x10000) & 0x3ff)) as u16;
+        } else {
+            out[len] = ch as u16;
+        }
+        len += needed;
+    }
+    Some(len)
+}
+
+// Convert UTF-16 units to UTF-8, appending to the buffer.
+pub fn utf16_to_utf8(units: &[u16], buffer: &mut [u8], len: &mut usize) {
+    let mut pos = 0;
+    while pos < units.len() {
+        push_utf8(buffer, len, next_utf16_char(units, &mut pos));
+    }
+}
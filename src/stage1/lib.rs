@@ -16,8 +16,6 @@ mod std {
 
 #[macro_use] mod macros;
 
-mod crc32c;
-mod fat32;
 mod panic;
 
 const STAGE2_SIZE: usize = 0x73000;
@@ -28,6 +26,83 @@ extern {
     static _stage2_end: UnsafeCell<[u8; 0]>;
 }
 
+// When booted from a CD by the El Torito boot image, stage2 is a file in the
+// root directory of the ISO 9660 volume.  The full sys::iso9660 reader is too
+// large for stage1, so scan the root directory for the primary name alone.
+fn read_stage2_from_cd(disk: &sys::Disk, buffer: &mut [u8]) -> u32 {
+    const BLOCK_SIZE: usize = sys::iso9660::BLOCK_SIZE;
+    const ROOT_RECORD_OFFSET: usize = 156;
+
+    let read = |block: u32, buffer: &mut [u8]| {
+        if let Err(err) = sys::read_disk_sectors(disk, block, buffer) {
+            sys::print_str(err.msg());
+            panic!();
+        }
+    };
+
+    // The primary volume descriptor comes first, at block 16.
+    let mut block = [0u8; BLOCK_SIZE];
+    read(16, &mut block);
+    if block[0] != 1 || &block[1..6] != &b"CD001"[..] {
+        panic!("no ISO 9660 primary volume descriptor");
+    }
+    let root_extent = sys::get32(&block, ROOT_RECORD_OFFSET + 2);
+    let root_size = sys::get32(&block, ROOT_RECORD_OFFSET + 10) as usize;
+
+    let mut offset = 0;
+    let mut loaded = None;
+    while offset < root_size {
+        let index = (offset / BLOCK_SIZE) as u32;
+        if loaded != Some(index) {
+            read(root_extent + index, &mut block);
+            loaded = Some(index);
+        }
+        let pos = offset % BLOCK_SIZE;
+        let len = block[pos] as usize;
+        if len == 0 {
+            // Records do not cross blocks; the rest of this one is padding.
+            offset = (offset / BLOCK_SIZE + 1) * BLOCK_SIZE;
+            continue;
+        }
+        if len < 34 || pos + len > BLOCK_SIZE || 33 + block[pos + 32] as usize > len {
+            panic!("corrupt ISO 9660 directory");
+        }
+        let name = &block[pos + 33..pos + 33 + block[pos + 32] as usize];
+        let name = &name[..name.iter().position(|c| *c == b';').unwrap_or(name.len())];
+        if name == &b"STAGE2.BIN"[..] {
+            let extent = sys::get32(&block, pos + 2);
+            let size = sys::get32(&block, pos + 10);
+            let length = (size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+            if length > buffer.len() {
+                panic!("STAGE2.BIN is too large");
+            }
+            read(extent, &mut buffer[..length]);
+            return size;
+        }
+        offset += len;
+    }
+    panic!("STAGE2.BIN not found on CD");
+}
+
+fn read_stage2_from_fat(disk: &sys::Disk, volume_lba: u32, buffer: &mut [u8]) -> u32 {
+    let volume = match sys::fat32::open_volume(disk, volume_lba as sys::SectorIndex) {
+        Ok(volume) => volume,
+        Err(err) => {
+            sys::print_str(err.description());
+            panic!();
+        }
+    };
+    match sys::fat32::read_file_reusing_buffer_in_find(&volume, strlit!("STAGE2  BIN"),
+                                                      buffer) {
+        Ok(size) => size,
+        Err(err) => {
+            sys::print_str(strlit!("cannot read STAGE2.BIN from the pcboot volume: "));
+            sys::print_str(err.description());
+            panic!();
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn pcboot_main(disk_number: u8, volume_lba: u32) -> ! {
     sys::print_str(strlit!("pcboot loading...\r\n"));
@@ -40,14 +115,18 @@ pub extern "C" fn pcboot_main(disk_number: u8, volume_lba: u32) -> ! {
     assert!(linker_size == STAGE2_SIZE);
 
     let disk = sys::open_disk(disk_number).unwrap();
-    let volume = fat32::open_volume(&disk, volume_lba as sys::SectorIndex);
 
     unsafe {
         let stage2 = &mut *_stage2.get();
-        let file_size = fat32::read_file_reusing_buffer_in_find(&volume, strlit!("STAGE2  BIN"), stage2);
+        let file_size = if disk.sector_size() == sys::iso9660::BLOCK_SIZE {
+            read_stage2_from_cd(&disk, stage2)
+        } else {
+            read_stage2_from_fat(&disk, volume_lba, stage2)
+        };
         let checksum_offset = (file_size - 4) as usize;
         let expected_checksum = sys::get32(stage2, checksum_offset);
-        let actual_checksum = crc32c::compute(&crc32c::table(), &stage2[..checksum_offset]);
+        let actual_checksum =
+            sys::crc32::compute(&sys::crc32::table_castagnoli(), &stage2[..checksum_offset]);
 
         sys::print_str(strlit!("read "));
         sys::print_u32(file_size);
@@ -21,5 +21,5 @@ extern fn rust_panic_fmt(msg: fmt::Arguments, file: &'static str, line: u32) ->
     sys::print_u32(line);
     sys::print_str(&": ");
     let _ = fmt::write(&mut SimpleWriter, msg);
-    sys::halt();
+    sys::fatal_error_halt();
 }
@@ -0,0 +1,383 @@
+// AHCI (SATA) driver.
+//
+// Controllers in AHCI mode have no legacy IDE ports, and many BIOSes are slow
+// or buggy when emulating INT 13h on top of them.  This driver finds the HBA
+// through PCI and issues READ/WRITE DMA EXT commands through command slot 0 of
+// each port, polling for completion.
+//
+// The BIOS keeps using the HBA for its other disks, so the driver leaves the
+// HBA's ownership and each port's memory alone.  A port that the BIOS has
+// started keeps its command list and received-FIS area, and the driver only
+// fills in slot 0, as the BIOS does for each of its own commands.  A port that
+// the BIOS left stopped gets memory of its own.
+//
+// Each command transfers directly into the caller's buffer using a single
+// PRD entry, which must be 2-byte aligned.  Other buffers are bounced through
+// a per-port DMA buffer.
+
+use ata;
+use dma;
+use sys;
+use sys::io;
+use sys::pci;
+use sys::{copy_bytes, ErrorStr, SectorIndex, SECTOR_SIZE};
+
+pub const MAX_DISKS: usize = 4;
+
+// HBA (generic host control) registers.
+const HBA_CAP: u32      = 0x00;
+const HBA_GHC: u32      = 0x04;
+const HBA_PI: u32       = 0x0c;
+
+const GHC_AE: u32       = 1 << 31;      // AHCI enable
+
+// Port registers, relative to 0x100 + port * 0x80.
+const PORT_CLB: u32     = 0x00;
+const PORT_CLBU: u32    = 0x04;
+const PORT_FB: u32      = 0x08;
+const PORT_FBU: u32     = 0x0c;
+const PORT_IS: u32      = 0x10;
+const PORT_IE: u32      = 0x14;
+const PORT_CMD: u32     = 0x18;
+const PORT_TFD: u32     = 0x20;
+const PORT_SIG: u32     = 0x24;
+const PORT_SSTS: u32    = 0x28;
+const PORT_SERR: u32    = 0x30;
+const PORT_CI: u32      = 0x38;
+
+const CMD_ST: u32       = 1 << 0;       // start processing the command list
+const CMD_FRE: u32      = 1 << 4;       // FIS receive enable
+const CMD_FR: u32       = 1 << 14;      // FIS receive running
+const CMD_CR: u32       = 1 << 15;      // command list running
+
+const IS_TFES: u32      = 1 << 30;      // task file error
+const TFD_ERR: u32      = 0x01;
+const TFD_DRQ: u32      = 0x08;
+const TFD_BSY: u32      = 0x80;
+
+const SSTS_DET_PRESENT: u32 = 3;        // device present, PHY established
+const SIG_ATA: u32      = 0x0000_0101;
+
+const FIS_TYPE_REG_H2D: u8 = 0x27;
+const FIS_REG_H2D_DWORDS: u16 = 5;
+
+const ATA_READ_DMA_EXT: u8      = 0x25;
+const ATA_WRITE_DMA_EXT: u8     = 0x35;
+const ATA_FLUSH_CACHE_EXT: u8   = 0xea;
+const ATA_IDENTIFY: u8          = 0xec;
+
+const COMMAND_LIST_SIZE: usize  = 1024;     // 32 command headers
+const RECEIVED_FIS_SIZE: usize  = 256;
+const COMMAND_TABLE_SIZE: usize = 0x80 + 16; // one PRD entry
+const BOUNCE_SIZE: usize        = 8 * SECTOR_SIZE;
+
+const MAX_SECTORS_PER_COMMAND: u32 = 128;
+const POLL_LIMIT: u32 = 10_000_000;
+
+#[repr(C)]
+struct CommandHeader {
+    flags: u16,             // CFL in bits 0-4, W (write) in bit 6
+    prdt_length: u16,
+    prd_byte_count: u32,
+    table_base: u32,
+    table_base_upper: u32,
+    _reserved: [u32; 4],
+}
+
+#[repr(C)]
+struct CommandTable {
+    command_fis: [u8; 64],
+    _atapi_command: [u8; 16],
+    _reserved: [u8; 48],
+    prd_base: u32,
+    prd_base_upper: u32,
+    _prd_reserved: u32,
+    prd_byte_count: u32,    // byte count - 1, interrupt flag in bit 31
+}
+
+#[derive(Copy, Clone)]
+pub struct AhciDisk {
+    pub pci: pci::Address,
+    pub port: u8,
+    port_base: u32,
+    command_list: u32,
+    command_table: u32,
+    bounce: u32,
+    pub identity: ata::Identity,
+}
+
+fn wait_until<F: FnMut() -> bool>(mut done: F) -> bool {
+    for _ in 0..POLL_LIMIT {
+        if done() {
+            return true;
+        }
+    }
+    false
+}
+
+impl AhciDisk {
+    fn read_reg(&self, offset: u32) -> u32 {
+        io::mmio_read32(self.port_base + offset)
+    }
+
+    fn write_reg(&self, offset: u32, value: u32) {
+        io::mmio_write32(self.port_base + offset, value)
+    }
+
+    // Stop the port's command engine so that its memory can be changed.
+    fn stop(&self) -> bool {
+        self.write_reg(PORT_CMD, self.read_reg(PORT_CMD) & !CMD_ST);
+        if !wait_until(|| self.read_reg(PORT_CMD) & CMD_CR == 0) {
+            return false;
+        }
+        self.write_reg(PORT_CMD, self.read_reg(PORT_CMD) & !CMD_FRE);
+        wait_until(|| self.read_reg(PORT_CMD) & CMD_FR == 0)
+    }
+
+    fn start(&self) -> bool {
+        self.write_reg(PORT_CMD, self.read_reg(PORT_CMD) | CMD_FRE);
+        if !wait_until(|| self.read_reg(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+            return false;
+        }
+        self.write_reg(PORT_CMD, self.read_reg(PORT_CMD) | CMD_ST);
+        true
+    }
+
+    // Issue an ATA command in slot 0 and wait for it to complete.  `buffer`
+    // is the physical address and length of the data, if any, and must be
+    // 2-byte aligned.
+    fn command(&self, ata_command: u8, lba: u64, count: u16,
+               buffer: u32, length: usize, write: bool) -> Result<(), ErrorStr> {
+        let header = unsafe { &mut *(self.command_list as *mut CommandHeader) };
+        let table = unsafe { &mut *(self.command_table as *mut CommandTable) };
+
+        header.flags = FIS_REG_H2D_DWORDS | if write { 1 << 6 } else { 0 };
+        header.prdt_length = if length > 0 { 1 } else { 0 };
+        header.prd_byte_count = 0;
+        header.table_base = self.command_table;
+        header.table_base_upper = 0;
+
+        let fis = &mut table.command_fis;
+        for b in fis.iter_mut() {
+            *b = 0;
+        }
+        fis[0] = FIS_TYPE_REG_H2D;
+        fis[1] = 0x80;              // C: this FIS carries a command
+        fis[2] = ata_command;
+        fis[4] = lba as u8;
+        fis[5] = (lba >> 8) as u8;
+        fis[6] = (lba >> 16) as u8;
+        fis[7] = 0x40;              // LBA mode
+        fis[8] = (lba >> 24) as u8;
+        fis[9] = (lba >> 32) as u8;
+        fis[10] = (lba >> 40) as u8;
+        fis[12] = count as u8;
+        fis[13] = (count >> 8) as u8;
+
+        table.prd_base = buffer;
+        table.prd_base_upper = 0;
+        table.prd_byte_count = if length > 0 { length as u32 - 1 } else { 0 };
+
+        if !wait_until(|| self.read_reg(PORT_TFD) & (TFD_BSY | TFD_DRQ) == 0) {
+            return Err(ErrorStr::new(strlit!("AHCI port is busy")));
+        }
+        self.write_reg(PORT_IS, 0xffff_ffff);
+        self.write_reg(PORT_CI, 1);
+
+        let mut failed = false;
+        let finished = wait_until(|| {
+            if self.read_reg(PORT_IS) & IS_TFES != 0 {
+                failed = true;
+            }
+            failed || self.read_reg(PORT_CI) & 1 == 0
+        });
+        if !finished {
+            return Err(ErrorStr::new(strlit!("AHCI command timed out")));
+        }
+        if failed || self.read_reg(PORT_TFD) & TFD_ERR != 0 {
+            log_debug!("AHCI port {}: TFD=0x{:x} IS=0x{:x}", self.port,
+                       self.read_reg(PORT_TFD), self.read_reg(PORT_IS));
+            return Err(ErrorStr::new(strlit!("AHCI command failed")));
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, start_sector: SectorIndex, buffer: u32, length: usize,
+                write: bool) -> Result<(), ErrorStr> {
+        assert!(length % SECTOR_SIZE == 0);
+        let chunk_size = MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE;
+        let mut done = 0;
+        while done < length {
+            let size = if length - done < chunk_size { length - done } else { chunk_size };
+            let lba = start_sector as u64 + (done / SECTOR_SIZE) as u64;
+            try!(self.command(if write { ATA_WRITE_DMA_EXT } else { ATA_READ_DMA_EXT },
+                              lba, (size / SECTOR_SIZE) as u16,
+                              buffer + done as u32, size, write));
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+fn chunk_sector(start_sector: SectorIndex, index: usize) -> SectorIndex {
+    start_sector + (index * (BOUNCE_SIZE / SECTOR_SIZE)) as SectorIndex
+}
+
+impl sys::BlockDevice for AhciDisk {
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        if buffer.as_ptr() as usize % 2 == 0 {
+            return self.transfer(start_sector, buffer.as_mut_ptr() as u32, buffer.len(),
+                                 false);
+        }
+        let bounce = dma::as_slice(self.bounce, BOUNCE_SIZE);
+        for (index, chunk) in buffer.chunks_mut(BOUNCE_SIZE).enumerate() {
+            try!(self.transfer(chunk_sector(start_sector, index), self.bounce, chunk.len(),
+                               false));
+            copy_bytes(chunk, bounce);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        if buffer.as_ptr() as usize % 2 == 0 {
+            try!(self.transfer(start_sector, buffer.as_ptr() as u32, buffer.len(), true));
+        } else {
+            let bounce = dma::as_slice(self.bounce, BOUNCE_SIZE);
+            for (index, chunk) in buffer.chunks(BOUNCE_SIZE).enumerate() {
+                copy_bytes(bounce, chunk);
+                try!(self.transfer(chunk_sector(start_sector, index), self.bounce,
+                                   chunk.len(), true));
+            }
+        }
+        self.command(ATA_FLUSH_CACHE_EXT, 0, 0, 0, 0, false)
+    }
+}
+
+fn init_port(pci_addr: pci::Address, abar: u32, port: u8) -> Option<AhciDisk> {
+    let port_base = abar + 0x100 + port as u32 * 0x80;
+    if io::mmio_read32(port_base + PORT_SSTS) & 0xf != SSTS_DET_PRESENT {
+        return None;
+    }
+    if io::mmio_read32(port_base + PORT_SIG) != SIG_ATA {
+        // ATAPI, port multiplier, etc.
+        return None;
+    }
+
+    let mut disk = AhciDisk {
+        pci: pci_addr,
+        port: port,
+        port_base: port_base,
+        command_list: 0,
+        command_table: 0,
+        bounce: 0,
+        identity: ata::Identity::unknown(),
+    };
+    let running = CMD_ST | CMD_FRE;
+    let bios_started = disk.read_reg(PORT_CMD) & running == running &&
+        disk.read_reg(PORT_CLBU) == 0 && disk.read_reg(PORT_FBU) == 0;
+    if bios_started {
+        disk.command_list = disk.read_reg(PORT_CLB);
+    } else {
+        if !disk.stop() {
+            log_warn!("AHCI port {}: cannot stop command engine", port);
+            return None;
+        }
+        disk.command_list = dma::alloc(COMMAND_LIST_SIZE, 1024);
+        let received_fis = dma::alloc(RECEIVED_FIS_SIZE, 256);
+        disk.write_reg(PORT_CLB, disk.command_list);
+        disk.write_reg(PORT_CLBU, 0);
+        disk.write_reg(PORT_FB, received_fis);
+        disk.write_reg(PORT_FBU, 0);
+        disk.write_reg(PORT_IE, 0);
+        disk.write_reg(PORT_SERR, 0xffff_ffff);
+        disk.write_reg(PORT_IS, 0xffff_ffff);
+        if !disk.start() {
+            log_warn!("AHCI port {}: device is busy", port);
+            return None;
+        }
+    }
+    disk.command_table = dma::alloc(COMMAND_TABLE_SIZE, 128);
+    disk.bounce = dma::alloc(BOUNCE_SIZE, 2);
+
+    // IDENTIFY data goes into the bounce buffer, which is otherwise unused
+    // until the first unaligned transfer.
+    if let Err(err) = disk.command(ATA_IDENTIFY, 0, 0, disk.bounce, SECTOR_SIZE, false) {
+        log_warn!("AHCI port {}: IDENTIFY failed: {}", port, err);
+        return None;
+    }
+    let data = unsafe { &*(disk.bounce as *const [u8; 512]) };
+    disk.identity = ata::parse_identify(data);
+    if !disk.identity.lba48 {
+        log_warn!("AHCI port {}: disk does not support LBA48", port);
+        return None;
+    }
+    Some(disk)
+}
+
+fn find_controller() -> Option<pci::Address> {
+    let mut iter = pci::devices();
+    while let Some(addr) = iter.next() {
+        // Mass storage, SATA, AHCI 1.0 programming interface.
+        if pci::class_code(addr) == (0x01, 0x06, 0x01) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+static mut DISKS: Option<[Option<AhciDisk>; MAX_DISKS]> = None;
+
+// Find the first AHCI controller and initialize the SATA disks attached to it.
+// The ports are set up on the first call, and later calls return the same
+// disks.
+pub fn detect_disks() -> [Option<AhciDisk>; MAX_DISKS] {
+    unsafe {
+        if let Some(disks) = DISKS {
+            return disks;
+        }
+        let disks = init_controller();
+        DISKS = Some(disks);
+        disks
+    }
+}
+
+fn init_controller() -> [Option<AhciDisk>; MAX_DISKS] {
+    let mut disks = [None; MAX_DISKS];
+
+    let pci_addr = match find_controller() {
+        None => return disks,
+        Some(addr) => addr,
+    };
+    let abar = match pci::memory_bar(pci_addr, 5) {
+        None => {
+            log_warn!("AHCI controller has no ABAR");
+            return disks;
+        },
+        Some(abar) => abar,
+    };
+    pci::enable_device(pci_addr);
+    let ghc = io::mmio_read32(abar + HBA_GHC);
+    if ghc & GHC_AE == 0 {
+        io::mmio_write32(abar + HBA_GHC, ghc | GHC_AE);
+    }
+
+    let port_count = (io::mmio_read32(abar + HBA_CAP) & 0x1f) + 1;
+    let implemented = io::mmio_read32(abar + HBA_PI);
+    let mut found = 0;
+    for port in 0..port_count {
+        if found == MAX_DISKS {
+            break;
+        }
+        if implemented & (1 << port) == 0 {
+            continue;
+        }
+        if let Some(disk) = init_port(pci_addr, abar, port as u8) {
+            log_info!("AHCI port {}: {} sectors", port, disk.identity.sector_count);
+            disks[found] = Some(disk);
+            found += 1;
+        }
+    }
+    disks
+}
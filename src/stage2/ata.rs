@@ -0,0 +1,297 @@
+// Protected-mode ATA/IDE driver using PIO transfers.
+//
+// This driver talks to the legacy IDE ports directly, rather than through
+// BIOS INT 13h, so it avoids a pair of mode switches per transfer and any
+// BIOS quirks.  It only supports ATA hard disks (not ATAPI), on the legacy
+// channels or on a PCI IDE controller's native-mode channels, with interrupts
+// disabled; every command is completed by polling the status register.
+//
+// LBA28 commands are used when the whole transfer fits below 2^28 sectors,
+// and LBA48 commands otherwise (if the drive supports them).
+
+use sys;
+use sys::io;
+use sys::pci;
+use sys::{ErrorStr, SectorIndex, SECTOR_SIZE};
+
+// Command-block register offsets.
+const REG_DATA: u16         = 0;
+const REG_ERROR: u16        = 1;
+const REG_SECTOR_COUNT: u16 = 2;
+const REG_LBA_LOW: u16      = 3;
+const REG_LBA_MID: u16      = 4;
+const REG_LBA_HIGH: u16     = 5;
+const REG_DRIVE: u16        = 6;
+const REG_STATUS: u16       = 7;
+const REG_COMMAND: u16      = 7;
+
+const STATUS_ERR: u8    = 0x01;
+const STATUS_DRQ: u8    = 0x08;
+const STATUS_DF: u8     = 0x20;
+const STATUS_BSY: u8    = 0x80;
+
+const CONTROL_NIEN: u8  = 0x02;     // Disable interrupts.
+
+const CMD_READ_SECTORS: u8      = 0x20;
+const CMD_READ_SECTORS_EXT: u8  = 0x24;
+const CMD_WRITE_SECTORS: u8     = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_FLUSH_CACHE: u8       = 0xe7;
+const CMD_FLUSH_CACHE_EXT: u8   = 0xea;
+const CMD_IDENTIFY: u8          = 0xec;
+
+const LBA28_LIMIT: u32 = 1 << 28;
+const MAX_SECTORS_PER_COMMAND: u32 = 256;
+const POLL_LIMIT: u32 = 10_000_000;
+
+// (command block base, control block base) for the legacy channels.
+pub const LEGACY_CHANNELS: [(u16, u16); 2] = [(0x1f0, 0x3f6), (0x170, 0x376)];
+
+// The parts of the IDENTIFY DEVICE data we use.  The AHCI driver issues the
+// same command and shares this parser.
+#[derive(Copy, Clone)]
+pub struct Identity {
+    pub lba48: bool,
+    pub sector_count: u64,
+    model: [u8; 40],
+}
+
+impl Identity {
+    pub fn unknown() -> Identity {
+        Identity { lba48: false, sector_count: 0, model: [b' '; 40] }
+    }
+
+    pub fn model(&self) -> &[u8] {
+        let mut end = self.model.len();
+        while end > 0 && self.model[end - 1] == b' ' {
+            end -= 1;
+        }
+        &self.model[..end]
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct AtaDisk {
+    pub channel: u8,
+    pub slave: bool,
+    io_base: u16,
+    control_base: u16,
+    pub identity: Identity,
+}
+
+impl AtaDisk {
+
+    fn status(&self) -> u8 {
+        io::inb(self.io_base + REG_STATUS)
+    }
+
+    // Reading the alternate status register takes ~100ns.  The spec requires
+    // a 400ns delay after selecting a drive before its status is valid.
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            io::inb(self.control_base);
+        }
+    }
+
+    fn select(&self, head_bits: u8) {
+        io::outb(self.io_base + REG_DRIVE,
+                 head_bits | if self.slave { 0x10 } else { 0 });
+        self.delay_400ns();
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, ErrorStr> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+        }
+        Err(ErrorStr::new(strlit!("ATA drive timed out")))
+    }
+
+    // Wait for the drive to be ready to transfer a sector of data.
+    fn wait_for_data(&self) -> Result<(), ErrorStr> {
+        let status = try!(self.wait_not_busy());
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            log_debug!("ATA error register: 0x{:x}", io::inb(self.io_base + REG_ERROR));
+            return Err(ErrorStr::new(strlit!("ATA command failed")));
+        }
+        if status & STATUS_DRQ == 0 {
+            return Err(ErrorStr::new(strlit!("ATA drive did not request data")));
+        }
+        Ok(())
+    }
+
+    fn issue_command(&self, lba: SectorIndex, count: u32, cmd28: u8, cmd48: u8) ->
+            Result<(), ErrorStr> {
+        assert!(count >= 1 && count <= MAX_SECTORS_PER_COMMAND);
+        try!(self.wait_not_busy());
+        let last = lba as u64 + count as u64;
+        if last <= LBA28_LIMIT as u64 {
+            self.select(0xe0 | ((lba >> 24) & 0x0f) as u8);
+            io::outb(self.io_base + REG_SECTOR_COUNT, count as u8);
+            io::outb(self.io_base + REG_LBA_LOW, lba as u8);
+            io::outb(self.io_base + REG_LBA_MID, (lba >> 8) as u8);
+            io::outb(self.io_base + REG_LBA_HIGH, (lba >> 16) as u8);
+            io::outb(self.io_base + REG_COMMAND, cmd28);
+        } else if self.identity.lba48 {
+            // Write the high-order bytes first; each register is a two-deep
+            // FIFO.
+            self.select(0x40);
+            io::outb(self.io_base + REG_SECTOR_COUNT, (count >> 8) as u8);
+            io::outb(self.io_base + REG_LBA_LOW, (lba >> 24) as u8);
+            io::outb(self.io_base + REG_LBA_MID, 0);
+            io::outb(self.io_base + REG_LBA_HIGH, 0);
+            io::outb(self.io_base + REG_SECTOR_COUNT, count as u8);
+            io::outb(self.io_base + REG_LBA_LOW, lba as u8);
+            io::outb(self.io_base + REG_LBA_MID, (lba >> 8) as u8);
+            io::outb(self.io_base + REG_LBA_HIGH, (lba >> 16) as u8);
+            io::outb(self.io_base + REG_COMMAND, cmd48);
+        } else {
+            return Err(ErrorStr::new(strlit!("sector is beyond the LBA28 limit")));
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), ErrorStr> {
+        try!(self.wait_not_busy());
+        self.select(0xe0);
+        io::outb(self.io_base + REG_COMMAND,
+                 if self.identity.lba48 { CMD_FLUSH_CACHE_EXT } else { CMD_FLUSH_CACHE });
+        let status = try!(self.wait_not_busy());
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(ErrorStr::new(strlit!("ATA cache flush failed")));
+        }
+        Ok(())
+    }
+}
+
+impl sys::BlockDevice for AtaDisk {
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        assert!(buffer.len() % SECTOR_SIZE == 0);
+        let mut lba = start_sector;
+        for chunk in buffer.chunks_mut(MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u32;
+            try!(self.issue_command(lba, count, CMD_READ_SECTORS, CMD_READ_SECTORS_EXT));
+            for sector in chunk.chunks_mut(SECTOR_SIZE) {
+                try!(self.wait_for_data());
+                io::insw(self.io_base + REG_DATA, sector);
+            }
+            lba += count;
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        assert!(buffer.len() % SECTOR_SIZE == 0);
+        let mut lba = start_sector;
+        for chunk in buffer.chunks(MAX_SECTORS_PER_COMMAND as usize * SECTOR_SIZE) {
+            let count = (chunk.len() / SECTOR_SIZE) as u32;
+            try!(self.issue_command(lba, count, CMD_WRITE_SECTORS, CMD_WRITE_SECTORS_EXT));
+            for sector in chunk.chunks(SECTOR_SIZE) {
+                try!(self.wait_for_data());
+                io::outsw(self.io_base + REG_DATA, sector);
+            }
+            lba += count;
+        }
+        self.flush()
+    }
+}
+
+fn identify_word(data: &[u8; 512], index: usize) -> u16 {
+    (data[index * 2] as u16) | ((data[index * 2 + 1] as u16) << 8)
+}
+
+pub fn parse_identify(data: &[u8; 512]) -> Identity {
+    let lba48 = identify_word(data, 83) & (1 << 10) != 0;
+    let sector_count = if lba48 {
+        (identify_word(data, 100) as u64) |
+            ((identify_word(data, 101) as u64) << 16) |
+            ((identify_word(data, 102) as u64) << 32) |
+            ((identify_word(data, 103) as u64) << 48)
+    } else {
+        (identify_word(data, 60) as u64) |
+            ((identify_word(data, 61) as u64) << 16)
+    };
+
+    // The model string occupies words 27-46, with the bytes in each word
+    // swapped.
+    let mut model = [0u8; 40];
+    for i in 0..20 {
+        let word = identify_word(data, 27 + i);
+        model[i * 2] = (word >> 8) as u8;
+        model[i * 2 + 1] = word as u8;
+    }
+
+    Identity { lba48: lba48, sector_count: sector_count, model: model }
+}
+
+// Return the (command block base, control block base) ports of a channel of
+// the PCI IDE controller at `addr`, or None if it is not an IDE controller.
+// A channel in native mode (programming interface bit 0 or 2) is decoded at
+// the controller's I/O BARs rather than at the legacy ports; the control
+// block register is at offset 2 of its BAR.
+pub fn pci_channel_ports(addr: pci::Address, channel: u8) -> Option<(u16, u16)> {
+    let (class, subclass, prog_if) = pci::class_code(addr);
+    if class != 0x01 || subclass != 0x01 || channel > 1 {
+        return None;
+    }
+    if prog_if & (1 << (channel * 2)) == 0 {
+        return Some(LEGACY_CHANNELS[channel as usize]);
+    }
+    match (pci::io_bar(addr, channel * 2), pci::io_bar(addr, channel * 2 + 1)) {
+        (Some(io_base), Some(control_bar)) => Some((io_base, control_bar + 2)),
+        _ => None,
+    }
+}
+
+// Probe for an ATA disk on the channel with the given ports.  Returns None if
+// there is no drive, or if the drive is not an ATA disk (e.g. ATAPI or a SATA
+// bridge in a non-ATA mode).
+pub fn detect(channel: u8, ports: (u16, u16), slave: bool) -> Option<AtaDisk> {
+    let (io_base, control_base) = ports;
+    let mut disk = AtaDisk {
+        channel: channel,
+        slave: slave,
+        io_base: io_base,
+        control_base: control_base,
+        identity: Identity::unknown(),
+    };
+
+    // A floating bus reads as 0xff.
+    if disk.status() == 0xff {
+        return None;
+    }
+
+    io::outb(control_base, CONTROL_NIEN);
+    disk.select(0xa0);
+    io::outb(io_base + REG_SECTOR_COUNT, 0);
+    io::outb(io_base + REG_LBA_LOW, 0);
+    io::outb(io_base + REG_LBA_MID, 0);
+    io::outb(io_base + REG_LBA_HIGH, 0);
+    io::outb(io_base + REG_COMMAND, CMD_IDENTIFY);
+    if disk.status() == 0 {
+        return None;
+    }
+    if disk.wait_not_busy().is_err() {
+        return None;
+    }
+
+    // ATAPI and SATA devices set a signature in the LBA registers and abort
+    // IDENTIFY DEVICE.
+    if io::inb(io_base + REG_LBA_MID) != 0 || io::inb(io_base + REG_LBA_HIGH) != 0 {
+        return None;
+    }
+    if disk.wait_for_data().is_err() {
+        return None;
+    }
+
+    let mut data = [0u8; 512];
+    io::insw(io_base + REG_DATA, &mut data);
+
+    disk.identity = parse_identify(&data);
+
+    Some(disk)
+}
@@ -0,0 +1,185 @@
+// Selection of the block device used to access the boot disk.
+//
+// By default, disks are read through BIOS INT 13h.  When a native driver is
+// enabled, the EDD device path of the BIOS disk identifies the controller and
+// the device behind it, and the matching native driver is used instead.
+// Disk access then avoids a pair of mode switches per transfer.
+
+use ahci;
+use ata;
+use nvme;
+use sys;
+use sys::edd::{DevicePath, HostBus, Interface};
+use sys::pci;
+use sys::{ErrorStr, SectorIndex, SECTOR_SIZE};
+use virtio_blk;
+
+pub enum Backend {
+    Bios(sys::Disk),
+    Ata(ata::AtaDisk),
+    Ahci(ahci::AhciDisk),
+    Virtio(virtio_blk::VirtioBlk),
+    Nvme(nvme::NvmeDisk),
+}
+
+impl Backend {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Backend::Bios(_) => "BIOS",
+            Backend::Ata(_) => "ATA",
+            Backend::Ahci(_) => "AHCI",
+            Backend::Virtio(_) => "virtio-blk",
+            Backend::Nvme(_) => "NVMe",
+        }
+    }
+
+    fn device(&self) -> &sys::BlockDevice {
+        match *self {
+            Backend::Bios(ref disk) => disk,
+            Backend::Ata(ref disk) => disk,
+            Backend::Ahci(ref disk) => disk,
+            Backend::Virtio(ref disk) => disk,
+            Backend::Nvme(ref disk) => disk,
+        }
+    }
+}
+
+impl sys::BlockDevice for Backend {
+    fn sector_size(&self) -> usize {
+        self.device().sector_size()
+    }
+
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        self.device().read_sectors(start_sector, buffer)
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        self.device().write_sectors(start_sector, buffer)
+    }
+}
+
+fn open_ata(path: &DevicePath, slave: bool) -> Option<Backend> {
+    let (channel, ports) = match path.host_bus {
+        HostBus::Pci { bus, device, function, channel } => {
+            let addr = pci::Address { bus: bus, device: device, function: function };
+            match ata::pci_channel_ports(addr, channel) {
+                None => return None,
+                Some(ports) => (channel, ports),
+            }
+        },
+        HostBus::Isa { base_address: 0x1f0 } => (0, ata::LEGACY_CHANNELS[0]),
+        HostBus::Isa { base_address: 0x170 } => (1, ata::LEGACY_CHANNELS[1]),
+        _ => return None,
+    };
+    ata::detect(channel, ports, slave).map(Backend::Ata)
+}
+
+fn open_ahci(path: &DevicePath, port: u8) -> Option<Backend> {
+    let pci_addr = match path.host_bus {
+        HostBus::Pci { bus, device, function, .. } =>
+            pci::Address { bus: bus, device: device, function: function },
+        _ => return None,
+    };
+    for disk in ahci::detect_disks().iter() {
+        if let Some(disk) = *disk {
+            if disk.pci == pci_addr && disk.port == port {
+                return Some(Backend::Ahci(disk));
+            }
+        }
+    }
+    None
+}
+
+fn open_virtio(path: &DevicePath) -> Option<Backend> {
+    match path.host_bus {
+        HostBus::Pci { bus, device, function, .. } => {
+            let addr = pci::Address { bus: bus, device: device, function: function };
+            match virtio_blk::open(addr) {
+                Ok(disk) => Some(Backend::Virtio(disk)),
+                Err(err) => {
+                    log_warn!("{}", err);
+                    None
+                }
+            }
+        },
+        _ => None,
+    }
+}
+
+fn open_nvme(addr: pci::Address) -> Option<Backend> {
+    match nvme::open(addr) {
+        Ok(disk) => Some(Backend::Nvme(disk)),
+        Err(err) => {
+            log_warn!("{}", err);
+            None
+        }
+    }
+}
+
+// Find a native driver for the device at the given EDD device path.
+pub fn open_native(path: &DevicePath) -> Option<Backend> {
+    // EDD has no interface type for NVMe, so recognize the controller by its
+    // PCI class.  We assume the BIOS disk is the first usable namespace.
+    if let HostBus::Pci { bus, device, function, .. } = path.host_bus {
+        let addr = pci::Address { bus: bus, device: device, function: function };
+        if nvme::is_nvme(addr) {
+            return open_nvme(addr);
+        }
+    }
+    match path.interface {
+        Interface::Ata { slave } => open_ata(path, slave),
+        Interface::Sata { port } => open_ahci(path, port),
+        Interface::Virtio => open_virtio(path),
+        _ => None,
+    }
+}
+
+// Open the boot disk, preferring a native driver if `native` is set and one
+// matches the disk's EDD device path.  Falls back to the BIOS.
+pub fn open(bios_disk_number: u8, path: Option<DevicePath>, native: bool) ->
+        Result<Backend, ErrorStr> {
+    if native {
+        if let Some(ref path) = path {
+            if let Some(backend) = open_native(path) {
+                return Ok(backend);
+            }
+            log_info!("no native driver for {}; using the BIOS", path);
+        }
+    }
+    sys::open_disk(bios_disk_number).map(Backend::Bios)
+}
+
+const COMPARE_SECTORS: usize = 16;
+
+static mut COMPARE_BUFFERS: [[u8; COMPARE_SECTORS * SECTOR_SIZE]; 2] =
+    [[0u8; COMPARE_SECTORS * SECTOR_SIZE]; 2];
+
+// Sectors read through BIOS INT 13h before a native driver takes the disk
+// over.  Once it has, the BIOS must not access the disk again: resetting a
+// virtio-blk or NVMe controller, for example, leaves the BIOS's own driver
+// polling a queue that no longer exists.
+pub struct BiosReference {
+    start_sector: SectorIndex,
+}
+
+pub fn read_bios_reference(bios: &sys::Disk, start_sector: SectorIndex) ->
+        Result<BiosReference, ErrorStr> {
+    use sys::BlockDevice;
+    try!(bios.read_sectors(start_sector, unsafe { &mut COMPARE_BUFFERS[1] }));
+    Ok(BiosReference { start_sector: start_sector })
+}
+
+// Read the reference sectors again through a native driver, and check that
+// they match.  Used to validate the drivers in QEMU.
+pub fn compare_with_bios(native: &Backend, reference: &BiosReference) ->
+        Result<bool, ErrorStr> {
+    use sys::BlockDevice;
+    let (native_buf, bios_buf) = unsafe {
+        let (first, second) = COMPARE_BUFFERS.split_at_mut(1);
+        (&mut first[0], &second[0])
+    };
+    try!(native.read_sectors(reference.start_sector, native_buf));
+    Ok(native_buf[..] == bios_buf[..])
+}
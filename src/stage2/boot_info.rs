@@ -0,0 +1,42 @@
+// The boot-info structure handed to a booted kernel.
+//
+// The structure lives in stage2's memory, below 1MiB.  A kernel receives its
+// address and should copy anything it needs before reusing low memory.  The
+// `size` field allows new fields to be appended without breaking kernels that
+// were built against an older layout.
+
+use core;
+
+use sys;
+
+pub const BOOT_INFO_MAGIC: u32 = 0x7462_6370;     // "pcbt"
+
+#[repr(C)]
+pub struct BootInfo {
+    pub magic: u32,
+    pub size: u32,
+    pub boot_disk: u32,             // BIOS disk number
+    pub boot_volume_lba: u32,       // LBA of the pcboot FAT32 volume
+    pub smbios_entry: u32,          // SMBIOS entry point address, or 0
+    pub log_buffer: u32,            // address of pcboot's log text
+    pub log_buffer_size: u32,
+}
+
+pub fn new(boot_disk: u8, boot_volume_lba: u32) -> BootInfo {
+    BootInfo {
+        magic: BOOT_INFO_MAGIC,
+        size: core::mem::size_of::<BootInfo>() as u32,
+        boot_disk: boot_disk as u32,
+        boot_volume_lba: boot_volume_lba,
+        smbios_entry: 0,
+        log_buffer: 0,
+        log_buffer_size: 0,
+    }
+}
+
+// Fill in the fields that must be computed immediately before booting.
+pub fn finish(info: &mut BootInfo) {
+    let log = sys::log::linearize_ring_buffer();
+    info.log_buffer = log.as_ptr() as u32;
+    info.log_buffer_size = log.len() as u32;
+}
@@ -0,0 +1,31 @@
+// Formatted output to the BIOS console.
+
+use core::fmt;
+
+use sys;
+
+pub struct Writer;
+
+impl fmt::Write for Writer {
+    #[inline]
+    fn write_str(&mut self, x: &str) -> fmt::Result {
+        sys::print_str(strref!(x)); Ok(())
+    }
+}
+
+#[inline(never)]
+pub fn print_fmt(args: fmt::Arguments) {
+    let _ = fmt::write(&mut Writer, args);
+}
+
+// Print a byte string from firmware or disk, replacing non-printable
+// characters.
+pub fn print_bytes(text: &[u8]) {
+    for ch in text.iter() {
+        if *ch >= 0x20 && *ch < 0x7f {
+            sys::print_char(*ch);
+        } else {
+            sys::print_char(b'?');
+        }
+    }
+}
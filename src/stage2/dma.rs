@@ -0,0 +1,44 @@
+// Allocation of memory that devices access directly.
+//
+// stage2 runs without paging, so a linear address is also the physical
+// address a device sees.  Allocations come from an area reserved by the
+// linker script and are never freed; drivers allocate their queues and
+// command structures once, when they are initialized.
+
+use core::cell::UnsafeCell;
+use core::slice;
+
+#[allow(improper_ctypes)]
+extern {
+    static _dma: UnsafeCell<[u8; 0]>;
+    static _dma_end: UnsafeCell<[u8; 0]>;
+}
+
+static mut NEXT: u32 = 0;
+
+// Allocate zeroed memory with the given power-of-two alignment, and return
+// its physical address.  Panics if the area is exhausted.
+pub fn alloc(size: usize, align: usize) -> u32 {
+    assert!(align.is_power_of_two());
+    unsafe {
+        let start = _dma.get() as u32;
+        let end = _dma_end.get() as u32;
+        if NEXT == 0 {
+            NEXT = start;
+        }
+        let addr = (NEXT + align as u32 - 1) & !(align as u32 - 1);
+        if addr > end || end - addr < size as u32 {
+            panic!("out of DMA memory");
+        }
+        NEXT = addr + size as u32;
+        for i in 0..size {
+            *((addr as usize + i) as *mut u8) = 0;
+        }
+        addr
+    }
+}
+
+// View allocated memory as a byte slice.
+pub fn as_slice(addr: u32, size: usize) -> &'static mut [u8] {
+    unsafe { slice::from_raw_parts_mut(addr as *mut u8, size) }
+}
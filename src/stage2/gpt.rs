@@ -0,0 +1,284 @@
+// GUID Partition Table (GPT) parsing.
+//
+// A GPT disk has a protective MBR, a primary header at LBA 1 that usually
+// points to an entry array starting at LBA 2, and a backup header in the last
+// sector of the disk with its own copy of the entry array.  Both the header
+// and the entry array are protected by IEEE CRC-32s.  If the primary copy is
+// damaged, we use the backup.
+//
+// Partitions are numbered by their 1-based slot in the entry array, as Linux
+// does, so unused slots leave gaps.
+
+use core::char;
+use core::fmt;
+
+use sys;
+use sys::crc32;
+use sys::unicode::next_utf16_char;
+use sys::{copy_bytes, get16, get64, ErrorStr, SectorIndex, SECTOR_SIZE};
+
+const SIGNATURE: &'static [u8] = b"EFI PART";
+const PRIMARY_HEADER_LBA: u64 = 1;
+const MIN_HEADER_SIZE: usize = 92;
+const MIN_ENTRY_SIZE: usize = 128;
+pub const MAX_ENTRY_ARRAY_SIZE: usize = 32 * 1024;
+const NAME_LENGTH: usize = 36;
+
+// Partition attribute bits.
+pub const ATTR_REQUIRED: u64                = 1 << 0;
+pub const ATTR_NO_BLOCK_IO: u64             = 1 << 1;
+pub const ATTR_LEGACY_BIOS_BOOTABLE: u64    = 1 << 2;
+
+// Partition types that may hold a FAT volume.  The GUIDs are in their on-disk
+// byte order.
+pub const TYPE_BASIC_DATA: Guid = Guid([
+    0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44,
+    0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99, 0xc7]);
+pub const TYPE_EFI_SYSTEM: Guid = Guid([
+    0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11,
+    0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9, 0x3b]);
+
+// Space for a partition entry array.  A Table borrows the caller's buffer,
+// which is too large for the stack.
+pub type EntryBuffer = [u8; MAX_ENTRY_ARRAY_SIZE];
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    fn from_bytes(bytes: &[u8]) -> Guid {
+        let mut guid = Guid([0; 16]);
+        copy_bytes(&mut guid.0, bytes);
+        guid
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|b| *b == 0)
+    }
+}
+
+// The usual mixed-endian text form: the first three fields are little-endian.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        try!(write!(f, "{:08X}-{:04X}-{:04X}-",
+                    sys::get32(b, 0), get16(b, 4), get16(b, 6)));
+        try!(write!(f, "{:02X}{:02X}-", b[8], b[9]));
+        for byte in b[10..].iter() {
+            try!(write!(f, "{:02X}", byte));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Partition {
+    pub number: u32,
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,          // inclusive
+    pub attributes: u64,
+    name: [u16; NAME_LENGTH],   // UTF-16LE, NUL-padded
+}
+
+impl Partition {
+    pub fn sector_count(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+
+    pub fn name(&self) -> PartitionName {
+        let len = self.name.iter().position(|c| *c == 0).unwrap_or(NAME_LENGTH);
+        PartitionName(&self.name[..len])
+    }
+}
+
+pub struct PartitionName<'a>(&'a [u16]);
+
+impl<'a> fmt::Display for PartitionName<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pos = 0;
+        while pos < self.0.len() {
+            let code = next_utf16_char(self.0, &mut pos);
+            try!(write!(f, "{}", char::from_u32(code).unwrap_or('\u{fffd}')));
+        }
+        Ok(())
+    }
+}
+
+pub struct Table<'a> {
+    pub disk_guid: Guid,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub using_backup: bool,
+    entry_count: usize,
+    entry_size: usize,
+    entries: &'a [u8],
+}
+
+struct Header {
+    alternate_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    disk_guid: Guid,
+    entry_array_lba: u64,
+    entry_count: usize,
+    entry_size: usize,
+    entry_array_crc: u32,
+}
+
+fn read_sector(device: &sys::BlockDevice, lba: u64, buffer: &mut [u8]) ->
+        Result<(), ErrorStr> {
+    if lba + (buffer.len() / SECTOR_SIZE) as u64 > (1 << 32) {
+        return Err(ErrorStr::new(strlit!("GPT structure is beyond 2TiB")));
+    }
+    device.read_sectors(lba as SectorIndex, buffer)
+}
+
+fn read_header(device: &sys::BlockDevice, lba: u64) -> Result<Header, ErrorStr> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    try!(read_sector(device, lba, &mut sector));
+    if &sector[..8] != SIGNATURE {
+        return Err(ErrorStr::new(strlit!("GPT header signature is missing")));
+    }
+    let header_size = sys::get32(&sector, 12) as usize;
+    if header_size < MIN_HEADER_SIZE || header_size > SECTOR_SIZE {
+        return Err(ErrorStr::new(strlit!("GPT header size is invalid")));
+    }
+    let expected_crc = sys::get32(&sector, 16);
+    for b in sector[16..20].iter_mut() {
+        *b = 0;
+    }
+    if crc32::compute(&crc32::table_ieee(), &sector[..header_size]) != expected_crc {
+        return Err(ErrorStr::new(strlit!("GPT header checksum mismatch")));
+    }
+    let header = Header {
+        alternate_lba: get64(&sector, 32),
+        first_usable_lba: get64(&sector, 40),
+        last_usable_lba: get64(&sector, 48),
+        disk_guid: Guid::from_bytes(&sector[56..72]),
+        entry_array_lba: get64(&sector, 72),
+        entry_count: sys::get32(&sector, 80) as usize,
+        entry_size: sys::get32(&sector, 84) as usize,
+        entry_array_crc: sys::get32(&sector, 88),
+    };
+    if get64(&sector, 24) != lba ||
+            header.first_usable_lba > header.last_usable_lba ||
+            header.entry_size < MIN_ENTRY_SIZE || header.entry_size % 8 != 0 {
+        return Err(ErrorStr::new(strlit!("GPT header is invalid")));
+    }
+    Ok(header)
+}
+
+// Read and check the header and entry array at the given LBA.  The entries
+// are left at the front of `buffer`.
+fn read_table_at(device: &sys::BlockDevice, lba: u64, buffer: &mut EntryBuffer) ->
+        Result<Header, ErrorStr> {
+    let header = try!(read_header(device, lba));
+    if header.entry_count > MAX_ENTRY_ARRAY_SIZE / header.entry_size {
+        return Err(ErrorStr::new(strlit!("GPT entry array is too large")));
+    }
+    let entry_array_size = header.entry_count * header.entry_size;
+    let entry_array_sectors = (entry_array_size + SECTOR_SIZE - 1) / SECTOR_SIZE;
+    let entry_array_end = header.entry_array_lba + entry_array_sectors as u64;
+    let outside_usable = entry_array_end <= header.first_usable_lba ||
+        header.entry_array_lba > header.last_usable_lba;
+    if header.entry_array_lba <= PRIMARY_HEADER_LBA || !outside_usable {
+        return Err(ErrorStr::new(strlit!("GPT entry array location is invalid")));
+    }
+
+    try!(read_sector(device, header.entry_array_lba,
+                     &mut buffer[..entry_array_sectors * SECTOR_SIZE]));
+    let entries = &buffer[..entry_array_size];
+    if crc32::compute(&crc32::table_ieee(), entries) != header.entry_array_crc {
+        return Err(ErrorStr::new(strlit!("GPT entry array checksum mismatch")));
+    }
+    Ok(header)
+}
+
+// Read the partition table, falling back to the backup copy if the primary
+// one is damaged.  The backup header is found through the primary header if
+// that much is intact, and otherwise in the last sector of the disk, which
+// requires `disk_sectors`.  The entry array is read into `buffer`.
+pub fn read_table<'a>(device: &sys::BlockDevice, disk_sectors: Option<u64>,
+                      buffer: &'a mut EntryBuffer) -> Result<Table<'a>, ErrorStr> {
+    if device.sector_size() != SECTOR_SIZE {
+        return Err(ErrorStr::new(strlit!("GPT support needs 512-byte sectors")));
+    }
+    let (header, using_backup) = match read_table_at(device, PRIMARY_HEADER_LBA, buffer) {
+        Ok(header) => (header, false),
+        Err(err) => {
+            log_warn!("primary GPT: {}", err);
+            let backup_lba = match read_header(device, PRIMARY_HEADER_LBA) {
+                Ok(primary) => primary.alternate_lba,
+                Err(_) => match disk_sectors {
+                    Some(sectors) if sectors > 2 => sectors - 1,
+                    _ => return Err(ErrorStr::new(strlit!("cannot locate the backup GPT"))),
+                },
+            };
+            let header = try!(read_table_at(device, backup_lba, buffer));
+            log_warn!("using the backup GPT at LBA {}", backup_lba);
+            (header, true)
+        }
+    };
+    if let Some(sectors) = disk_sectors {
+        if header.last_usable_lba >= sectors {
+            return Err(ErrorStr::new(strlit!("GPT usable area is beyond the end of the disk")));
+        }
+    }
+    let buffer: &'a EntryBuffer = buffer;
+    Ok(Table {
+        disk_guid: header.disk_guid,
+        first_usable_lba: header.first_usable_lba,
+        last_usable_lba: header.last_usable_lba,
+        using_backup: using_backup,
+        entry_count: header.entry_count,
+        entry_size: header.entry_size,
+        entries: &buffer[..header.entry_count * header.entry_size],
+    })
+}
+
+impl<'a> Table<'a> {
+    pub fn partitions(&self) -> PartitionIterator {
+        PartitionIterator { table: self, index: 0 }
+    }
+}
+
+pub struct PartitionIterator<'a> {
+    table: &'a Table<'a>,
+    index: usize,
+}
+
+impl<'a> PartitionIterator<'a> {
+    // Return the next used entry, skipping the ones with a zero type GUID.
+    pub fn next(&mut self) -> Result<Option<Partition>, ErrorStr> {
+        while self.index < self.table.entry_count {
+            let offset = self.index * self.table.entry_size;
+            let entry = &self.table.entries[offset..offset + MIN_ENTRY_SIZE];
+            self.index += 1;
+            let type_guid = Guid::from_bytes(&entry[0..16]);
+            if type_guid.is_zero() {
+                continue;
+            }
+            let mut partition = Partition {
+                number: self.index as u32,
+                type_guid: type_guid,
+                unique_guid: Guid::from_bytes(&entry[16..32]),
+                first_lba: get64(entry, 32),
+                last_lba: get64(entry, 40),
+                attributes: get64(entry, 48),
+                name: [0; NAME_LENGTH],
+            };
+            for (i, ch) in partition.name.iter_mut().enumerate() {
+                *ch = get16(entry, 56 + i * 2);
+            }
+            if partition.first_lba > partition.last_lba ||
+                    partition.first_lba < self.table.first_usable_lba ||
+                    partition.last_lba > self.table.last_usable_lba {
+                return Err(ErrorStr::new(strlit!("GPT partition entry is out of bounds")));
+            }
+            return Ok(Some(partition));
+        }
+        Ok(None)
+    }
+}
@@ -13,14 +13,467 @@ mod std {
     pub use core::fmt;
 }
 
+#[macro_use] mod macros;
+
+mod ahci;
+mod ata;
+mod block;
+mod boot_info;
+mod console;
+mod dma;
+mod gpt;
+mod long_mode;
+mod nvme;
+mod partition;
+mod pxe;
+mod smbios;
+mod virtio_blk;
+mod volume;
+
+// Access the boot disk through a native driver (ATA, AHCI, virtio-blk, or
+// NVMe) when one matches it, rather than through BIOS INT 13h.  Once a native
+// driver owns the disk, stage2 never reads it through INT 13h again.
+const NATIVE_DISK_DRIVERS: bool = true;
+
+// Seconds to wait before rebooting after a fatal error, or 0 to halt instead
+// (e.g. to keep the error on screen while debugging).
+const FATAL_ERROR_REBOOT_TIMEOUT: u32 = 30;
+
+fn reboot_after_fatal_error() {
+    sys::print_str(strlit!("\r\n"));
+    sys::reboot_after_timeout(FATAL_ERROR_REBOOT_TIMEOUT);
+}
+
 #[lang = "panic_fmt"] #[cold] #[inline(never)]
-extern fn rust_panic_fmt(_msg: std::fmt::Arguments, file: &'static str, line: u32) -> ! {
-    // TODO: Replace this with a full argument-printing panic function.
-    sys::simple_panic(strref!(file), line, strlit!("rust_panic_fmt"), strlit!(""))
+extern fn rust_panic_fmt(msg: std::fmt::Arguments, file: &'static str, line: u32) -> ! {
+    print!("internal error: {}:{}: {}", file, line, msg);
+    sys::fatal_error_halt();
+}
+
+static mut LOG_RING: [u8; 4096] = [0u8; 4096];
+
+// Show the log ring buffer (e.g. from a diagnostics menu).
+#[allow(dead_code)]
+fn print_log() {
+    let (older, newer) = sys::log::ring_buffer();
+    sys::print_byte_str(&older);
+    sys::print_byte_str(&newer);
+}
+
+fn print_cpu_info() {
+    use sys::cpuid;
+
+    let info = cpuid::cpu_info();
+    print!("CPU: ");
+    console::print_bytes(info.vendor());
+    print!(" ");
+    console::print_bytes(info.brand());
+    println!(" (family {}, model {}, stepping {})",
+             info.family, info.model, info.stepping);
+
+    let features = [
+        (cpuid::FEATURE_LONG_MODE, "lm"),
+        (cpuid::FEATURE_PAE, "pae"),
+        (cpuid::FEATURE_NX, "nx"),
+        (cpuid::FEATURE_SSE4_2, "sse4.2"),
+        (cpuid::FEATURE_RDRAND, "rdrand"),
+        (cpuid::FEATURE_INVARIANT_TSC, "invtsc"),
+        (cpuid::FEATURE_HYPERVISOR, "hypervisor"),
+    ];
+    print!("CPU features:");
+    for &(flag, name) in features.iter() {
+        if info.has(flag) {
+            print!(" {}", name);
+        }
+    }
+    println!("");
+}
+
+// Check that the native driver reads the same data as the BIOS did.
+fn check_native_driver(disk: &block::Backend, reference: &block::BiosReference) {
+    match block::compare_with_bios(disk, reference) {
+        Err(err) => log_error!("{} read failed: {}", disk.name(), err),
+        Ok(true) => log_info!("{} driver matches BIOS disk reads", disk.name()),
+        Ok(false) => log_error!("{} driver does not match BIOS disk reads", disk.name()),
+    }
+}
+
+// Lent to one GPT table at a time, by pcboot_main.
+static mut GPT_ENTRIES: gpt::EntryBuffer = [0u8; gpt::MAX_ENTRY_ARRAY_SIZE];
+
+fn print_gpt_partitions(disk: &sys::BlockDevice, disk_sectors: Option<u64>,
+                        entries: &mut gpt::EntryBuffer) {
+    let table = match gpt::read_table(disk, disk_sectors, entries) {
+        Err(err) => {
+            log_warn!("boot disk GPT: {}", err);
+            return;
+        },
+        Ok(table) => table,
+    };
+    println!("GPT disk {}{}", table.disk_guid,
+             if table.using_backup { " (backup table)" } else { "" });
+    let mut iter = table.partitions();
+    loop {
+        match iter.next() {
+            Err(err) => {
+                log_warn!("boot disk GPT: {}", err);
+                break;
+            },
+            Ok(None) => break,
+            Ok(Some(part)) => {
+                println!("Partition {}: \"{}\", start {}, {} sectors{}",
+                         part.number, part.name(), part.first_lba, part.sector_count(),
+                         if part.attributes & gpt::ATTR_LEGACY_BIOS_BOOTABLE != 0 {
+                             ", bootable"
+                         } else {
+                             ""
+                         });
+                println!("  type {}, id {}", part.type_guid, part.unique_guid);
+            }
+        }
+    }
+}
+
+fn print_partitions(disk: &sys::BlockDevice, disk_sectors: Option<u64>,
+                    gpt_entries: &mut gpt::EntryBuffer) {
+    let mut iter = match partition::partitions(disk, disk_sectors) {
+        Err(err) => {
+            log_warn!("boot disk partitions: {}", err);
+            return;
+        },
+        Ok(iter) => iter,
+    };
+    loop {
+        match iter.next() {
+            Err(err) => {
+                log_warn!("boot disk partitions: {}", err);
+                break;
+            },
+            Ok(None) => break,
+            Ok(Some(part)) => {
+                // A protective MBR entry means the real table is the GPT.
+                if part.type_id == partition::TYPE_GPT_PROTECTIVE {
+                    print_gpt_partitions(disk, disk_sectors, gpt_entries);
+                    break;
+                }
+                println!("Partition {}: type 0x{:02x}, start {}, {} sectors{}",
+                         part.number, part.type_id, part.start_lba, part.sector_count,
+                         if part.bootable { ", bootable" } else { "" });
+            }
+        }
+    }
+}
+
+// Look for every volume carrying the boot volume's marker ID.  The boot
+// volume should be the only one, at the location stage1 passed in.
+fn check_boot_volume(disk: &sys::BlockDevice, disk_number: u8, disk_sectors: Option<u64>,
+                     volume_lba: u32, gpt_entries: &mut gpt::EntryBuffer) {
+    let id = match volume::read_marker_id(disk, volume_lba) {
+        Err(err) => {
+            log_warn!("boot volume: {}", err);
+            return;
+        },
+        Ok(None) => {
+            log_warn!("boot volume at LBA {} has no pcboot marker", volume_lba);
+            return;
+        },
+        Ok(Some(id)) => id,
+    };
+    let matches = volume::find_all(id, disk, disk_number, disk_sectors, gpt_entries);
+    let mut found_boot_volume = false;
+    for i in 0..matches.count() {
+        if let Some(location) = matches.get(i) {
+            println!("pcboot volume {}: disk 0x{:x}, partition {}, LBA {}",
+                     id, location.disk_number, location.partition_number, location.lba);
+            found_boot_volume = found_boot_volume ||
+                (location.disk_number == disk_number && location.lba == volume_lba);
+        }
+    }
+    if matches.count() > 1 {
+        log_warn!("{} pcboot volumes have marker ID {}", matches.count(), id);
+    } else if !found_boot_volume {
+        log_warn!("boot volume not found on disk 0x{:x} at LBA {}", disk_number, volume_lba);
+    }
+}
+
+// Whether a BIOS disk might be behind the PCI device at `addr`.  A disk
+// without an EDD device path could be behind any controller.  The boot disk's
+// path is passed in, since a native driver may own it.
+fn bios_uses_device(addr: sys::pci::Address, boot_disk_number: u8,
+                    boot_device_path: Option<sys::edd::DevicePath>) -> bool {
+    use sys::edd::HostBus;
+
+    for index in 0..sys::hard_disk_count() {
+        let disk_number = 0x80u8.wrapping_add(index);
+        if disk_number < 0x80 {
+            break;
+        }
+        let path = if disk_number == boot_disk_number {
+            boot_device_path
+        } else {
+            sys::edd::get_drive_parameters(disk_number).ok()
+                .and_then(|params| params.device_path)
+        };
+        match path {
+            None => return true,
+            Some(path) => if let HostBus::Pci { bus, device, function, .. } = path.host_bus {
+                if addr.bus == bus && addr.device == device && addr.function == function {
+                    return true;
+                }
+            },
+        }
+    }
+    false
+}
+
+// Show the first NVMe disk that the BIOS is not using.  Resetting a controller
+// under the BIOS's own driver would break INT 13h for its disks.
+fn print_nvme_disk(boot_disk_number: u8, boot_device_path: Option<sys::edd::DevicePath>) {
+    let in_use = |addr| bios_uses_device(addr, boot_disk_number, boot_device_path);
+    if let Some(addr) = nvme::find_controller(&in_use) {
+        match nvme::open(addr) {
+            Err(err) => log_warn!("{}", err),
+            Ok(disk) => println!("NVMe disk pci-0000:{:02x}:{:02x}.{:x}-nvme-{}: {} sectors",
+                                 addr.bus, addr.device, addr.function,
+                                 disk.namespace, disk.sector_count),
+        }
+    }
+}
+
+// The kernel's name is not an 8.3 name, so finding it exercises the FAT long
+// name and the ISO 9660 Rock Ridge and Joliet lookups.
+const KERNEL_FILE_NAME: &'static [u8] = b"kernel-x86_64.bin";
+
+// A flat 64-bit kernel image is loaded at 1MiB and entered at its first byte.
+// It must end below 15MiB, where some old chipsets have a memory hole.
+const KERNEL_LOAD_ADDRESS: u32 = 0x100000;
+const KERNEL_MAX_SIZE: u32 = 0xe00000;
+
+// Load the kernel from the pcboot FAT volume into `image`.  Returns its size,
+// or None if there is no kernel to load.
+fn load_kernel_from_fat(disk: &sys::BlockDevice, volume_lba: u32, image: &mut [u8]) ->
+        Option<usize> {
+    use sys::fat32;
+
+    let volume = match fat32::open_volume(disk, volume_lba as sys::SectorIndex) {
+        Err(err) => {
+            log_error!("boot volume: {}", err);
+            return None;
+        },
+        Ok(volume) => volume,
+    };
+    let mut file = match fat32::open(&volume, KERNEL_FILE_NAME) {
+        Err(err) => {
+            log_error!("kernel-x86_64.bin: {}", err);
+            return None;
+        },
+        Ok(None) => {
+            log_info!("no kernel-x86_64.bin on the boot volume");
+            return None;
+        },
+        Ok(Some(file)) => file,
+    };
+    if file.size() as usize > image.len() {
+        log_error!("kernel-x86_64.bin is too large ({} bytes)", file.size());
+        return None;
+    }
+    match file.read(image) {
+        Err(err) => {
+            log_error!("cannot read kernel-x86_64.bin: {}", err);
+            None
+        },
+        Ok(size) => Some(size),
+    }
+}
+
+// Load the kernel from the ISO 9660 volume on a CD into `image`.
+fn load_kernel_from_iso9660(disk: &sys::BlockDevice, image: &mut [u8]) -> Option<usize> {
+    use sys::iso9660;
+
+    let volume = match iso9660::open_volume(disk, 0) {
+        Err(err) => {
+            log_error!("boot CD: {}", err);
+            return None;
+        },
+        Ok(volume) => volume,
+    };
+    let file = match volume.lookup(KERNEL_FILE_NAME) {
+        Err(err) => {
+            log_error!("kernel-x86_64.bin: {}", err);
+            return None;
+        },
+        Ok(Some(ref file)) if file.is_dir => {
+            log_error!("kernel-x86_64.bin is a directory");
+            return None;
+        },
+        Ok(None) => {
+            log_info!("no kernel-x86_64.bin on the boot CD");
+            return None;
+        },
+        Ok(Some(file)) => file,
+    };
+    match volume.read_contents(&file, image) {
+        Err(err) => {
+            log_error!("cannot read kernel-x86_64.bin ({} bytes): {}", file.size, err);
+            None
+        },
+        Ok(size) => Some(size as usize),
+    }
+}
+
+// Load the kernel from the boot volume or CD and enter it in long mode.
+// Returns if there is no kernel that can be booted.
+fn boot_kernel(disk: &sys::BlockDevice, volume_lba: u32,
+               boot_info: &mut boot_info::BootInfo) {
+    if !long_mode::is_supported() {
+        log_info!("CPU does not support long mode; not loading a kernel");
+        return;
+    }
+    if !long_mode::a20_enabled() {
+        log_error!("cannot load a kernel: the A20 line is disabled");
+        return;
+    }
+    let image = unsafe {
+        core::slice::from_raw_parts_mut(KERNEL_LOAD_ADDRESS as *mut u8,
+                                        KERNEL_MAX_SIZE as usize)
+    };
+    let size = if disk.sector_size() == sys::iso9660::BLOCK_SIZE {
+        load_kernel_from_iso9660(disk, image)
+    } else if disk.sector_size() == sys::SECTOR_SIZE {
+        load_kernel_from_fat(disk, volume_lba, image)
+    } else {
+        log_warn!("cannot load a kernel from {}-byte sectors", disk.sector_size());
+        None
+    };
+    if let Some(size) = size {
+        println!("Booting kernel-x86_64.bin ({} bytes)", size);
+        long_mode::enter(KERNEL_LOAD_ADDRESS as u64, boot_info);
+    }
+}
+
+const CONFIG_FILE_NAME: &'static [u8] = b"pcboot.cfg";
+
+static mut CONFIG_BUFFER: [u8; 4096] = [0u8; 4096];
+
+// Booted from the network by the PXE stub.  Report the DHCP-assigned
+// addresses and fetch the configuration file from the boot server.
+fn network_boot() -> ! {
+    use sys::FileSource;
+
+    let pxe = match pxe::init() {
+        Ok(pxe) => pxe,
+        Err(err) => panic!("cannot initialize PXE: {}", err),
+    };
+    println!("Network boot: client {}, server {}, gateway {}",
+             pxe::IpAddr(&pxe.client_ip), pxe::IpAddr(&pxe.server_ip),
+             pxe::IpAddr(&pxe.gateway_ip));
+    print!("Boot file: ");
+    console::print_bytes(pxe.boot_file());
+    println!("");
+
+    match pxe.file_size(CONFIG_FILE_NAME) {
+        Err(err) => log_error!("TFTP: {}", err),
+        Ok(None) => log_info!("no pcboot.cfg on the boot server"),
+        Ok(Some(size)) => {
+            let buffer = unsafe { &mut CONFIG_BUFFER };
+            match pxe.read_file(CONFIG_FILE_NAME, buffer) {
+                Err(err) => log_error!("cannot read pcboot.cfg ({} bytes): {}", size, err),
+                Ok(size) => println!("Loaded pcboot.cfg: {} bytes", size),
+            }
+        }
+    }
+
+    sys::halt();
 }
 
 #[no_mangle]
-pub extern "C" fn pcboot_main(_disk_number: u8, _volume_lba: u32) -> ! {
+pub extern "C" fn pcboot_main(disk_number: u8, volume_lba: u32) -> ! {
+    use sys::BlockDevice;
+
     sys::print_str(strlit!("pcboot stage2 loading...\r\n"));
+
+    if FATAL_ERROR_REBOOT_TIMEOUT != 0 {
+        sys::set_fatal_error_handler(reboot_after_fatal_error);
+    }
+
+    sys::log::set_ring_buffer(unsafe { &mut LOG_RING });
+    sys::log::set_sinks(sys::log::SINK_SCREEN | sys::log::SINK_DEBUG_PORT);
+
+    let mut boot_info = boot_info::new(disk_number, volume_lba);
+
+    match smbios::find() {
+        None => log_warn!("SMBIOS entry point not found"),
+        Some(entry) => {
+            boot_info.smbios_entry = entry.addr;
+            smbios::print_summary(&entry);
+        }
+    }
+
+    print_cpu_info();
+
+    if disk_number == pxe::NETWORK_BOOT_DISK {
+        network_boot();
+    }
+
+    let drive_params = sys::edd::get_drive_parameters(disk_number);
+    let device_path = match drive_params {
+        Err(ref err) => {
+            log_warn!("boot disk 0x{:x}: {}", disk_number, err);
+            None
+        },
+        Ok(ref params) => {
+            print!("Boot disk 0x{:x}: {} sectors of {} bytes",
+                   disk_number, params.total_sectors, params.bytes_per_sector);
+            match params.device_path {
+                None => println!(""),
+                Some(path) => println!(", {}", path),
+            }
+            params.device_path
+        }
+    };
+
+    // A native driver's reads are checked against the BIOS's, which must be
+    // done before the driver takes the disk over.
+    let reference = if NATIVE_DISK_DRIVERS && device_path.is_some() {
+        match sys::open_disk(disk_number)
+                .and_then(|disk| block::read_bios_reference(&disk, volume_lba)) {
+            Err(err) => {
+                log_warn!("boot disk 0x{:x}: {}", disk_number, err);
+                None
+            },
+            Ok(reference) => Some(reference),
+        }
+    } else {
+        None
+    };
+
+    // From here on, the boot disk is only accessed through `boot_disk`.
+    let boot_disk = match block::open(disk_number, device_path, NATIVE_DISK_DRIVERS) {
+        Ok(disk) => disk,
+        Err(err) => panic!("cannot open boot disk: {}", err),
+    };
+    println!("Boot disk driver: {}", boot_disk.name());
+    match boot_disk {
+        block::Backend::Bios(_) => {},
+        _ => if let Some(ref reference) = reference {
+            check_native_driver(&boot_disk, reference);
+        },
+    }
+
+    let disk_sectors = drive_params.ok().map(|params| params.total_sectors);
+    let gpt_entries = unsafe { &mut GPT_ENTRIES };
+    print_partitions(&boot_disk, disk_sectors, gpt_entries);
+    if boot_disk.sector_size() == sys::SECTOR_SIZE {
+        check_boot_volume(&boot_disk, disk_number, disk_sectors, volume_lba, gpt_entries);
+    }
+
+    // The BIOS may not see NVMe drives at all, so look for one directly.
+    match boot_disk {
+        block::Backend::Nvme(_) => {},
+        _ => print_nvme_disk(disk_number, device_path),
+    }
+
+    boot_kernel(&boot_disk, volume_lba, &mut boot_info);
+
     sys::halt();
 }
@@ -0,0 +1,95 @@
+        ;
+        ; GDT used once the CPU has entered long mode.  The protected-mode GDT
+        ; in libsys has no 64-bit code segment, and once paging is enabled in
+        ; long mode we never return to real mode, so a separate minimal table
+        ; suffices.
+        ;
+
+        section .data
+        align 8
+gdt64:
+        ; LGDT operand
+        dw .end-gdt64-1
+        dd gdt64
+        dw 0
+.code64:
+        ; 64-bit code segment, Type=Code+R, S=1, DPL=0, P=1, L=1, D=0, G=1
+        dd 0x0000ffff
+        dd 0x00af9a00
+.data64:
+        ; Data segment, Type=Data+RW, S=1, DPL=0, P=1, B=1, G=1
+        dd 0x0000ffff
+        dd 0x00cf9200
+.end:
+
+
+        section .text
+        bits 32
+
+
+        ;
+        ; Switch the CPU into 64-bit long mode and jump to a 64-bit entry
+        ; point.  Does not return.
+        ;
+        ; C prototype:
+        ;
+        ;     void enter_long_mode(u32 pml4, u32 entry_lo, u32 entry_hi,
+        ;                          u32 boot_info);
+        ;
+        ; The PML4 must identity-map this code, the stack, and the boot-info
+        ; structure.  The entry point is called with RDI holding the address of
+        ; the boot-info structure (as in the SysV x86-64 ABI), interrupts
+        ; disabled, and RSP 16-byte aligned.
+        ;
+        global enter_long_mode
+enter_long_mode:
+        cli
+        mov esi, [esp + 8]              ; entry_lo
+        mov ebx, [esp + 12]             ; entry_hi
+        mov edi, [esp + 16]             ; boot_info
+
+        ; Load the page table root.  Paging is still off, so this is inert
+        ; until CR0.PG is set.
+        mov eax, [esp + 4]
+        mov cr3, eax
+
+        ; Enable PAE.
+        mov eax, cr4
+        or eax, 1 << 5
+        mov cr4, eax
+
+        ; Set EFER.LME.
+        mov ecx, 0xc0000080
+        rdmsr
+        or eax, 1 << 8
+        wrmsr
+
+        ; Enable paging, which activates long mode (in compatibility mode
+        ; until we load a 64-bit code segment).
+        lgdt [gdt64]
+        mov eax, cr0
+        or eax, 1 << 31
+        mov cr0, eax
+        jmp (gdt64.code64 - gdt64):.step1
+.step1:
+        bits 64
+        mov ax, (gdt64.data64 - gdt64)
+        mov ds, ax
+        mov es, ax
+        mov fs, ax
+        mov gs, ax
+        mov ss, ax
+
+        ; The upper halves of the GPRs are undefined after the mode switch.
+        ; Writing a 32-bit register zero-extends it.
+        mov eax, esi
+        shl rbx, 32
+        or rax, rbx
+        mov edi, edi
+        mov esp, esp
+        and rsp, -16
+
+        call rax
+.loop:
+        hlt
+        jmp .loop
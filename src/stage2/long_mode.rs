@@ -0,0 +1,120 @@
+// Transition from 32-bit protected mode to 64-bit long mode.
+//
+// stage2 runs without paging, but long mode requires it.  Before jumping to a
+// 64-bit kernel, we build page tables that identity-map the first 4GiB of the
+// physical address space, which covers pcboot itself, the loaded kernel, and
+// the usual MMIO regions.  1GiB pages are used when the CPU supports them;
+// otherwise, we fall back to 2MiB pages, which need four page directories.
+//
+// The A20 line must already be enabled if the kernel lives above 1MiB; see
+// a20_enabled().
+
+use core::cell::UnsafeCell;
+
+use boot_info;
+use boot_info::BootInfo;
+use sys;
+use sys::io;
+
+const PAGE_TABLE_ENTRIES: usize = 512;
+
+// One PML4, one PDPT, and (without 1GiB pages) four page directories.
+const PAGE_TABLE_COUNT: usize = 6;
+const PAGE_TABLE_AREA_ENTRIES: usize = PAGE_TABLE_ENTRIES * PAGE_TABLE_COUNT;
+
+const PAGE_PRESENT: u64     = 0x01;
+const PAGE_WRITABLE: u64    = 0x02;
+const PAGE_LARGE: u64       = 0x80;     // PS bit: 2MiB/1GiB page
+
+const IDENTITY_MAPPED_GIB: usize = 4;
+
+#[allow(improper_ctypes)]
+extern {
+    // A 4KiB-aligned area reserved by the linker script.
+    static _page_tables: UnsafeCell<[u64; PAGE_TABLE_AREA_ENTRIES]>;
+    static _page_tables_end: UnsafeCell<[u64; 0]>;
+}
+
+extern "C" {
+    fn enter_long_mode(pml4: u32, entry_lo: u32, entry_hi: u32, boot_info: u32) -> !;
+}
+
+pub fn is_supported() -> bool {
+    sys::cpuid::cpu_info().has(sys::cpuid::FEATURE_LONG_MODE)
+}
+
+static mut A20_TEST: u32 = 0;
+
+// Check whether the A20 line is enabled.  With it disabled, addresses wrap at
+// 1MiB, so a write to low memory also shows up 1MiB higher.
+pub fn a20_enabled() -> bool {
+    let low = unsafe { &A20_TEST as *const u32 as u32 };
+    let high = low + 0x100000;
+    let original = io::mmio_read32(high);
+    io::mmio_write32(low, !original);
+    io::mmio_read32(high) == original
+}
+
+// Fill in the page tables and return the physical address of the PML4.
+fn build_page_tables() -> u32 {
+    let tables = unsafe { &mut *_page_tables.get() };
+    let linker_size = unsafe {
+        _page_tables_end.get() as usize - _page_tables.get() as usize
+    };
+    assert!(linker_size == PAGE_TABLE_AREA_ENTRIES * 8);
+
+    for entry in tables.iter_mut() {
+        *entry = 0;
+    }
+
+    let base = tables.as_ptr() as u64;
+    assert!(base % 4096 == 0);
+    let table_addr = |index: usize| base + (index * PAGE_TABLE_ENTRIES * 8) as u64;
+
+    const PML4: usize = 0;
+    const PDPT: usize = 1;
+    const FIRST_PD: usize = 2;
+
+    tables[PML4 * PAGE_TABLE_ENTRIES] =
+        table_addr(PDPT) | PAGE_PRESENT | PAGE_WRITABLE;
+
+    let use_1gib_pages =
+        sys::cpuid::cpu_info().has(sys::cpuid::FEATURE_1GIB_PAGES);
+    for gib in 0..IDENTITY_MAPPED_GIB {
+        let pdpte = &mut tables[PDPT * PAGE_TABLE_ENTRIES + gib];
+        if use_1gib_pages {
+            *pdpte = ((gib as u64) << 30) |
+                PAGE_PRESENT | PAGE_WRITABLE | PAGE_LARGE;
+        } else {
+            *pdpte = table_addr(FIRST_PD + gib) | PAGE_PRESENT | PAGE_WRITABLE;
+        }
+    }
+
+    if !use_1gib_pages {
+        for gib in 0..IDENTITY_MAPPED_GIB {
+            let pd_start = (FIRST_PD + gib) * PAGE_TABLE_ENTRIES;
+            for i in 0..PAGE_TABLE_ENTRIES {
+                tables[pd_start + i] =
+                    ((gib as u64) << 30) | ((i as u64) << 21) |
+                    PAGE_PRESENT | PAGE_WRITABLE | PAGE_LARGE;
+            }
+        }
+    }
+
+    base as u32
+}
+
+// Enter a 64-bit kernel at the given physical address.  The caller must check
+// is_supported() first.
+pub fn enter(entry: u64, boot_info: &mut BootInfo) -> ! {
+    assert!(is_supported());
+    boot_info::finish(boot_info);
+    let pml4 = build_page_tables();
+    unsafe {
+        enter_long_mode(
+            pml4,
+            entry as u32,
+            (entry >> 32) as u32,
+            boot_info as *const BootInfo as u32)
+    }
+}
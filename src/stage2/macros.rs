@@ -0,0 +1,14 @@
+macro_rules! print {
+    ($($arg:tt)*) => (
+        ::console::print_fmt(format_args!($($arg)*))
+    );
+}
+
+macro_rules! println {
+    ($fmt:expr) => (
+        print!(concat!($fmt, "\r\n"))
+    );
+    ($fmt:expr, $($arg:tt)*) => (
+        print!(concat!($fmt, "\r\n"), $($arg)*)
+    );
+}
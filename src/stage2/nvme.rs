@@ -0,0 +1,394 @@
+// Minimal NVMe driver.
+//
+// Older BIOSes cannot boot from NVMe drives, or do not expose them through
+// INT 13h at all.  This driver resets the controller, sets up the admin queue
+// pair, identifies the controller and its namespaces, and creates a single
+// I/O queue pair.  Commands are submitted one at a time, and completion is
+// detected by polling the completion queue's phase bit.
+//
+// Only namespaces formatted with 512-byte LBAs are supported.  Each command
+// uses PRP1 and PRP2 only (no PRP lists), so transfers are split into chunks
+// that span at most two memory pages.  PRP entries must be dword-aligned, so
+// other buffers are bounced through a page of DMA memory.
+//
+// The queues and buffers are allocated once and reused by every open, since
+// DMA memory is never freed.  Opening a controller resets it, so only the most
+// recently opened NvmeDisk may be used.
+
+use core::cell::Cell;
+use core::cmp;
+
+use dma;
+use sys;
+use sys::io;
+use sys::pci;
+use sys::{copy_bytes, get32, ErrorStr, SectorIndex, SECTOR_SIZE};
+
+// Controller registers.
+const REG_CAP: u32      = 0x00;
+const REG_CC: u32       = 0x14;
+const REG_CSTS: u32     = 0x1c;
+const REG_AQA: u32      = 0x24;
+const REG_ASQ: u32      = 0x28;
+const REG_ACQ: u32      = 0x30;
+const REG_DOORBELLS: u32 = 0x1000;
+
+const CC_EN: u32        = 1 << 0;
+const CC_IOSQES: u32    = 6 << 16;      // 64-byte submission entries
+const CC_IOCQES: u32    = 4 << 20;      // 16-byte completion entries
+const CSTS_RDY: u32     = 1 << 0;
+const CSTS_CFS: u32     = 1 << 1;       // controller fatal status
+
+const ADMIN_CREATE_IO_SQ: u8 = 0x01;
+const ADMIN_CREATE_IO_CQ: u8 = 0x05;
+const ADMIN_IDENTIFY: u8     = 0x06;
+
+const IO_FLUSH: u8      = 0x00;
+const IO_WRITE: u8      = 0x01;
+const IO_READ: u8       = 0x02;
+
+const IDENTIFY_NAMESPACE: u32  = 0;
+const IDENTIFY_CONTROLLER: u32 = 1;
+
+const PAGE_SIZE: usize = 4096;
+const SUBMISSION_ENTRY_SIZE: usize = 64;
+const COMPLETION_ENTRY_SIZE: usize = 16;
+const ADMIN_QUEUE_ENTRIES: u16 = 16;
+const MAX_IO_QUEUE_ENTRIES: u16 = 64;
+const IO_QUEUE_ID: u16 = 1;
+const MAX_NAMESPACES: u32 = 1024;    // bounds the IDENTIFY loop
+const POLL_LIMIT: u32 = 100_000_000;
+
+struct Queue {
+    id: u16,
+    entries: u16,
+    submission: u32,
+    completion: u32,
+    sq_tail: Cell<u16>,
+    cq_head: Cell<u16>,
+    phase: Cell<bool>,
+}
+
+impl Queue {
+    fn new(id: u16, entries: u16, submission: u32, completion: u32) -> Queue {
+        // A previous open may have left completions with the phase bit set.
+        for byte in dma::as_slice(completion, entries as usize * COMPLETION_ENTRY_SIZE) {
+            *byte = 0;
+        }
+        Queue {
+            id: id,
+            entries: entries,
+            submission: submission,
+            completion: completion,
+            sq_tail: Cell::new(0),
+            cq_head: Cell::new(0),
+            phase: Cell::new(true),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Buffers {
+    admin_submission: u32,
+    admin_completion: u32,
+    io_submission: u32,
+    io_completion: u32,
+    identify: u32,
+    bounce: u32,
+}
+
+static mut BUFFERS: Option<Buffers> = None;
+
+// Return the driver's DMA memory, allocating it on first use.
+fn buffers() -> Buffers {
+    fn alloc_queue(entries: u16, entry_size: usize) -> u32 {
+        dma::alloc(entries as usize * entry_size, PAGE_SIZE)
+    }
+    unsafe {
+        if BUFFERS.is_none() {
+            BUFFERS = Some(Buffers {
+                admin_submission: alloc_queue(ADMIN_QUEUE_ENTRIES, SUBMISSION_ENTRY_SIZE),
+                admin_completion: alloc_queue(ADMIN_QUEUE_ENTRIES, COMPLETION_ENTRY_SIZE),
+                io_submission: alloc_queue(MAX_IO_QUEUE_ENTRIES, SUBMISSION_ENTRY_SIZE),
+                io_completion: alloc_queue(MAX_IO_QUEUE_ENTRIES, COMPLETION_ENTRY_SIZE),
+                identify: dma::alloc(PAGE_SIZE, PAGE_SIZE),
+                bounce: dma::alloc(PAGE_SIZE, PAGE_SIZE),
+            });
+        }
+        BUFFERS.unwrap()
+    }
+}
+
+// The fields of a submission queue entry that we use.
+struct Command {
+    opcode: u8,
+    namespace: u32,
+    prp1: u32,
+    prp2: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+}
+
+pub struct NvmeDisk {
+    pub pci: pci::Address,
+    pub namespace: u32,
+    pub sector_count: u64,
+    regs: u32,
+    doorbell_stride: u32,
+    admin: Queue,
+    io: Queue,
+    bounce: u32,
+    command_id: Cell<u16>,
+}
+
+impl NvmeDisk {
+    fn read_reg(&self, offset: u32) -> u32 {
+        io::mmio_read32(self.regs + offset)
+    }
+
+    fn write_reg(&self, offset: u32, value: u32) {
+        io::mmio_write32(self.regs + offset, value)
+    }
+
+    fn submit(&self, queue: &Queue, cmd: &Command) -> Result<(), ErrorStr> {
+        let command_id = self.command_id.get();
+        self.command_id.set(command_id.wrapping_add(1));
+
+        let tail = queue.sq_tail.get();
+        let entry = queue.submission + tail as u32 * SUBMISSION_ENTRY_SIZE as u32;
+        for offset in 0..(SUBMISSION_ENTRY_SIZE / 4) as u32 {
+            io::mmio_write32(entry + offset * 4, 0);
+        }
+        io::mmio_write32(entry, cmd.opcode as u32 | ((command_id as u32) << 16));
+        io::mmio_write32(entry + 4, cmd.namespace);
+        io::mmio_write32(entry + 24, cmd.prp1);
+        io::mmio_write32(entry + 32, cmd.prp2);
+        io::mmio_write32(entry + 40, cmd.cdw10);
+        io::mmio_write32(entry + 44, cmd.cdw11);
+        io::mmio_write32(entry + 48, cmd.cdw12);
+
+        let tail = (tail + 1) % queue.entries;
+        queue.sq_tail.set(tail);
+        self.write_reg(REG_DOORBELLS + (2 * queue.id as u32) * self.doorbell_stride,
+                       tail as u32);
+
+        // Wait for the completion entry's phase bit to flip.
+        let head = queue.cq_head.get();
+        let completion = queue.completion + head as u32 * COMPLETION_ENTRY_SIZE as u32;
+        let mut tries = 0;
+        loop {
+            let dw3 = io::mmio_read32(completion + 12);
+            if (dw3 & (1 << 16) != 0) == queue.phase.get() {
+                let head = (head + 1) % queue.entries;
+                if head == 0 {
+                    queue.phase.set(!queue.phase.get());
+                }
+                queue.cq_head.set(head);
+                self.write_reg(REG_DOORBELLS + (2 * queue.id as u32 + 1) * self.doorbell_stride,
+                               head as u32);
+                if (dw3 >> 17) & 0x7fff != 0 {
+                    log_debug!("NVMe command 0x{:x} failed: status 0x{:x}",
+                               cmd.opcode, (dw3 >> 17) & 0x7fff);
+                    return Err(ErrorStr::new(strlit!("NVMe command failed")));
+                }
+                return Ok(());
+            }
+            tries += 1;
+            if tries == POLL_LIMIT {
+                return Err(ErrorStr::new(strlit!("NVMe command timed out")));
+            }
+        }
+    }
+
+    fn identify(&self, cns: u32, namespace: u32, buffer: u32) -> Result<(), ErrorStr> {
+        self.submit(&self.admin, &Command {
+            opcode: ADMIN_IDENTIFY, namespace: namespace, prp1: buffer, prp2: 0,
+            cdw10: cns, cdw11: 0, cdw12: 0,
+        })
+    }
+
+    fn transfer(&self, opcode: u8, start_sector: SectorIndex, buffer: u32, length: usize) ->
+            Result<(), ErrorStr> {
+        assert!(length % SECTOR_SIZE == 0);
+        assert!(buffer % 4 == 0);
+        let mut done = 0;
+        while done < length {
+            // PRP1 may start mid-page; PRP2 covers the following page.
+            let addr = buffer + done as u32;
+            let first_page = PAGE_SIZE - addr as usize % PAGE_SIZE;
+            let mut size = (first_page + PAGE_SIZE) / SECTOR_SIZE * SECTOR_SIZE;
+            if size > length - done {
+                size = length - done;
+            }
+            let prp2 = if size > first_page { addr + first_page as u32 } else { 0 };
+            let lba = start_sector as u64 + (done / SECTOR_SIZE) as u64;
+            try!(self.submit(&self.io, &Command {
+                opcode: opcode, namespace: self.namespace, prp1: addr, prp2: prp2,
+                cdw10: lba as u32, cdw11: (lba >> 32) as u32,
+                cdw12: (size / SECTOR_SIZE - 1) as u32,
+            }));
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+fn chunk_sector(start_sector: SectorIndex, index: usize) -> SectorIndex {
+    start_sector + (index * (PAGE_SIZE / SECTOR_SIZE)) as SectorIndex
+}
+
+impl sys::BlockDevice for NvmeDisk {
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        if buffer.as_ptr() as usize % 4 == 0 {
+            return self.transfer(IO_READ, start_sector, buffer.as_mut_ptr() as u32,
+                                 buffer.len());
+        }
+        let bounce = dma::as_slice(self.bounce, PAGE_SIZE);
+        for (index, chunk) in buffer.chunks_mut(PAGE_SIZE).enumerate() {
+            try!(self.transfer(IO_READ, chunk_sector(start_sector, index), self.bounce,
+                               chunk.len()));
+            copy_bytes(chunk, bounce);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        if buffer.as_ptr() as usize % 4 == 0 {
+            try!(self.transfer(IO_WRITE, start_sector, buffer.as_ptr() as u32,
+                               buffer.len()));
+        } else {
+            let bounce = dma::as_slice(self.bounce, PAGE_SIZE);
+            for (index, chunk) in buffer.chunks(PAGE_SIZE).enumerate() {
+                copy_bytes(bounce, chunk);
+                try!(self.transfer(IO_WRITE, chunk_sector(start_sector, index), self.bounce,
+                                   chunk.len()));
+            }
+        }
+        self.submit(&self.io, &Command {
+            opcode: IO_FLUSH, namespace: self.namespace, prp1: 0, prp2: 0,
+            cdw10: 0, cdw11: 0, cdw12: 0,
+        })
+    }
+}
+
+pub fn is_nvme(addr: pci::Address) -> bool {
+    pci::class_code(addr) == (0x01, 0x08, 0x02)
+}
+
+// Find the first NVMe controller for which `skip` returns false.
+pub fn find_controller(skip: &Fn(pci::Address) -> bool) -> Option<pci::Address> {
+    let mut iter = pci::devices();
+    while let Some(addr) = iter.next() {
+        if is_nvme(addr) && !skip(addr) {
+            return Some(addr);
+        }
+    }
+    None
+}
+
+fn wait_ready(regs: u32, ready: bool) -> Result<(), ErrorStr> {
+    for _ in 0..POLL_LIMIT {
+        let status = io::mmio_read32(regs + REG_CSTS);
+        if status & CSTS_CFS != 0 {
+            return Err(ErrorStr::new(strlit!("NVMe controller fatal status")));
+        }
+        if (status & CSTS_RDY != 0) == ready {
+            return Ok(());
+        }
+    }
+    Err(ErrorStr::new(strlit!("NVMe controller timed out")))
+}
+
+// Returns the namespace's size in sectors, or None if it is inactive or does
+// not use 512-byte LBAs.
+fn namespace_sectors(data: &[u8]) -> Option<u64> {
+    let size = get32(data, 0) as u64 | ((get32(data, 4) as u64) << 32);
+    let format = (data[26] & 0xf) as usize;
+    let lba_shift = data[128 + format * 4 + 2];
+    if size == 0 || lba_shift != 9 {
+        None
+    } else {
+        Some(size)
+    }
+}
+
+// Initialize the NVMe controller at the given PCI address and open its first
+// usable namespace.
+pub fn open(addr: pci::Address) -> Result<NvmeDisk, ErrorStr> {
+    let regs = match pci::memory_bar(addr, 0) {
+        None => return Err(ErrorStr::new(strlit!("NVMe controller has no usable BAR"))),
+        Some(base) => base,
+    };
+    pci::enable_device(addr);
+
+    let cap_lo = io::mmio_read32(regs + REG_CAP);
+    let cap_hi = io::mmio_read32(regs + REG_CAP + 4);
+    let max_entries = (cap_lo & 0xffff) + 1;
+    let doorbell_stride = 4 << (cap_hi & 0xf);
+    if (cap_hi >> 16) & 0xf != 0 {
+        return Err(ErrorStr::new(strlit!("NVMe controller does not support 4KiB pages")));
+    }
+
+    // Reset the controller and point it at the admin queues.
+    io::mmio_write32(regs + REG_CC, io::mmio_read32(regs + REG_CC) & !CC_EN);
+    try!(wait_ready(regs, false));
+
+    let io_entries = if max_entries < MAX_IO_QUEUE_ENTRIES as u32 {
+        max_entries as u16
+    } else {
+        MAX_IO_QUEUE_ENTRIES
+    };
+    let buffers = buffers();
+    let mut disk = NvmeDisk {
+        pci: addr,
+        namespace: 0,
+        sector_count: 0,
+        regs: regs,
+        doorbell_stride: doorbell_stride,
+        admin: Queue::new(0, ADMIN_QUEUE_ENTRIES, buffers.admin_submission,
+                          buffers.admin_completion),
+        io: Queue::new(IO_QUEUE_ID, io_entries, buffers.io_submission,
+                       buffers.io_completion),
+        bounce: buffers.bounce,
+        command_id: Cell::new(0),
+    };
+    let admin_size = ADMIN_QUEUE_ENTRIES as u32 - 1;
+    disk.write_reg(REG_AQA, (admin_size << 16) | admin_size);
+    disk.write_reg(REG_ASQ, disk.admin.submission);
+    disk.write_reg(REG_ASQ + 4, 0);
+    disk.write_reg(REG_ACQ, disk.admin.completion);
+    disk.write_reg(REG_ACQ + 4, 0);
+    disk.write_reg(REG_CC, CC_EN | CC_IOSQES | CC_IOCQES);
+    try!(wait_ready(regs, true));
+
+    let identify = buffers.identify;
+    try!(disk.identify(IDENTIFY_CONTROLLER, 0, identify));
+    let namespace_count = cmp::min(get32(dma::as_slice(identify, PAGE_SIZE), 516),
+                                   MAX_NAMESPACES);
+
+    // Create the I/O completion queue, then the submission queue that posts
+    // to it.  Both are physically contiguous, with interrupts disabled.
+    let io_size = (io_entries as u32 - 1) << 16;
+    try!(disk.submit(&disk.admin, &Command {
+        opcode: ADMIN_CREATE_IO_CQ, namespace: 0, prp1: disk.io.completion, prp2: 0,
+        cdw10: io_size | IO_QUEUE_ID as u32, cdw11: 1, cdw12: 0,
+    }));
+    try!(disk.submit(&disk.admin, &Command {
+        opcode: ADMIN_CREATE_IO_SQ, namespace: 0, prp1: disk.io.submission, prp2: 0,
+        cdw10: io_size | IO_QUEUE_ID as u32, cdw11: ((IO_QUEUE_ID as u32) << 16) | 1,
+        cdw12: 0,
+    }));
+
+    for namespace in 1..namespace_count + 1 {
+        try!(disk.identify(IDENTIFY_NAMESPACE, namespace, identify));
+        if let Some(sectors) = namespace_sectors(dma::as_slice(identify, PAGE_SIZE)) {
+            disk.namespace = namespace;
+            disk.sector_count = sectors;
+            return Ok(disk);
+        }
+    }
+    Err(ErrorStr::new(strlit!("no NVMe namespace with 512-byte sectors")))
+}
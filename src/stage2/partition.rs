@@ -0,0 +1,212 @@
+// MBR partition table parsing.
+//
+// The MBR holds four primary entries.  One of them may be an extended
+// partition, which contains a chain of extended boot records (EBRs).  Each EBR
+// describes one logical partition, relative to the EBR itself, and links to
+// the next EBR, relative to the start of the extended partition.
+//
+// Partitions are numbered the way Linux numbers them: primary entries are 1-4
+// (by table slot), and logical partitions start at 5.
+//
+// Partition tables come from the disk, so every entry is checked against the
+// bounds of its container, and the EBR chain is checked for loops.
+
+use sys;
+use sys::{ErrorStr, SectorIndex, SECTOR_SIZE};
+
+const TABLE_OFFSET: usize = 446;
+const ENTRY_SIZE: usize = 16;
+const PRIMARY_COUNT: usize = 4;
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+pub const TYPE_EMPTY: u8 = 0x00;
+pub const TYPE_GPT_PROTECTIVE: u8 = 0xee;
+
+#[derive(Copy, Clone)]
+pub struct Partition {
+    pub number: u32,
+    pub type_id: u8,
+    pub bootable: bool,
+    pub start_lba: SectorIndex,     // absolute
+    pub sector_count: u32,
+}
+
+impl Partition {
+    pub fn is_extended(&self) -> bool {
+        is_extended_type(self.type_id)
+    }
+}
+
+fn is_extended_type(type_id: u8) -> bool {
+    type_id == 0x05 || type_id == 0x0f || type_id == 0x85
+}
+
+// A raw table entry, with its start relative to some base LBA.
+struct Entry {
+    status: u8,
+    type_id: u8,
+    start: u32,
+    sector_count: u32,
+}
+
+fn read_entry(sector: &[u8; SECTOR_SIZE], index: usize) -> Entry {
+    let offset = TABLE_OFFSET + index * ENTRY_SIZE;
+    Entry {
+        status: sector[offset],
+        type_id: sector[offset + 4],
+        start: sys::get32(sector, offset + 8),
+        sector_count: sys::get32(sector, offset + 12),
+    }
+}
+
+fn has_signature(sector: &[u8; SECTOR_SIZE]) -> bool {
+    sector[510] == 0x55 && sector[511] == 0xaa
+}
+
+// Resolve `start` relative to `base` and check that the partition fits in the
+// container [container_start, container_end).
+fn resolve(entry: &Entry, base: SectorIndex, container_start: u64, container_end: u64) ->
+        Result<SectorIndex, ErrorStr> {
+    let start = base as u64 + entry.start as u64;
+    let end = start + entry.sector_count as u64;
+    if entry.sector_count == 0 || start < container_start || end > container_end {
+        return Err(ErrorStr::new(strlit!("partition entry is out of bounds")));
+    }
+    Ok(start as SectorIndex)
+}
+
+pub struct PartitionIterator<'a> {
+    device: &'a sys::BlockDevice,
+    disk_end: u64,
+    mbr: [u8; SECTOR_SIZE],
+    primary_index: usize,
+    extended: Option<Partition>,
+    next_ebr: Option<SectorIndex>,
+    visited: [SectorIndex; MAX_LOGICAL_PARTITIONS],
+    visited_count: usize,
+    logical_number: u32,
+}
+
+impl<'a> PartitionIterator<'a> {
+    fn next_primary(&mut self) -> Result<Option<Partition>, ErrorStr> {
+        while self.primary_index < PRIMARY_COUNT {
+            let index = self.primary_index;
+            self.primary_index += 1;
+            let entry = read_entry(&self.mbr, index);
+            if entry.type_id == TYPE_EMPTY {
+                continue;
+            }
+            // Protective entries often claim 0xffffffff sectors regardless of
+            // the disk size, so they are exempt from the bounds check.
+            let start = if entry.type_id == TYPE_GPT_PROTECTIVE {
+                entry.start
+            } else {
+                try!(resolve(&entry, 0, 1, self.disk_end))
+            };
+            let partition = Partition {
+                number: index as u32 + 1,
+                type_id: entry.type_id,
+                bootable: entry.status & 0x80 != 0,
+                start_lba: start,
+                sector_count: entry.sector_count,
+            };
+            if partition.is_extended() {
+                if self.extended.is_none() {
+                    self.extended = Some(partition);
+                    self.next_ebr = Some(start);
+                } else {
+                    log_warn!("ignoring extra extended partition {}", partition.number);
+                }
+            }
+            return Ok(Some(partition));
+        }
+        Ok(None)
+    }
+
+    fn next_logical(&mut self) -> Result<Option<Partition>, ErrorStr> {
+        let extended = match self.extended {
+            None => return Ok(None),
+            Some(extended) => extended,
+        };
+        let extended_start = extended.start_lba as u64;
+        let extended_end = extended_start + extended.sector_count as u64;
+
+        // An EBR whose first entry is empty only links to the next EBR, so
+        // keep walking until a logical partition turns up.
+        while let Some(ebr_lba) = self.next_ebr {
+            self.next_ebr = None;
+            if self.visited[..self.visited_count].contains(&ebr_lba) {
+                return Err(ErrorStr::new(strlit!("EBR chain contains a loop")));
+            }
+            if self.visited_count == MAX_LOGICAL_PARTITIONS {
+                return Err(ErrorStr::new(strlit!("EBR chain is too long")));
+            }
+            self.visited[self.visited_count] = ebr_lba;
+            self.visited_count += 1;
+
+            let mut ebr = [0u8; SECTOR_SIZE];
+            try!(self.device.read_sectors(ebr_lba, &mut ebr));
+            if !has_signature(&ebr) {
+                return Err(ErrorStr::new(strlit!("EBR is missing its signature")));
+            }
+
+            let link = read_entry(&ebr, 1);
+            if link.type_id != TYPE_EMPTY {
+                if !is_extended_type(link.type_id) {
+                    return Err(ErrorStr::new(strlit!("bad EBR link entry")));
+                }
+                self.next_ebr = Some(try!(resolve(&link, extended.start_lba,
+                                                  extended_start, extended_end)));
+            }
+
+            let entry = read_entry(&ebr, 0);
+            if entry.type_id == TYPE_EMPTY {
+                continue;
+            }
+            // A logical partition may not overlap its own EBR.
+            let start = try!(resolve(&entry, ebr_lba, ebr_lba as u64 + 1, extended_end));
+            let partition = Partition {
+                number: self.logical_number,
+                type_id: entry.type_id,
+                bootable: entry.status & 0x80 != 0,
+                start_lba: start,
+                sector_count: entry.sector_count,
+            };
+            self.logical_number += 1;
+            return Ok(Some(partition));
+        }
+        Ok(None)
+    }
+
+    pub fn next(&mut self) -> Result<Option<Partition>, ErrorStr> {
+        if let Some(partition) = try!(self.next_primary()) {
+            return Ok(Some(partition));
+        }
+        self.next_logical()
+    }
+}
+
+// Read the MBR of the device.  `disk_sectors` bounds the partitions if the
+// disk size is known.
+pub fn partitions<'a>(device: &'a sys::BlockDevice, disk_sectors: Option<u64>) ->
+        Result<PartitionIterator<'a>, ErrorStr> {
+    if device.sector_size() != SECTOR_SIZE {
+        return Err(ErrorStr::new(strlit!("MBR partitions need 512-byte sectors")));
+    }
+    let mut mbr = [0u8; SECTOR_SIZE];
+    try!(device.read_sectors(0, &mut mbr));
+    if !has_signature(&mbr) {
+        return Err(ErrorStr::new(strlit!("MBR is missing its signature")));
+    }
+    Ok(PartitionIterator {
+        device: device,
+        disk_end: disk_sectors.unwrap_or(1 << 32),
+        mbr: mbr,
+        primary_index: 0,
+        extended: None,
+        next_ebr: None,
+        visited: [0; MAX_LOGICAL_PARTITIONS],
+        visited_count: 0,
+        logical_number: 5,
+    })
+}
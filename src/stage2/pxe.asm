@@ -0,0 +1,56 @@
+        section .text16
+        bits 16
+
+
+        ;
+        ; Locate the PXENV+ structure using INT 1Ah/5650h.
+        ;
+        ; Return: the PXENV+ far pointer (segment << 16 | offset), or 0 if
+        ;         there is no PXE stack.
+        ;
+        global pxe_find_16bit
+pxe_find_16bit:
+        mov ax, 0x5650
+        int 0x1a
+        jc .fail
+        cmp ax, 0x564e
+        jne .fail
+        mov ax, es
+        shl eax, 16
+        mov ax, bx
+        ret
+.fail:
+        xor eax, eax
+        ret
+
+
+        ;
+        ; Call the PXE API.
+        ;
+        ; Arguments:
+        ; [bp+0] entry: far pointer to the API entry point
+        ; [bp+4] opcode: u16
+        ; [bp+8] params: far pointer to the parameter structure
+        ; [bp+12] style: 1 to use the !PXE calling convention (parameters on
+        ;         the stack), 0 to use the PXENV+ convention (BX and ES:DI)
+        ;
+        ; Return: the PXENV_EXIT code in AX (0 on success)
+        ;
+        global pxe_call_16bit
+pxe_call_16bit:
+        cmp byte [bp + 12], 0
+        je .pxenv
+
+        push word [bp + 10]             ; params segment
+        push word [bp + 8]              ; params offset
+        push word [bp + 4]              ; opcode
+        call far [bp + 0]
+        add sp, 6
+        ret
+
+.pxenv:
+        mov bx, [bp + 4]
+        mov di, [bp + 8]
+        mov es, [bp + 10]
+        call far [bp + 0]
+        ret
@@ -0,0 +1,300 @@
+// PXE network boot support.
+//
+// When pcboot is booted as a PXE network bootstrap program (see
+// boot_records/pxe_stub.asm), the PXE ROM's API stays resident.  We find it
+// with INT 1Ah/5650h, read the cached DHCP reply to learn our own address and
+// the boot server's, and download files with the PXE TFTP services.
+//
+// All API calls are made in real mode, so parameter blocks and packet buffers
+// live below 0x80000.  Each TFTP packet is copied from a bounce buffer to the
+// caller's buffer, which may be anywhere in memory.
+
+use core::cmp;
+use core::mem;
+use core::slice;
+
+use sys;
+use sys::{addr_linear_to_segmented, call_real_mode, copy_bytes, ErrorStr};
+
+extern "C" {
+    fn pxe_find_16bit();
+    fn pxe_call_16bit();
+}
+
+// Passed to stage2 in DL by the PXE stub instead of a BIOS disk number.
+pub const NETWORK_BOOT_DISK: u8 = 0xff;
+
+const PXENV_TFTP_OPEN: u16          = 0x0020;
+const PXENV_TFTP_CLOSE: u16         = 0x0021;
+const PXENV_TFTP_READ: u16          = 0x0022;
+const PXENV_TFTP_GET_FSIZE: u16     = 0x0025;
+const PXENV_GET_CACHED_INFO: u16    = 0x0071;
+
+const PXENV_STATUS_TFTP_FILE_NOT_FOUND: u16 = 0x003b;
+
+const PACKET_TYPE_DHCP_ACK: u16     = 2;
+const PACKET_TYPE_CACHED_REPLY: u16 = 3;
+
+const TFTP_PORT: u16 = 69;
+const TFTP_PACKET_SIZE: u16 = 1432;
+const MAX_FILE_NAME: usize = 128;
+
+// BOOTP packet offsets.
+const BOOTP_YIADDR: usize = 16;
+const BOOTP_SIADDR: usize = 20;
+const BOOTP_GIADDR: usize = 24;
+const BOOTP_FILE: usize = 108;
+const BOOTP_MIN_SIZE: usize = 236;
+
+#[repr(C, packed)]
+struct GetCachedInfo {
+    status: u16,
+    packet_type: u16,
+    buffer_size: u16,
+    buffer: u32,            // far pointer; 0 to use the ROM's buffer
+    buffer_limit: u16,
+}
+
+#[repr(C, packed)]
+struct TftpOpen {
+    status: u16,
+    server_ip: [u8; 4],
+    gateway_ip: [u8; 4],
+    file_name: [u8; MAX_FILE_NAME],
+    port: u16,              // big-endian
+    packet_size: u16,
+}
+
+#[repr(C, packed)]
+struct TftpRead {
+    status: u16,
+    packet_number: u16,
+    buffer_size: u16,
+    buffer: u32,            // far pointer
+}
+
+#[repr(C, packed)]
+struct TftpGetFsize {
+    status: u16,
+    server_ip: [u8; 4],
+    gateway_ip: [u8; 4],
+    file_name: [u8; MAX_FILE_NAME],
+    file_size: u32,
+}
+
+static mut PACKET_BUFFER: [u8; TFTP_PACKET_SIZE as usize] = [0u8; TFTP_PACKET_SIZE as usize];
+
+pub struct Pxe {
+    entry: u32,             // far pointer to the API entry point
+    bang_pxe: bool,         // true to use the !PXE calling convention
+    pub client_ip: [u8; 4],
+    pub server_ip: [u8; 4],
+    pub gateway_ip: [u8; 4],
+    boot_file: [u8; MAX_FILE_NAME],
+}
+
+fn far_to_linear(far: u32) -> u32 {
+    ((far >> 16) << 4) + (far & 0xffff)
+}
+
+fn checksum_ok(addr: u32, len: usize) -> bool {
+    let bytes = unsafe { slice::from_raw_parts(addr as *const u8, len) };
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) == 0
+}
+
+fn bytes_at(addr: u32, len: usize) -> &'static [u8] {
+    unsafe { slice::from_raw_parts(addr as *const u8, len) }
+}
+
+fn read16(addr: u32) -> u16 {
+    unsafe { *(addr as *const u16) }
+}
+
+fn read32(addr: u32) -> u32 {
+    unsafe { *(addr as *const u32) }
+}
+
+fn copy_ip(packet: &[u8], offset: usize) -> [u8; 4] {
+    [packet[offset], packet[offset + 1], packet[offset + 2], packet[offset + 3]]
+}
+
+pub struct IpAddr<'a>(pub &'a [u8; 4]);
+
+impl<'a> ::core::fmt::Display for IpAddr<'a> {
+    fn fmt(&self, f: &mut ::core::fmt::Formatter) -> ::core::fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+impl Pxe {
+    fn call<T>(&self, opcode: u16, params: &mut T) -> Result<(), ErrorStr> {
+        let params_ptr = addr_linear_to_segmented(params as *mut T as u32);
+        let exit = unsafe {
+            call_real_mode(pxe_call_16bit,
+                           self.entry,
+                           opcode as u32,
+                           params_ptr,
+                           self.bang_pxe as u32) as u16
+        };
+        // Every parameter structure starts with a status word.
+        let status = unsafe { *(params as *mut T as *const u16) };
+        if exit != 0 || status != 0 {
+            log_debug!("PXE call 0x{:x} failed: exit {}, status 0x{:x}", opcode, exit, status);
+            return Err(ErrorStr::new(strlit!("PXE API call failed")));
+        }
+        Ok(())
+    }
+
+    // The boot file name from the DHCP reply (e.g. "pxe/pcboot.pxe").
+    pub fn boot_file(&self) -> &[u8] {
+        let end = self.boot_file.iter().position(|c| *c == 0).unwrap_or(MAX_FILE_NAME);
+        &self.boot_file[..end]
+    }
+
+    // Resolve a path relative to the directory containing the boot file.
+    // Paths starting with '/' are relative to the TFTP root instead.
+    fn resolve(&self, path: &[u8], out: &mut [u8; MAX_FILE_NAME]) -> Result<(), ErrorStr> {
+        let mut len = 0;
+        let mut path = path;
+        if !path.is_empty() && path[0] == b'/' {
+            path = &path[1..];
+        } else {
+            let boot_file = self.boot_file();
+            if let Some(slash) = boot_file.iter().rposition(|c| *c == b'/') {
+                for ch in boot_file[..slash + 1].iter() {
+                    out[len] = *ch;
+                    len += 1;
+                }
+            }
+        }
+        if len + path.len() >= MAX_FILE_NAME {
+            return Err(ErrorStr::new(strlit!("TFTP path is too long")));
+        }
+        for ch in path.iter() {
+            out[len] = *ch;
+            len += 1;
+        }
+        for ch in out[len..].iter_mut() {
+            *ch = 0;
+        }
+        Ok(())
+    }
+}
+
+impl sys::FileSource for Pxe {
+    fn file_size(&self, path: &[u8]) -> Result<Option<u32>, ErrorStr> {
+        let mut params: TftpGetFsize = unsafe { mem::zeroed() };
+        params.server_ip = self.server_ip;
+        params.gateway_ip = self.gateway_ip;
+        try!(self.resolve(path, &mut params.file_name));
+        match self.call(PXENV_TFTP_GET_FSIZE, &mut params) {
+            Ok(()) => Ok(Some(params.file_size)),
+            // Timeouts and unreachable servers are errors, not missing files.
+            Err(_) if params.status == PXENV_STATUS_TFTP_FILE_NOT_FOUND => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_file(&self, path: &[u8], buffer: &mut [u8]) -> Result<u32, ErrorStr> {
+        let mut open: TftpOpen = unsafe { mem::zeroed() };
+        open.server_ip = self.server_ip;
+        open.gateway_ip = self.gateway_ip;
+        try!(self.resolve(path, &mut open.file_name));
+        open.port = TFTP_PORT.to_be();
+        open.packet_size = TFTP_PACKET_SIZE;
+        try!(self.call(PXENV_TFTP_OPEN, &mut open));
+        let packet_size = open.packet_size as usize;
+
+        let mut result = Ok(0);
+        let mut size = 0;
+        loop {
+            let packet = unsafe { &mut PACKET_BUFFER };
+            let mut read: TftpRead = unsafe { mem::zeroed() };
+            read.buffer_size = packet.len() as u16;
+            read.buffer = addr_linear_to_segmented(packet.as_mut_ptr() as u32);
+            if let Err(err) = self.call(PXENV_TFTP_READ, &mut read) {
+                result = Err(err);
+                break;
+            }
+            let count = read.buffer_size as usize;
+            if size + count > buffer.len() {
+                result = Err(ErrorStr::new(strlit!("file is too large for the buffer")));
+                break;
+            }
+            copy_bytes(&mut buffer[size..size + count], &packet);
+            size += count;
+            if count < packet_size {
+                result = Ok(size as u32);
+                break;
+            }
+        }
+
+        let mut close: u16 = 0;
+        let _ = self.call(PXENV_TFTP_CLOSE, &mut close);
+        result
+    }
+}
+
+// Find the PXE API, preferring the !PXE structure (PXE 2.1+) over PXENV+.
+fn find_entry_point() -> Result<(u32, bool), ErrorStr> {
+    let pxenv_far = unsafe { call_real_mode(pxe_find_16bit) as u32 };
+    if pxenv_far == 0 {
+        return Err(ErrorStr::new(strlit!("PXE API not found")));
+    }
+    let pxenv = far_to_linear(pxenv_far);
+    let pxenv_len = bytes_at(pxenv, 9)[8] as usize;
+    if bytes_at(pxenv, 6) != &b"PXENV+"[..] || !checksum_ok(pxenv, pxenv_len) {
+        return Err(ErrorStr::new(strlit!("bad PXENV+ structure")));
+    }
+
+    if read16(pxenv + 6) >= 0x0201 {
+        let bang_pxe = far_to_linear(read32(pxenv + 0x28));
+        let bang_pxe_len = bytes_at(bang_pxe, 5)[4] as usize;
+        if bytes_at(bang_pxe, 4) == &b"!PXE"[..] && checksum_ok(bang_pxe, bang_pxe_len) {
+            return Ok((read32(bang_pxe + 0x10), true));
+        }
+        log_warn!("bad !PXE structure; using PXENV+");
+    }
+    Ok((read32(pxenv + 0x0a), false))
+}
+
+pub fn init() -> Result<Pxe, ErrorStr> {
+    let (entry, bang_pxe) = try!(find_entry_point());
+    let mut pxe = Pxe {
+        entry: entry,
+        bang_pxe: bang_pxe,
+        client_ip: [0; 4],
+        server_ip: [0; 4],
+        gateway_ip: [0; 4],
+        boot_file: [0; MAX_FILE_NAME],
+    };
+
+    // The cached reply is the proxyDHCP/boot server reply, if there was one;
+    // otherwise, the DHCP ACK has everything.
+    let mut packet = None;
+    for packet_type in [PACKET_TYPE_CACHED_REPLY, PACKET_TYPE_DHCP_ACK].iter() {
+        let mut params: GetCachedInfo = unsafe { mem::zeroed() };
+        params.packet_type = *packet_type;
+        if pxe.call(PXENV_GET_CACHED_INFO, &mut params).is_ok() &&
+                params.buffer_size as usize >= BOOTP_MIN_SIZE {
+            let bytes = bytes_at(far_to_linear(params.buffer), params.buffer_size as usize);
+            let has_server = copy_ip(bytes, BOOTP_SIADDR) != [0; 4];
+            match packet {
+                Some((_, true)) => {},
+                Some((_, false)) if !has_server => {},
+                _ => packet = Some((bytes, has_server)),
+            }
+        }
+    }
+    let ack = match packet {
+        None => return Err(ErrorStr::new(strlit!("no cached DHCP reply"))),
+        Some((bytes, _)) => bytes,
+    };
+    pxe.client_ip = copy_ip(ack, BOOTP_YIADDR);
+    pxe.server_ip = copy_ip(ack, BOOTP_SIADDR);
+    pxe.gateway_ip = copy_ip(ack, BOOTP_GIADDR);
+    let file = &ack[BOOTP_FILE..BOOTP_FILE + MAX_FILE_NAME];
+    let len = cmp::min(file.len(), MAX_FILE_NAME - 1);
+    copy_bytes(&mut pxe.boot_file[..len], file);
+    Ok(pxe)
+}
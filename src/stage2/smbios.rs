@@ -0,0 +1,444 @@
+// SMBIOS (DMI) table parsing.
+//
+// The BIOS publishes an SMBIOS entry point structure on a 16-byte boundary in
+// the 0xF0000-0xFFFFF range.  The 2.x entry point ("_SM_") has a 32-bit
+// table address and a structure count; the 3.x entry point ("_SM3_") has a
+// 64-bit address and only a maximum table size.  The table itself is a
+// sequence of structures, each a formatted area followed by a set of
+// NUL-terminated strings that the formatted area refers to by 1-based index.
+//
+// Everything here refers directly to firmware memory, which stays valid for
+// as long as pcboot runs.
+
+use core;
+
+const SCAN_START: u32 = 0xf0000;
+const SCAN_END: u32 = 0x100000;
+
+pub const TYPE_BIOS: u8             = 0;
+pub const TYPE_SYSTEM: u8           = 1;
+pub const TYPE_BASEBOARD: u8        = 2;
+pub const TYPE_PROCESSOR: u8        = 4;
+pub const TYPE_MEMORY_DEVICE: u8    = 17;
+pub const TYPE_END_OF_TABLE: u8     = 127;
+
+unsafe fn read8(addr: u32) -> u8 {
+    core::ptr::read(addr as *const u8)
+}
+
+unsafe fn read16(addr: u32) -> u16 {
+    core::ptr::read(addr as *const u16)
+}
+
+unsafe fn read32(addr: u32) -> u32 {
+    core::ptr::read(addr as *const u32)
+}
+
+unsafe fn read64(addr: u32) -> u64 {
+    core::ptr::read(addr as *const u64)
+}
+
+unsafe fn memory(addr: u32, len: u32) -> &'static [u8] {
+    core::slice::from_raw_parts(addr as *const u8, len as usize)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    let mut sum = 0u8;
+    for b in bytes.iter() {
+        sum = sum.wrapping_add(*b);
+    }
+    sum == 0
+}
+
+#[derive(Copy, Clone)]
+pub struct EntryPoint {
+    pub addr: u32,              // address of the entry point structure
+    pub major: u8,
+    pub minor: u8,
+    pub table_addr: u32,
+    pub table_len: u32,         // exact (2.x) or maximum (3.x) table size
+    pub structure_count: Option<u16>,   // 2.x only
+}
+
+unsafe fn parse_entry_point_3(addr: u32) -> Option<EntryPoint> {
+    let len = read8(addr + 6) as u32;
+    if len < 0x18 || !checksum_ok(memory(addr, len)) {
+        return None;
+    }
+    let table_addr = read64(addr + 16);
+    if table_addr > 0xffff_ffff {
+        return None;
+    }
+    Some(EntryPoint {
+        addr: addr,
+        major: read8(addr + 7),
+        minor: read8(addr + 8),
+        table_addr: table_addr as u32,
+        table_len: read32(addr + 12),
+        structure_count: None,
+    })
+}
+
+unsafe fn parse_entry_point_2(addr: u32) -> Option<EntryPoint> {
+    // The length is 0x1f, but SMBIOS 2.1 wrongly specified 0x1e, and some
+    // BIOSes report that.
+    let len = read8(addr + 5) as u32;
+    if len < 0x1e || !checksum_ok(memory(addr, len)) {
+        return None;
+    }
+    // The intermediate "_DMI_" entry point has its own checksum.
+    if memory(addr + 16, 5) != &b"_DMI_"[..] || !checksum_ok(memory(addr + 16, 15)) {
+        return None;
+    }
+    Some(EntryPoint {
+        addr: addr,
+        major: read8(addr + 6),
+        minor: read8(addr + 7),
+        table_addr: read32(addr + 24),
+        table_len: read16(addr + 22) as u32,
+        structure_count: Some(read16(addr + 28)),
+    })
+}
+
+// Locate the SMBIOS entry point.  The 3.x entry point is preferred when the
+// table lies below 4GiB.
+pub fn find() -> Option<EntryPoint> {
+    let mut found_2: Option<EntryPoint> = None;
+    let mut addr = SCAN_START;
+    while addr < SCAN_END {
+        unsafe {
+            if memory(addr, 5) == &b"_SM3_"[..] {
+                let entry = parse_entry_point_3(addr);
+                if entry.is_some() {
+                    return entry;
+                }
+            } else if found_2.is_none() && memory(addr, 4) == &b"_SM_"[..] {
+                found_2 = parse_entry_point_2(addr);
+            }
+        }
+        addr += 16;
+    }
+    found_2
+}
+
+#[derive(Copy, Clone)]
+pub struct Structure {
+    pub kind: u8,
+    pub handle: u16,
+    addr: u32,
+    len: u8,                    // length of the formatted area
+    strings: u32,               // address of the string-set
+    end: u32,                   // address past the string-set
+}
+
+impl Structure {
+    pub fn byte(&self, offset: u8) -> u8 {
+        if offset as u32 + 1 > self.len as u32 { return 0; }
+        unsafe { read8(self.addr + offset as u32) }
+    }
+
+    pub fn word(&self, offset: u8) -> u16 {
+        if offset as u32 + 2 > self.len as u32 { return 0; }
+        unsafe { read16(self.addr + offset as u32) }
+    }
+
+    pub fn dword(&self, offset: u8) -> u32 {
+        if offset as u32 + 4 > self.len as u32 { return 0; }
+        unsafe { read32(self.addr + offset as u32) }
+    }
+
+    pub fn bytes(&self, offset: u8, count: u8) -> Option<&'static [u8]> {
+        if offset as u32 + count as u32 > self.len as u32 { return None; }
+        unsafe { Some(memory(self.addr + offset as u32, count as u32)) }
+    }
+
+    // Return the string referenced by the string-number byte at the given
+    // offset.  Missing strings are returned as an empty slice.
+    pub fn string(&self, offset: u8) -> &'static [u8] {
+        let mut index = self.byte(offset);
+        if index == 0 {
+            return b"";
+        }
+        let mut start = self.strings;
+        let mut p = self.strings;
+        while p < self.end {
+            if unsafe { read8(p) } == 0 {
+                if p == start {
+                    break;
+                }
+                index -= 1;
+                if index == 0 {
+                    return unsafe { memory(start, p - start) };
+                }
+                start = p + 1;
+            }
+            p += 1;
+        }
+        b""
+    }
+}
+
+pub struct StructureIterator {
+    next: u32,
+    end: u32,
+    remaining: Option<u16>,
+}
+
+impl StructureIterator {
+    pub fn next(&mut self) -> Option<Structure> {
+        if self.remaining == Some(0) || self.next + 4 > self.end {
+            return None;
+        }
+        let addr = self.next;
+        let (kind, len, handle) = unsafe {
+            (read8(addr), read8(addr + 1), read16(addr + 2))
+        };
+        if kind == TYPE_END_OF_TABLE || len < 4 || addr + len as u32 > self.end {
+            return None;
+        }
+
+        // The string-set ends with two NUL bytes.  A structure with no
+        // strings still has both.
+        let strings = addr + len as u32;
+        let mut p = strings;
+        while p + 1 < self.end && unsafe { read16(p) } != 0 {
+            p += 1;
+        }
+        if p + 1 >= self.end {
+            return None;
+        }
+        self.next = p + 2;
+        self.remaining = self.remaining.map(|count| count - 1);
+
+        Some(Structure {
+            kind: kind,
+            handle: handle,
+            addr: addr,
+            len: len,
+            strings: strings,
+            end: p + 2,
+        })
+    }
+}
+
+pub fn structures(entry: &EntryPoint) -> StructureIterator {
+    StructureIterator {
+        next: entry.table_addr,
+        end: entry.table_addr + entry.table_len,
+        remaining: entry.structure_count,
+    }
+}
+
+pub fn find_structure(entry: &EntryPoint, kind: u8) -> Option<Structure> {
+    let mut it = structures(entry);
+    loop {
+        match it.next() {
+            None => { return None; },
+            Some(s) => {
+                if s.kind == kind {
+                    return Some(s);
+                }
+            }
+        }
+    }
+}
+
+// Type 0
+pub struct BiosInfo {
+    pub vendor: &'static [u8],
+    pub version: &'static [u8],
+    pub release_date: &'static [u8],
+}
+
+pub fn bios_info(s: &Structure) -> BiosInfo {
+    BiosInfo {
+        vendor: s.string(0x04),
+        version: s.string(0x05),
+        release_date: s.string(0x08),
+    }
+}
+
+// Type 1
+pub struct SystemInfo {
+    pub manufacturer: &'static [u8],
+    pub product: &'static [u8],
+    pub version: &'static [u8],
+    pub serial_number: &'static [u8],
+    pub uuid: Option<&'static [u8]>,        // 16 bytes, SMBIOS 2.1+
+    pub sku: &'static [u8],                 // SMBIOS 2.4+
+    pub family: &'static [u8],              // SMBIOS 2.4+
+}
+
+pub fn system_info(s: &Structure) -> SystemInfo {
+    SystemInfo {
+        manufacturer: s.string(0x04),
+        product: s.string(0x05),
+        version: s.string(0x06),
+        serial_number: s.string(0x07),
+        uuid: s.bytes(0x08, 16),
+        sku: s.string(0x19),
+        family: s.string(0x1a),
+    }
+}
+
+// Type 2
+pub struct BaseboardInfo {
+    pub manufacturer: &'static [u8],
+    pub product: &'static [u8],
+    pub version: &'static [u8],
+    pub serial_number: &'static [u8],
+}
+
+pub fn baseboard_info(s: &Structure) -> BaseboardInfo {
+    BaseboardInfo {
+        manufacturer: s.string(0x04),
+        product: s.string(0x05),
+        version: s.string(0x06),
+        serial_number: s.string(0x07),
+    }
+}
+
+// Type 4
+pub struct ProcessorInfo {
+    pub socket: &'static [u8],
+    pub manufacturer: &'static [u8],
+    pub version: &'static [u8],
+    pub max_speed_mhz: u16,
+    pub current_speed_mhz: u16,
+    pub core_count: u8,                     // SMBIOS 2.5+, 0 if unknown
+    pub thread_count: u8,                   // SMBIOS 2.5+, 0 if unknown
+}
+
+pub fn processor_info(s: &Structure) -> ProcessorInfo {
+    ProcessorInfo {
+        socket: s.string(0x04),
+        manufacturer: s.string(0x07),
+        version: s.string(0x10),
+        max_speed_mhz: s.word(0x14),
+        current_speed_mhz: s.word(0x16),
+        core_count: s.byte(0x23),
+        thread_count: s.byte(0x25),
+    }
+}
+
+// Type 17
+pub struct MemoryDeviceInfo {
+    pub size_mib: Option<u32>,              // None if unknown; Some(0) if empty
+    pub device_locator: &'static [u8],
+    pub bank_locator: &'static [u8],
+    pub memory_type: u8,
+    pub speed_mts: u16,                     // 0 if unknown
+    pub manufacturer: &'static [u8],
+    pub serial_number: &'static [u8],
+    pub part_number: &'static [u8],
+}
+
+pub fn memory_device_info(s: &Structure) -> MemoryDeviceInfo {
+    let size = s.word(0x0c);
+    let size_mib = if size == 0xffff {
+        None
+    } else if size == 0x7fff {
+        // SMBIOS 2.7+: the real size is in the Extended Size field.
+        Some(s.dword(0x1c) & 0x7fff_ffff)
+    } else if size & 0x8000 != 0 {
+        // Granularity is KiB.
+        Some(((size & 0x7fff) as u32) / 1024)
+    } else {
+        Some(size as u32)
+    };
+    MemoryDeviceInfo {
+        size_mib: size_mib,
+        device_locator: s.string(0x10),
+        bank_locator: s.string(0x11),
+        memory_type: s.byte(0x12),
+        speed_mts: s.word(0x15),
+        manufacturer: s.string(0x17),
+        serial_number: s.string(0x18),
+        part_number: s.string(0x1a),
+    }
+}
+
+// The system-information screen.
+pub fn print_summary(entry: &EntryPoint) {
+    use console::print_bytes;
+
+    println!("SMBIOS {}.{} at 0x{:x}", entry.major, entry.minor, entry.table_addr);
+
+    let mut it = structures(entry);
+    loop {
+        let s = match it.next() { None => break, Some(s) => s };
+        match s.kind {
+            TYPE_BIOS => {
+                let info = bios_info(&s);
+                print!("BIOS: ");
+                print_bytes(info.vendor);
+                print!(" ");
+                print_bytes(info.version);
+                print!(" (");
+                print_bytes(info.release_date);
+                println!(")");
+            },
+            TYPE_SYSTEM => {
+                let info = system_info(&s);
+                print!("System: ");
+                print_bytes(info.manufacturer);
+                print!(" ");
+                print_bytes(info.product);
+                print!(" ");
+                print_bytes(info.version);
+                println!("");
+                if let Some(uuid) = info.uuid {
+                    // Since SMBIOS 2.6, the first three UUID fields are
+                    // little-endian.  Earlier versions store them in network
+                    // order.
+                    const LITTLE_ENDIAN_ORDER: [usize; 16] =
+                        [3, 2, 1, 0, 5, 4, 7, 6, 8, 9, 10, 11, 12, 13, 14, 15];
+                    const NETWORK_ORDER: [usize; 16] =
+                        [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+                    let order = if (entry.major, entry.minor) >= (2, 6) {
+                        &LITTLE_ENDIAN_ORDER
+                    } else {
+                        &NETWORK_ORDER
+                    };
+                    print!("UUID: ");
+                    for (i, index) in order.iter().enumerate() {
+                        if i == 4 || i == 6 || i == 8 || i == 10 {
+                            print!("-");
+                        }
+                        print!("{:02x}", uuid[*index]);
+                    }
+                    println!("");
+                }
+            },
+            TYPE_BASEBOARD => {
+                let info = baseboard_info(&s);
+                print!("Baseboard: ");
+                print_bytes(info.manufacturer);
+                print!(" ");
+                print_bytes(info.product);
+                println!("");
+            },
+            TYPE_PROCESSOR => {
+                let info = processor_info(&s);
+                print!("Processor: ");
+                print_bytes(info.socket);
+                print!(": ");
+                print_bytes(info.version);
+                println!(" ({} MHz, {} cores, {} threads)",
+                         info.current_speed_mhz, info.core_count,
+                         info.thread_count);
+            },
+            TYPE_MEMORY_DEVICE => {
+                let info = memory_device_info(&s);
+                if let Some(size) = info.size_mib {
+                    if size != 0 {
+                        print!("Memory: ");
+                        print_bytes(info.device_locator);
+                        println!(": {} MiB, {} MT/s", size, info.speed_mts);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+}
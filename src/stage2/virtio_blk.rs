@@ -0,0 +1,239 @@
+// virtio-blk driver using the legacy (virtio 0.9.5) PCI interface.
+//
+// Legacy and transitional virtio devices expose their registers in I/O BAR 0.
+// We negotiate only the flush feature, set up the single request queue, and
+// submit one request at a time, polling the used ring for completion.  Each
+// request is a three-descriptor chain: the request header, the caller's
+// buffer, and a status byte.  Data is transferred directly to or from the
+// caller's buffer.
+
+use core::cell::Cell;
+
+use dma;
+use sys;
+use sys::io;
+use sys::pci;
+use sys::{ErrorStr, SectorIndex, SECTOR_SIZE};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+const DEVICE_ID_LEGACY_BLK: u16 = 0x1001;
+
+// Legacy register offsets in BAR 0.
+const REG_DEVICE_FEATURES: u16  = 0x00;
+const REG_GUEST_FEATURES: u16   = 0x04;
+const REG_QUEUE_PFN: u16        = 0x08;
+const REG_QUEUE_SIZE: u16       = 0x0c;
+const REG_QUEUE_SELECT: u16     = 0x0e;
+const REG_QUEUE_NOTIFY: u16     = 0x10;
+const REG_DEVICE_STATUS: u16    = 0x12;
+const REG_CONFIG: u16           = 0x14;     // without MSI-X
+
+const STATUS_ACKNOWLEDGE: u8    = 1;
+const STATUS_DRIVER: u8         = 2;
+const STATUS_DRIVER_OK: u8      = 4;
+const STATUS_FAILED: u8         = 0x80;
+
+const FEATURE_RO: u32           = 1 << 5;
+const FEATURE_FLUSH: u32        = 1 << 9;
+
+const DESC_F_NEXT: u16          = 1;
+const DESC_F_WRITE: u16         = 2;
+
+const REQUEST_IN: u32           = 0;
+const REQUEST_OUT: u32          = 1;
+const REQUEST_FLUSH: u32        = 4;
+const REQUEST_STATUS_OK: u8     = 0;
+
+const QUEUE_ALIGN: usize = 4096;
+const MAX_SECTORS_PER_REQUEST: usize = 1024;
+const POLL_LIMIT: u32 = 100_000_000;
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct RequestHeader {
+    request_type: u32,
+    _reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk {
+    pub pci: pci::Address,
+    io_base: u16,
+    pub sector_count: u64,
+    read_only: bool,
+    flush: bool,                // the device has a write cache to flush
+    queue_size: u16,
+    descriptors: u32,
+    avail: u32,
+    used: u32,
+    header: u32,
+    status: u32,
+    next_avail: Cell<u16>,
+    last_used: Cell<u16>,
+}
+
+fn queue_bytes(queue_size: usize) -> (usize, usize) {
+    let avail_end = 16 * queue_size + 2 * (3 + queue_size);
+    let used_offset = (avail_end + QUEUE_ALIGN - 1) & !(QUEUE_ALIGN - 1);
+    (used_offset, used_offset + 2 * 3 + 8 * queue_size)
+}
+
+impl VirtioBlk {
+    fn descriptor(&self, index: u16) -> &mut Descriptor {
+        unsafe { &mut *((self.descriptors as usize + index as usize * 16) as *mut Descriptor) }
+    }
+
+    fn request(&self, request_type: u32, sector: u64, buffer: u32, length: usize) ->
+            Result<(), ErrorStr> {
+        let header = unsafe { &mut *(self.header as *mut RequestHeader) };
+        header.request_type = request_type;
+        header.sector = sector;
+        io::mmio_write8(self.status, 0xff);
+
+        // Descriptors 0-2 form the only chain we ever submit.
+        let mut next = 0;
+        {
+            let d = self.descriptor(next);
+            d.addr = self.header as u64;
+            d.len = 16;
+            d.flags = DESC_F_NEXT;
+            d.next = next + 1;
+        }
+        next += 1;
+        if length > 0 {
+            let d = self.descriptor(next);
+            d.addr = buffer as u64;
+            d.len = length as u32;
+            d.flags = DESC_F_NEXT | if request_type == REQUEST_IN { DESC_F_WRITE } else { 0 };
+            d.next = next + 1;
+            next += 1;
+        }
+        {
+            let d = self.descriptor(next);
+            d.addr = self.status as u64;
+            d.len = 1;
+            d.flags = DESC_F_WRITE;
+            d.next = 0;
+        }
+
+        // Publish the chain head in the available ring, then the new index.
+        let avail_index = self.next_avail.get();
+        io::mmio_write16(self.avail + 4 + 2 * (avail_index % self.queue_size) as u32, 0);
+        io::mmio_write16(self.avail + 2, avail_index.wrapping_add(1));
+        self.next_avail.set(avail_index.wrapping_add(1));
+        io::outw(self.io_base + REG_QUEUE_NOTIFY, 0);
+
+        let expected = self.last_used.get().wrapping_add(1);
+        let mut tries = 0;
+        while io::mmio_read16(self.used + 2) != expected {
+            tries += 1;
+            if tries == POLL_LIMIT {
+                return Err(ErrorStr::new(strlit!("virtio-blk request timed out")));
+            }
+        }
+        self.last_used.set(expected);
+
+        if io::mmio_read8(self.status) != REQUEST_STATUS_OK {
+            return Err(ErrorStr::new(strlit!("virtio-blk request failed")));
+        }
+        Ok(())
+    }
+
+    fn transfer(&self, request_type: u32, start_sector: SectorIndex, buffer: u32,
+                length: usize) -> Result<(), ErrorStr> {
+        assert!(length % SECTOR_SIZE == 0);
+        let chunk_size = MAX_SECTORS_PER_REQUEST * SECTOR_SIZE;
+        let mut done = 0;
+        while done < length {
+            let size = if length - done < chunk_size { length - done } else { chunk_size };
+            try!(self.request(request_type,
+                              start_sector as u64 + (done / SECTOR_SIZE) as u64,
+                              buffer + done as u32, size));
+            done += size;
+        }
+        Ok(())
+    }
+}
+
+impl sys::BlockDevice for VirtioBlk {
+    fn read_sectors(&self, start_sector: SectorIndex, buffer: &mut [u8]) ->
+            Result<(), ErrorStr> {
+        self.transfer(REQUEST_IN, start_sector, buffer.as_mut_ptr() as u32, buffer.len())
+    }
+
+    fn write_sectors(&self, start_sector: SectorIndex, buffer: &[u8]) ->
+            Result<(), ErrorStr> {
+        if self.read_only {
+            return Err(ErrorStr::new(strlit!("virtio-blk device is read-only")));
+        }
+        try!(self.transfer(REQUEST_OUT, start_sector, buffer.as_ptr() as u32, buffer.len()));
+        // Without the flush feature, the device writes through its cache.
+        if self.flush {
+            try!(self.request(REQUEST_FLUSH, 0, 0, 0));
+        }
+        Ok(())
+    }
+}
+
+pub fn is_virtio_blk(addr: pci::Address) -> bool {
+    pci::vendor_id(addr) == VENDOR_ID && pci::device_id(addr) == DEVICE_ID_LEGACY_BLK
+}
+
+// Initialize the legacy virtio-blk device at the given PCI address.
+pub fn open(addr: pci::Address) -> Result<VirtioBlk, ErrorStr> {
+    if !is_virtio_blk(addr) {
+        return Err(ErrorStr::new(strlit!("not a legacy virtio-blk device")));
+    }
+    let io_base = match pci::io_bar(addr, 0) {
+        None => return Err(ErrorStr::new(strlit!("virtio-blk device has no I/O BAR"))),
+        Some(base) => base,
+    };
+    pci::enable_device(addr);
+
+    // Reset, then acknowledge the device.
+    io::outb(io_base + REG_DEVICE_STATUS, 0);
+    io::outb(io_base + REG_DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+    let features = io::inl(io_base + REG_DEVICE_FEATURES);
+    io::outl(io_base + REG_GUEST_FEATURES, features & FEATURE_FLUSH);
+
+    io::outw(io_base + REG_QUEUE_SELECT, 0);
+    let queue_size = io::inw(io_base + REG_QUEUE_SIZE);
+    if queue_size < 3 {
+        io::outb(io_base + REG_DEVICE_STATUS, STATUS_FAILED);
+        return Err(ErrorStr::new(strlit!("virtio-blk queue is unusable")));
+    }
+    let (used_offset, total) = queue_bytes(queue_size as usize);
+    let queue = dma::alloc(total, QUEUE_ALIGN);
+    io::outl(io_base + REG_QUEUE_PFN, queue / QUEUE_ALIGN as u32);
+
+    let sector_count = (io::inl(io_base + REG_CONFIG) as u64) |
+        ((io::inl(io_base + REG_CONFIG + 4) as u64) << 32);
+    let request = dma::alloc(32, 16);
+
+    io::outb(io_base + REG_DEVICE_STATUS,
+             STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_DRIVER_OK);
+
+    Ok(VirtioBlk {
+        pci: addr,
+        io_base: io_base,
+        sector_count: sector_count,
+        read_only: features & FEATURE_RO != 0,
+        flush: features & FEATURE_FLUSH != 0,
+        queue_size: queue_size,
+        descriptors: queue,
+        avail: queue + 16 * queue_size as u32,
+        used: queue + used_offset as u32,
+        header: request,
+        status: request + 16,
+        next_avail: Cell::new(0),
+        last_used: Cell::new(0),
+    })
+}
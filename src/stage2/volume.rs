@@ -0,0 +1,165 @@
+// Discovery of pcboot volumes by their VBR marker.
+//
+// The MBR and VBR find the boot volume by an 18-byte marker at the end of its
+// VBR (see boot_records/mbr.asm):
+//
+//     "pcboot err" 0x00 0x00 <4-byte ID> 0x55 0xAA
+//
+// stage2 only learns a BIOS disk number and an LBA, which can go stale if the
+// BIOS renumbers its disks (e.g. when a USB drive is plugged in).  Here we
+// scan the MBR, EBR, and GPT partitions of every BIOS hard disk for VBRs with
+// a given marker ID, so stage2 can find its volume again and report duplicate
+// installs, which the MBR and VBR refuse to boot.
+//
+// Like the MBR, we only consider partition types that can hold a FAT32
+// volume, so that a partition whose contents an attacker controls cannot
+// impersonate the boot volume.
+
+use core::fmt;
+
+use gpt;
+use partition;
+use sys;
+use sys::{ErrorStr, SectorIndex, SECTOR_SIZE};
+
+const MARKER_TEXT: &'static [u8] = b"pcboot err\0\0";
+pub const MARKER_SIZE: usize = 18;
+const MAX_MATCHES: usize = 8;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct MarkerId(pub [u8; 4]);
+
+impl fmt::Display for MarkerId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}{:02x}{:02x}{:02x}", self.0[0], self.0[1], self.0[2], self.0[3])
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Location {
+    pub disk_number: u8,
+    pub partition_number: u32,
+    pub lba: SectorIndex,
+}
+
+// Return the marker ID of the VBR in `sector`, or None if it has no marker.
+pub fn marker_id(sector: &[u8; SECTOR_SIZE]) -> Option<MarkerId> {
+    let marker = &sector[SECTOR_SIZE - MARKER_SIZE..];
+    if &marker[..12] != MARKER_TEXT || marker[16] != 0x55 || marker[17] != 0xaa {
+        return None;
+    }
+    Some(MarkerId([marker[12], marker[13], marker[14], marker[15]]))
+}
+
+// Read the marker ID of the VBR at the given LBA.
+pub fn read_marker_id(device: &sys::BlockDevice, lba: SectorIndex) ->
+        Result<Option<MarkerId>, ErrorStr> {
+    let mut sector = [0u8; SECTOR_SIZE];
+    try!(device.read_sectors(lba, &mut sector));
+    Ok(marker_id(&sector))
+}
+
+fn is_fat32_type(type_id: u8) -> bool {
+    type_id == 0x0b || type_id == 0x0c || type_id == 0x1b || type_id == 0x1c
+}
+
+pub struct Matches {
+    locations: [Option<Location>; MAX_MATCHES],
+    count: usize,
+}
+
+impl Matches {
+    // The total number of matches, which may exceed the number stored.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    pub fn get(&self, index: usize) -> Option<Location> {
+        if index < MAX_MATCHES { self.locations[index] } else { None }
+    }
+
+    fn add(&mut self, location: Location) {
+        if self.count < MAX_MATCHES {
+            self.locations[self.count] = Some(location);
+        }
+        self.count += 1;
+    }
+}
+
+fn check_partition(disk: &sys::BlockDevice, disk_number: u8, partition_number: u32,
+                   lba: SectorIndex, id: MarkerId, matches: &mut Matches) {
+    match read_marker_id(disk, lba) {
+        Err(err) => log_warn!("disk 0x{:x} partition {}: {}",
+                              disk_number, partition_number, err),
+        Ok(Some(found)) if found == id => matches.add(Location {
+            disk_number: disk_number,
+            partition_number: partition_number,
+            lba: lba,
+        }),
+        Ok(_) => {},
+    }
+}
+
+fn scan_gpt(disk: &sys::BlockDevice, disk_number: u8, disk_sectors: Option<u64>, id: MarkerId,
+            matches: &mut Matches, entries: &mut gpt::EntryBuffer) -> Result<(), ErrorStr> {
+    let table = try!(gpt::read_table(disk, disk_sectors, entries));
+    let mut iter = table.partitions();
+    while let Some(part) = try!(iter.next()) {
+        let fat_type = part.type_guid == gpt::TYPE_BASIC_DATA ||
+            part.type_guid == gpt::TYPE_EFI_SYSTEM;
+        if fat_type && part.first_lba < (1 << 32) {
+            check_partition(disk, disk_number, part.number, part.first_lba as SectorIndex,
+                            id, matches);
+        }
+    }
+    Ok(())
+}
+
+fn scan_disk(disk: &sys::BlockDevice, disk_number: u8, disk_sectors: Option<u64>,
+             id: MarkerId, matches: &mut Matches, entries: &mut gpt::EntryBuffer) ->
+        Result<(), ErrorStr> {
+    let mut iter = try!(partition::partitions(disk, disk_sectors));
+    while let Some(part) = try!(iter.next()) {
+        if part.type_id == partition::TYPE_GPT_PROTECTIVE {
+            return scan_gpt(disk, disk_number, disk_sectors, id, matches, entries);
+        }
+        if is_fat32_type(part.type_id) {
+            check_partition(disk, disk_number, part.number, part.start_lba, id, matches);
+        }
+    }
+    Ok(())
+}
+
+fn scan_bios_disk(disk_number: u8, id: MarkerId, matches: &mut Matches,
+                  entries: &mut gpt::EntryBuffer) -> Result<(), ErrorStr> {
+    let disk = try!(sys::open_disk(disk_number));
+    let disk_sectors = sys::edd::get_drive_parameters(disk_number).ok()
+        .map(|params| params.total_sectors);
+    scan_disk(&disk, disk_number, disk_sectors, id, matches, entries)
+}
+
+// Scan every BIOS hard disk for pcboot volumes with the given marker ID.
+// The boot disk is read through `boot_disk`, which may be a native driver
+// that the BIOS can no longer share the disk with.  Disks that cannot be read
+// or parsed are skipped with a warning.  GPT entry arrays are read into
+// `entries`.
+pub fn find_all(id: MarkerId, boot_disk: &sys::BlockDevice, boot_disk_number: u8,
+                boot_disk_sectors: Option<u64>, entries: &mut gpt::EntryBuffer) -> Matches {
+    let mut matches = Matches { locations: [None; MAX_MATCHES], count: 0 };
+    let disk_count = sys::hard_disk_count();
+    for index in 0..disk_count {
+        let disk_number = 0x80u8.wrapping_add(index);
+        if disk_number < 0x80 {
+            break;
+        }
+        let result = if disk_number == boot_disk_number {
+            scan_disk(boot_disk, disk_number, boot_disk_sectors, id, &mut matches, entries)
+        } else {
+            scan_bios_disk(disk_number, id, &mut matches, entries)
+        };
+        if let Err(err) = result {
+            log_warn!("disk 0x{:x}: {}", disk_number, err);
+        }
+    }
+    matches
+}